@@ -0,0 +1,17 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use paulx_escrow_contract::{event::EscrowEvent, instruction::EscrowInstruction};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(instruction) = EscrowInstruction::unpack(data) {
+        // Whatever decodes has to survive a round trip, trailing bytes aside
+        let packed = instruction.pack();
+        assert_eq!(EscrowInstruction::unpack(&packed).unwrap(), instruction);
+        assert_eq!(packed[..], data[..packed.len()]);
+    }
+
+    if let Ok(event) = EscrowEvent::unpack(data) {
+        assert_eq!(event.pack(), data);
+    }
+});
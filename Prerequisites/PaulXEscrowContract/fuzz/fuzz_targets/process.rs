@@ -0,0 +1,197 @@
+#![no_main]
+
+//! Runs `Processor::process` against in-memory accounts built from the fuzz input. CPIs are
+//! no-ops, so this hunts for panics in the processor's own parsing and arithmetic rather than
+//! checking what a real runtime would accept.
+//!
+//! Input layout: one byte with the number of accounts, then for each account a key index, an
+//! owner index, a flags byte (bit 0 signer, bit 1 writable), 8 bytes of lamports, a 2 byte data
+//! length and the data itself. Whatever follows is the instruction data. The rent sysvar always
+//! holds the default rent, as the runtime only ever passes the real sysvar. The instructions
+//! sysvar holds an ed25519 program instruction with the account's data, followed by the current
+//! instruction.
+
+use std::sync::Once;
+
+use libfuzzer_sys::fuzz_target;
+use paulx_escrow_contract::{
+    instruction::EscrowInstruction,
+    oracle::pyth_receiver,
+    processor::Processor,
+    state::{find_config_address, find_escrow_pda, find_intent_nonce_address, find_vault_address},
+};
+use solana_program::{
+    account_info::AccountInfo,
+    clock::Clock,
+    ed25519_program,
+    entrypoint::{ProgramResult, SUCCESS},
+    instruction::Instruction,
+    program_stubs::{set_syscall_stubs, SyscallStubs},
+    pubkey::Pubkey,
+    rent::Rent,
+    system_program,
+    sysvar::{
+        self,
+        instructions::{construct_instructions_data, store_current_index, BorrowedInstruction},
+    },
+};
+
+const PROGRAM_ID: Pubkey = Pubkey::new_from_array([7; 32]);
+const INITIALIZER: Pubkey = Pubkey::new_from_array([1; 32]);
+const TAKER: Pubkey = Pubkey::new_from_array([2; 32]);
+const ESCROW: Pubkey = Pubkey::new_from_array([3; 32]);
+const MINT_X: Pubkey = Pubkey::new_from_array([4; 32]);
+const MINT_Y: Pubkey = Pubkey::new_from_array([5; 32]);
+const TOKEN_ACCOUNT: Pubkey = Pubkey::new_from_array([6; 32]);
+const PRICE_ACCOUNT: Pubkey = Pubkey::new_from_array([8; 32]);
+
+const MAX_ACCOUNTS: usize = 32;
+
+struct FuzzSyscallStubs;
+
+impl SyscallStubs for FuzzSyscallStubs {
+    fn sol_log(&self, _message: &str) {}
+
+    fn sol_log_data(&self, _fields: &[&[u8]]) {}
+
+    fn sol_invoke_signed(
+        &self,
+        _instruction: &Instruction,
+        _account_infos: &[AccountInfo],
+        _signers_seeds: &[&[&[u8]]],
+    ) -> ProgramResult {
+        Ok(())
+    }
+
+    fn sol_get_clock_sysvar(&self, var_addr: *mut u8) -> u64 {
+        let clock = Clock {
+            unix_timestamp: 1_700_000_000,
+            ..Clock::default()
+        };
+        unsafe { *(var_addr as *mut Clock) = clock };
+        SUCCESS
+    }
+
+    fn sol_get_rent_sysvar(&self, var_addr: *mut u8) -> u64 {
+        unsafe { *(var_addr as *mut Rent) = Rent::default() };
+        SUCCESS
+    }
+}
+
+/// Keys and owners are picked from this pool so that the processor's key checks can pass
+fn key_pool() -> Vec<Pubkey> {
+    vec![
+        PROGRAM_ID,
+        INITIALIZER,
+        TAKER,
+        ESCROW,
+        MINT_X,
+        MINT_Y,
+        TOKEN_ACCOUNT,
+        find_escrow_pda(&PROGRAM_ID, &ESCROW, &INITIALIZER).0,
+        find_vault_address(&PROGRAM_ID, &ESCROW).0,
+        spl_token::id(),
+        spl_token_2022::id(),
+        system_program::id(),
+        sysvar::rent::id(),
+        find_config_address(&PROGRAM_ID).0,
+        sysvar::instructions::id(),
+        find_intent_nonce_address(&PROGRAM_ID, &TAKER).0,
+        PRICE_ACCOUNT,
+        pyth_receiver::id(),
+    ]
+}
+
+fn rent_sysvar_data() -> Vec<u8> {
+    let rent = Rent::default();
+    let mut data = rent.lamports_per_byte_year.to_le_bytes().to_vec();
+    data.extend_from_slice(&rent.exemption_threshold.to_le_bytes());
+    data.push(rent.burn_percent);
+    data
+}
+
+fn instructions_sysvar_data(ed25519_data: &[u8]) -> Vec<u8> {
+    let mut data = construct_instructions_data(&[
+        BorrowedInstruction {
+            program_id: &ed25519_program::id(),
+            accounts: vec![],
+            data: ed25519_data,
+        },
+        BorrowedInstruction {
+            program_id: &PROGRAM_ID,
+            accounts: vec![],
+            data: &[],
+        },
+    ]);
+    store_current_index(&mut data, 1);
+    data
+}
+
+struct FuzzAccount {
+    key: Pubkey,
+    owner: Pubkey,
+    is_signer: bool,
+    is_writable: bool,
+    lamports: u64,
+    data: Vec<u8>,
+}
+
+fn parse(input: &[u8], pool: &[Pubkey]) -> Option<(Vec<FuzzAccount>, Vec<u8>)> {
+    let (&count, mut rest) = input.split_first()?;
+    let mut accounts = Vec::new();
+    for _ in 0..(count as usize % (MAX_ACCOUNTS + 1)) {
+        let (header, tail) = (rest.get(..13)?, &rest[13..]);
+        let data_len = u16::from_le_bytes([header[11], header[12]]) as usize;
+        let key = pool[header[0] as usize % pool.len()];
+        // The runtime controls what sysvar accounts hold
+        let data = if key == sysvar::rent::id() {
+            rent_sysvar_data()
+        } else if key == sysvar::instructions::id() {
+            instructions_sysvar_data(tail.get(..data_len)?)
+        } else {
+            tail.get(..data_len)?.to_vec()
+        };
+        accounts.push(FuzzAccount {
+            key,
+            owner: pool[header[1] as usize % pool.len()],
+            is_signer: header[2] & 1 != 0,
+            is_writable: header[2] & 2 != 0,
+            lamports: u64::from_le_bytes(header[3..11].try_into().unwrap()),
+            data,
+        });
+        rest = tail.get(data_len..)?;
+    }
+    Some((accounts, rest.to_vec()))
+}
+
+fuzz_target!(|input: &[u8]| {
+    static STUBS: Once = Once::new();
+    STUBS.call_once(|| {
+        set_syscall_stubs(Box::new(FuzzSyscallStubs));
+    });
+
+    let pool = key_pool();
+    let Some((mut accounts, instruction_data)) = parse(input, &pool) else {
+        return;
+    };
+    let account_infos = accounts
+        .iter_mut()
+        .map(|account| {
+            AccountInfo::new(
+                &account.key,
+                account.is_signer,
+                account.is_writable,
+                &mut account.lamports,
+                &mut account.data,
+                &account.owner,
+                false,
+                0,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let result = Processor::process(&PROGRAM_ID, &account_infos, &instruction_data);
+    if EscrowInstruction::unpack(&instruction_data).is_err() {
+        assert!(result.is_err());
+    }
+});
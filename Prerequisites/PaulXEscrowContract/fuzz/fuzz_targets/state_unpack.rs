@@ -0,0 +1,87 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use paulx_escrow_contract::state::{
+    ArbiterEscrow, Bundle, Config, Escrow, IntentNonce, MilestoneEscrow, Stream,
+    MAX_PROTOCOL_FEE_BASIS_POINTS,
+};
+use solana_program::program_pack::Pack;
+
+fuzz_target!(|data: &[u8]| {
+    // Reachable with any length, unlike `unpack` which checks it first
+    let unpacked = Escrow::unpack_from_slice(data);
+    if data.len() != Escrow::LEN {
+        assert!(Escrow::unpack(data).is_err());
+    }
+
+    if let Ok(escrow) = unpacked {
+        let is_initialized = escrow.is_initialized;
+        let mut packed = vec![0; Escrow::LEN];
+        Escrow::pack_into_slice(&escrow, &mut packed);
+        // Unused option bodies are zeroed on the way back, so compare decoded values
+        let repacked = Escrow::unpack_from_slice(&packed).unwrap();
+        assert_eq!(repacked.is_initialized, is_initialized);
+        assert_eq!(repacked.expected_amount, escrow.expected_amount);
+        assert_eq!(repacked.remaining_amount, escrow.remaining_amount);
+        assert_eq!(repacked.expires_at, escrow.expires_at);
+        assert_eq!(repacked.allowed_taker, escrow.allowed_taker);
+        assert_eq!(repacked.bump_seed, escrow.bump_seed);
+        assert_eq!(repacked.auction, escrow.auction);
+        assert_eq!(repacked.oracle, escrow.oracle);
+    }
+
+    if let Ok(arbiter_escrow) = ArbiterEscrow::unpack_from_slice(data) {
+        let mut packed = vec![0; ArbiterEscrow::LEN];
+        ArbiterEscrow::pack_into_slice(&arbiter_escrow, &mut packed);
+        assert_eq!(
+            ArbiterEscrow::unpack_from_slice(&packed).unwrap(),
+            arbiter_escrow
+        );
+    }
+
+    if let Ok(stream) = Stream::unpack_from_slice(data) {
+        let mut packed = vec![0; Stream::LEN];
+        Stream::pack_into_slice(&stream, &mut packed);
+        let repacked = Stream::unpack_from_slice(&packed).unwrap();
+        assert_eq!(repacked, stream);
+        // Vesting never pays out more than the stream holds
+        for now in [i64::MIN, stream.start_time, stream.end_time, i64::MAX] {
+            assert!(stream.vested_amount_at(now) <= stream.total_amount);
+        }
+    }
+
+    if let Ok(config) = Config::unpack_from_slice(data) {
+        let mut packed = vec![0; Config::LEN];
+        Config::pack_into_slice(&config, &mut packed);
+        assert_eq!(Config::unpack_from_slice(&packed).unwrap(), config);
+        // Only fees above the cap are rejected
+        match config.fee_for(u64::MAX) {
+            Some(_) => assert!(config.fee_basis_points <= MAX_PROTOCOL_FEE_BASIS_POINTS),
+            None => assert!(config.fee_basis_points > MAX_PROTOCOL_FEE_BASIS_POINTS),
+        }
+    }
+
+    if let Ok(intent_nonce) = IntentNonce::unpack_from_slice(data) {
+        let mut packed = vec![0; IntentNonce::LEN];
+        IntentNonce::pack_into_slice(&intent_nonce, &mut packed);
+        assert_eq!(
+            IntentNonce::unpack_from_slice(&packed).unwrap(),
+            intent_nonce
+        );
+    }
+
+    if let Ok(bundle) = Bundle::unpack(data) {
+        // Decodes with any number of legs, but only packs within the caps
+        let mut packed = vec![0; data.len()];
+        if bundle.pack(&mut packed).is_ok() {
+            assert_eq!(Bundle::unpack(&packed).unwrap(), bundle);
+        }
+    }
+
+    if let Ok(milestone_escrow) = MilestoneEscrow::unpack(data) {
+        let mut packed = vec![0; data.len()];
+        if milestone_escrow.pack(&mut packed).is_ok() {
+            assert_eq!(MilestoneEscrow::unpack(&packed).unwrap(), milestone_escrow);
+        }
+    }
+});
@@ -1,8 +1,14 @@
+use num_derive::FromPrimitive;
+use num_traits::FromPrimitive;
 use thiserror::Error;
 
-use solana_program::program_error::ProgramError;
+use solana_program::{
+    decode_error::DecodeError,
+    msg,
+    program_error::{PrintProgramError, ProgramError},
+};
 
-#[derive(Error, Debug, Copy, Clone)]
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum EscrowError {
     /// Invalid instruction
     #[error("Invalid Instruction")]
@@ -16,10 +22,138 @@ pub enum EscrowError {
     /// Amount Overflow
     #[error("Amount Overflow")]
     AmountOverflow,
+    /// Token account holds a different mint than the escrow expects
+    #[error("Mint Mismatch")]
+    MintMismatch,
+    /// Token account is not owned by the expected wallet
+    #[error("Token Account Owner Mismatch")]
+    TokenAccountOwnerMismatch,
+    /// The escrow's expiry has passed
+    #[error("Expired")]
+    Expired,
+    /// The escrow is restricted to a different taker
+    #[error("Taker Not Allowed")]
+    TakerNotAllowed,
+    /// A bundle has no legs on one side or more than the program supports
+    #[error("Invalid Bundle Size")]
+    InvalidBundleSize,
+    /// The arbiter escrow is already disputed
+    #[error("Already Disputed")]
+    AlreadyDisputed,
+    /// A milestone escrow has no milestones, more than the program supports or an empty one
+    #[error("Invalid Milestones")]
+    InvalidMilestones,
+    /// The milestone escrow's deadline has not passed yet
+    #[error("Deadline Not Reached")]
+    DeadlineNotReached,
+    /// Nothing has vested in the stream since it was last claimed
+    #[error("Nothing To Claim")]
+    NothingToClaim,
+    /// The protocol fee is above `state::MAX_PROTOCOL_FEE_BASIS_POINTS`
+    #[error("Invalid Fee")]
+    InvalidFee,
+    /// The program is paused and only lets existing trades be exited
+    #[error("Program Paused")]
+    ProgramPaused,
+    /// The taker's intent uses a nonce below the next one their nonce account accepts
+    #[error("Nonce Already Used")]
+    NonceAlreadyUsed,
+    /// The configured protocol fee is above the most the taker accepted
+    #[error("Protocol Fee Too High")]
+    ProtocolFeeTooHigh,
+    /// An instruction refused while the program is paused was sent without the config account
+    #[error("Missing Config Account")]
+    MissingConfigAccount,
+    /// The oracle's price was published longer ago than the escrow accepts, or after the cluster
+    /// clock's time
+    #[error("Stale Oracle Price")]
+    StaleOraclePrice,
+    /// The oracle's price is not positive or its confidence interval is wider than the escrow accepts
+    #[error("Oracle Price Uncertain")]
+    OraclePriceUncertain,
+    /// An oracle priced escrow was exchanged without its price account among the trailing accounts
+    #[error("Missing Price Account")]
+    MissingPriceAccount,
+    /// The oracle's price account holds the price of another feed than the escrow's
+    #[error("Wrong Oracle Feed")]
+    WrongOracleFeed,
+}
+
+impl EscrowError {
+    /// Maps a `ProgramError::Custom` code returned by a failed escrow
+    /// instruction back to the `EscrowError` that caused it
+    pub fn from_program_error(error: &ProgramError) -> Option<Self> {
+        match error {
+            ProgramError::Custom(code) => Self::from_u32(*code),
+            _ => None,
+        }
+    }
 }
 
 impl From<EscrowError> for ProgramError {
     fn from(e: EscrowError) -> Self {
         ProgramError::Custom(e as u32)
     }
-}
\ No newline at end of file
+}
+
+impl<T> DecodeError<T> for EscrowError {
+    fn type_of() -> &'static str {
+        "EscrowError"
+    }
+}
+
+impl PrintProgramError for EscrowError {
+    fn print<E>(&self)
+    where
+        E: 'static + std::error::Error + DecodeError<E> + PrintProgramError + FromPrimitive,
+    {
+        msg!("EscrowError::{:?}: {}", self, self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_program_error() {
+        for error in [
+            EscrowError::InvalidInstruction,
+            EscrowError::NotRentExempt,
+            EscrowError::ExpectedAmountMismatch,
+            EscrowError::AmountOverflow,
+            EscrowError::MintMismatch,
+            EscrowError::TokenAccountOwnerMismatch,
+            EscrowError::Expired,
+            EscrowError::TakerNotAllowed,
+            EscrowError::InvalidBundleSize,
+            EscrowError::AlreadyDisputed,
+            EscrowError::InvalidMilestones,
+            EscrowError::DeadlineNotReached,
+            EscrowError::NothingToClaim,
+            EscrowError::InvalidFee,
+            EscrowError::ProgramPaused,
+            EscrowError::NonceAlreadyUsed,
+            EscrowError::ProtocolFeeTooHigh,
+            EscrowError::MissingConfigAccount,
+            EscrowError::StaleOraclePrice,
+            EscrowError::OraclePriceUncertain,
+            EscrowError::MissingPriceAccount,
+            EscrowError::WrongOracleFeed,
+        ] {
+            assert_eq!(EscrowError::from_program_error(&error.into()), Some(error));
+        }
+        assert_eq!(
+            EscrowError::from_program_error(&ProgramError::Custom(2)),
+            Some(EscrowError::ExpectedAmountMismatch)
+        );
+        assert_eq!(
+            EscrowError::from_program_error(&ProgramError::Custom(u32::MAX)),
+            None
+        );
+        assert_eq!(
+            EscrowError::from_program_error(&ProgramError::InvalidAccountData),
+            None
+        );
+    }
+}
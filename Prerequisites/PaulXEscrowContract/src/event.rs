@@ -0,0 +1,510 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    clock::UnixTimestamp, log::sol_log_data, program_error::ProgramError, pubkey::Pubkey,
+};
+
+/// Machine readable record of an escrow state change, logged by the program with `sol_log_data`
+/// as a single Borsh encoded field. It shows up in the transaction logs as a base64
+/// `Program data: ` line, which [`EscrowEvent::unpack`] decodes once base64 decoded.
+///
+/// Only new variants are ever appended so older records keep decoding. A variant's fields are
+/// never changed, a change to them means adding a new variant instead.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub enum EscrowEvent {
+    Created(EscrowCreated),
+    Exchanged(EscrowExchanged),
+    Closed(EscrowClosed),
+    BundleCreated(BundleCreated),
+    BundleExchanged(BundleExchanged),
+    BundleClosed(BundleClosed),
+    Amended(EscrowAmended),
+    ArbiterEscrowCreated(ArbiterEscrowCreated),
+    ArbiterEscrowDisputed(ArbiterEscrowDisputed),
+    ArbiterEscrowSettled(ArbiterEscrowSettled),
+    MilestoneEscrowCreated(MilestoneEscrowCreated),
+    MilestoneReleased(MilestoneReleased),
+    MilestoneEscrowRefunded(MilestoneEscrowRefunded),
+    StreamCreated(StreamCreated),
+    StreamClaimed(StreamClaimed),
+    StreamCancelled(StreamCancelled),
+    ConfigUpdated(ConfigUpdated),
+    FeesCollected(FeesCollected),
+    PauseToggled(PauseToggled),
+    StreamCutOff(StreamCutOff),
+}
+
+/// An escrow was initialized and its offered tokens or lamports are held by the PDA
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EscrowCreated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    /// The vault token account, or the PDA itself when SOL is offered
+    pub vault: Pubkey,
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    /// What ended up in the vault, net of any transfer fee
+    pub offered_amount: u64,
+    pub expected_amount: u64,
+    pub expires_at: Option<UnixTimestamp>,
+    pub allowed_taker: Option<Pubkey>,
+}
+
+/// A taker filled an escrow, in full or in part
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EscrowExchanged {
+    pub escrow: Pubkey,
+    pub taker: Pubkey,
+    pub offered_mint: Pubkey,
+    pub requested_mint: Pubkey,
+    /// Offered tokens released to the taker, before any transfer fee
+    pub amount: u64,
+    /// Requested tokens the initializer received
+    pub payment: u64,
+    pub remaining_amount: u64,
+    pub remaining_expected_amount: u64,
+}
+
+/// Why an escrow account was closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CloseReason {
+    /// The last taker filled it
+    Filled,
+    /// The initializer cancelled it
+    Cancelled,
+    /// It was refunded after expiring, without the initializer's signature
+    Expired,
+}
+
+/// An escrow account and its vault were closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EscrowClosed {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub reason: CloseReason,
+    /// Offered tokens or lamports returned to the initializer, before any transfer fee
+    pub refunded_amount: u64,
+}
+
+/// An amount of one token of a bundle
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BundleAmount {
+    pub mint: Pubkey,
+    pub amount: u64,
+}
+
+/// A bundle was initialized and all its offered tokens are held in its vaults
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BundleCreated {
+    pub bundle: Pubkey,
+    pub initializer: Pubkey,
+    /// What ended up in each vault, net of any transfer fee
+    pub offered: Vec<BundleAmount>,
+    pub requested: Vec<BundleAmount>,
+    pub expires_at: Option<UnixTimestamp>,
+    pub allowed_taker: Option<Pubkey>,
+}
+
+/// A taker filled a bundle, which is closed right after
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BundleExchanged {
+    pub bundle: Pubkey,
+    pub taker: Pubkey,
+    /// Offered tokens released to the taker, before any transfer fee
+    pub amounts: Vec<BundleAmount>,
+    /// Requested tokens the initializer received
+    pub payments: Vec<BundleAmount>,
+}
+
+/// A bundle account and its vaults were closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct BundleClosed {
+    pub bundle: Pubkey,
+    pub initializer: Pubkey,
+    pub reason: CloseReason,
+    /// Offered tokens returned to the initializer, before any transfer fee
+    pub refunded: Vec<BundleAmount>,
+}
+
+/// The initializer changed the terms of an escrow
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct EscrowAmended {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub remaining_amount: u64,
+    pub expected_amount: u64,
+}
+
+/// An arbiter escrow was initialized and its payment is held in its vault
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArbiterEscrowCreated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub recipient: Pubkey,
+    pub arbiter: Pubkey,
+    pub mint: Pubkey,
+    /// What ended up in the vault, net of any transfer fee
+    pub amount: u64,
+}
+
+/// The initializer or the recipient disputed an arbiter escrow
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArbiterEscrowDisputed {
+    pub escrow: Pubkey,
+    pub disputed_by: Pubkey,
+}
+
+/// Where the payment of an arbiter escrow went
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Settlement {
+    /// Paid to the recipient
+    Released,
+    /// Returned to the initializer
+    Refunded,
+}
+
+/// An arbiter escrow and its vault were closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ArbiterEscrowSettled {
+    pub escrow: Pubkey,
+    pub settlement: Settlement,
+    /// Tokens paid out of the vault, before any transfer fee
+    pub amount: u64,
+}
+
+/// A milestone escrow was initialized and its whole payment is held in its vault
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MilestoneEscrowCreated {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    pub recipient: Pubkey,
+    pub mint: Pubkey,
+    pub milestones: Vec<u64>,
+    pub deadline: UnixTimestamp,
+}
+
+/// The initializer approved a milestone and it was paid to the recipient. The escrow is closed
+/// after the last one
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MilestoneReleased {
+    pub escrow: Pubkey,
+    /// Position of the milestone, counting from zero
+    pub index: u8,
+    /// Tokens paid out of the vault, before any transfer fee
+    pub amount: u64,
+}
+
+/// The unreleased milestones of an escrow were refunded after its deadline and the escrow closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MilestoneEscrowRefunded {
+    pub escrow: Pubkey,
+    pub initializer: Pubkey,
+    /// Tokens returned to the initializer, before any transfer fee
+    pub refunded: u64,
+}
+
+/// A stream was initialized and its tokens are held in its vault
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamCreated {
+    pub stream: Pubkey,
+    pub funder: Pubkey,
+    pub beneficiary: Pubkey,
+    pub mint: Pubkey,
+    /// What arrived in the vault, net of any transfer fee
+    pub amount: u64,
+    pub start_time: UnixTimestamp,
+    pub end_time: UnixTimestamp,
+    pub cliff_time: Option<UnixTimestamp>,
+}
+
+/// The beneficiary claimed vested tokens. The stream is closed once `claimed_amount` reaches its
+/// total
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamClaimed {
+    pub stream: Pubkey,
+    /// Tokens paid out of the vault by this claim, before any transfer fee
+    pub amount: u64,
+    /// Tokens claimed over the life of the stream, including this claim
+    pub claimed_amount: u64,
+}
+
+/// The funder cancelled a stream and it was closed
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamCancelled {
+    pub stream: Pubkey,
+    /// Vested tokens that had not been claimed, paid to the beneficiary before any transfer fee
+    pub vested: u64,
+    /// Unvested tokens returned to the funder, before any transfer fee
+    pub refunded: u64,
+}
+
+/// The program's config was initialized or updated
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ConfigUpdated {
+    pub admin: Pubkey,
+    pub fee_basis_points: u16,
+    pub treasury: Pubkey,
+}
+
+/// An exchange paid the protocol fee into the treasury, logged right after its `Exchanged`
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct FeesCollected {
+    pub escrow: Pubkey,
+    /// Offered tokens skimmed before they reached the taker, before any transfer fee
+    pub offered_fee: u64,
+    /// Requested tokens skimmed before they reached the initializer, before any transfer fee
+    pub requested_fee: u64,
+}
+
+/// The admin paused or unpaused the program
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PauseToggled {
+    pub admin: Pubkey,
+    pub is_paused: bool,
+}
+
+/// The funder cancelled a stream while the program was paused. The stream now ends at the
+/// cancellation and stays open until the beneficiary claims what had vested
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamCutOff {
+    pub stream: Pubkey,
+    /// Vested tokens that had not been claimed, left in the vault for the beneficiary
+    pub vested: u64,
+    /// Unvested tokens returned to the funder, before any transfer fee
+    pub refunded: u64,
+}
+
+impl EscrowEvent {
+    /// Decodes the data of a `Program data: ` log line logged by this program
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        Ok(Self::try_from_slice(input)?)
+    }
+
+    pub fn pack(&self) -> Vec<u8> {
+        borsh::to_vec(self).unwrap()
+    }
+
+    /// Logs the event with `sol_log_data`
+    pub fn emit(&self) {
+        sol_log_data(&[&self.pack()]);
+    }
+}
+
+impl From<EscrowCreated> for EscrowEvent {
+    fn from(event: EscrowCreated) -> Self {
+        Self::Created(event)
+    }
+}
+
+impl From<EscrowExchanged> for EscrowEvent {
+    fn from(event: EscrowExchanged) -> Self {
+        Self::Exchanged(event)
+    }
+}
+
+impl From<EscrowClosed> for EscrowEvent {
+    fn from(event: EscrowClosed) -> Self {
+        Self::Closed(event)
+    }
+}
+
+impl From<BundleCreated> for EscrowEvent {
+    fn from(event: BundleCreated) -> Self {
+        Self::BundleCreated(event)
+    }
+}
+
+impl From<BundleExchanged> for EscrowEvent {
+    fn from(event: BundleExchanged) -> Self {
+        Self::BundleExchanged(event)
+    }
+}
+
+impl From<BundleClosed> for EscrowEvent {
+    fn from(event: BundleClosed) -> Self {
+        Self::BundleClosed(event)
+    }
+}
+
+impl From<EscrowAmended> for EscrowEvent {
+    fn from(event: EscrowAmended) -> Self {
+        Self::Amended(event)
+    }
+}
+
+impl From<ArbiterEscrowCreated> for EscrowEvent {
+    fn from(event: ArbiterEscrowCreated) -> Self {
+        Self::ArbiterEscrowCreated(event)
+    }
+}
+
+impl From<ArbiterEscrowDisputed> for EscrowEvent {
+    fn from(event: ArbiterEscrowDisputed) -> Self {
+        Self::ArbiterEscrowDisputed(event)
+    }
+}
+
+impl From<ArbiterEscrowSettled> for EscrowEvent {
+    fn from(event: ArbiterEscrowSettled) -> Self {
+        Self::ArbiterEscrowSettled(event)
+    }
+}
+
+impl From<MilestoneEscrowCreated> for EscrowEvent {
+    fn from(event: MilestoneEscrowCreated) -> Self {
+        Self::MilestoneEscrowCreated(event)
+    }
+}
+
+impl From<MilestoneReleased> for EscrowEvent {
+    fn from(event: MilestoneReleased) -> Self {
+        Self::MilestoneReleased(event)
+    }
+}
+
+impl From<MilestoneEscrowRefunded> for EscrowEvent {
+    fn from(event: MilestoneEscrowRefunded) -> Self {
+        Self::MilestoneEscrowRefunded(event)
+    }
+}
+
+impl From<StreamCreated> for EscrowEvent {
+    fn from(event: StreamCreated) -> Self {
+        Self::StreamCreated(event)
+    }
+}
+
+impl From<StreamClaimed> for EscrowEvent {
+    fn from(event: StreamClaimed) -> Self {
+        Self::StreamClaimed(event)
+    }
+}
+
+impl From<StreamCancelled> for EscrowEvent {
+    fn from(event: StreamCancelled) -> Self {
+        Self::StreamCancelled(event)
+    }
+}
+
+impl From<ConfigUpdated> for EscrowEvent {
+    fn from(event: ConfigUpdated) -> Self {
+        Self::ConfigUpdated(event)
+    }
+}
+
+impl From<FeesCollected> for EscrowEvent {
+    fn from(event: FeesCollected) -> Self {
+        Self::FeesCollected(event)
+    }
+}
+
+impl From<PauseToggled> for EscrowEvent {
+    fn from(event: PauseToggled) -> Self {
+        Self::PauseToggled(event)
+    }
+}
+
+impl From<StreamCutOff> for EscrowEvent {
+    fn from(event: StreamCutOff) -> Self {
+        Self::StreamCutOff(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pack_unpack() {
+        let events = [
+            EscrowEvent::Created(EscrowCreated {
+                escrow: Pubkey::new_unique(),
+                initializer: Pubkey::new_unique(),
+                vault: Pubkey::new_unique(),
+                offered_mint: Pubkey::new_unique(),
+                requested_mint: Pubkey::new_unique(),
+                offered_amount: 10,
+                expected_amount: 30,
+                expires_at: Some(1_000),
+                allowed_taker: None,
+            }),
+            EscrowEvent::Exchanged(EscrowExchanged {
+                escrow: Pubkey::new_unique(),
+                taker: Pubkey::new_unique(),
+                offered_mint: Pubkey::new_unique(),
+                requested_mint: Pubkey::new_unique(),
+                amount: 4,
+                payment: 12,
+                remaining_amount: 6,
+                remaining_expected_amount: 18,
+            }),
+            EscrowEvent::Closed(EscrowClosed {
+                escrow: Pubkey::new_unique(),
+                initializer: Pubkey::new_unique(),
+                reason: CloseReason::Expired,
+                refunded_amount: 6,
+            }),
+            EscrowEvent::BundleExchanged(BundleExchanged {
+                bundle: Pubkey::new_unique(),
+                taker: Pubkey::new_unique(),
+                amounts: vec![BundleAmount {
+                    mint: Pubkey::new_unique(),
+                    amount: 4,
+                }],
+                payments: vec![],
+            }),
+            EscrowEvent::ArbiterEscrowSettled(ArbiterEscrowSettled {
+                escrow: Pubkey::new_unique(),
+                settlement: Settlement::Refunded,
+                amount: 42,
+            }),
+            EscrowEvent::MilestoneEscrowCreated(MilestoneEscrowCreated {
+                escrow: Pubkey::new_unique(),
+                initializer: Pubkey::new_unique(),
+                recipient: Pubkey::new_unique(),
+                mint: Pubkey::new_unique(),
+                milestones: vec![10, 20],
+                deadline: 1_000,
+            }),
+            EscrowEvent::StreamCreated(StreamCreated {
+                stream: Pubkey::new_unique(),
+                funder: Pubkey::new_unique(),
+                beneficiary: Pubkey::new_unique(),
+                mint: Pubkey::new_unique(),
+                amount: 1_000,
+                start_time: 100,
+                end_time: 200,
+                cliff_time: Some(150),
+            }),
+            EscrowEvent::FeesCollected(FeesCollected {
+                escrow: Pubkey::new_unique(),
+                offered_fee: 3,
+                requested_fee: 9,
+            }),
+            EscrowEvent::PauseToggled(PauseToggled {
+                admin: Pubkey::new_unique(),
+                is_paused: true,
+            }),
+            EscrowEvent::StreamCutOff(StreamCutOff {
+                stream: Pubkey::new_unique(),
+                vested: 25,
+                refunded: 75,
+            }),
+        ];
+        for event in events {
+            let packed = event.pack();
+            assert_eq!(EscrowEvent::unpack(&packed).unwrap(), event);
+        }
+
+        let closed = EscrowEvent::Closed(EscrowClosed {
+            escrow: Pubkey::new_unique(),
+            initializer: Pubkey::new_unique(),
+            reason: CloseReason::Filled,
+            refunded_amount: 0,
+        });
+        let packed = closed.pack();
+        assert_eq!(packed[0], 2);
+        assert_eq!(packed.len(), 1 + 32 + 32 + 1 + 8);
+        assert!(EscrowEvent::unpack(&packed[..packed.len() - 1]).is_err());
+        assert!(EscrowEvent::unpack(&[19]).is_err());
+    }
+}
@@ -1,66 +1,2721 @@
-use solana_program::program_error::ProgramError;
-use std::convert::TryInto;
+use solana_program::{
+    clock::UnixTimestamp,
+    ed25519_program,
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+use std::{convert::TryInto, mem::size_of};
 
-use crate::error::EscrowError::InvalidInstruction;
+use num_traits::FromPrimitive;
 
+use crate::{
+    error::EscrowError::InvalidInstruction,
+    oracle::OracleKind,
+    state::{
+        find_bundle_vault_address, find_config_address, find_escrow_pda, find_intent_nonce_address,
+        find_program_data_address, find_vault_address, Auction, OraclePricing, NATIVE_MINT,
+    },
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum EscrowInstruction {
-    /// Starts the trade by creating and populating an escrow account and transferring ownership of the given temp token account to the PDA
+    /// Starts the trade by populating an escrow account, creating a vault token account at the address derived from
+    /// `[VAULT_PDA_SEED, escrow account]` owned by the PDA derived from `[ESCROW_PDA_SEED, escrow account, initializer]`,
+    /// and moving the offered tokens into it
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person initializing the escrow
-    /// 1. `[writable]` Temporary token account that should be created prior to this instruction and owned by the initializer
-    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
-    /// 4. `[]` The rent sysvar
-    /// 5. `[]` The token program
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, it pays for the vault
+    /// 1. `[writable]` The initializer's token account holding the offered tokens
+    /// 2. `[writable]` The vault token account to create
+    /// 3. `[]` The initializer's token account for the token they will receive should the trade go through
+    /// 4. `[writable]` The escrow account, it will hold all necessary info about the trade.
+    /// 5. `[]` The rent sysvar
+    /// 6. `[]` The token program of the offered mint, SPL Token or Token-2022
+    /// 7. `[]` The offered mint
+    /// 8. `[]` The system program
+    /// 9. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 10. `[]` Any extra accounts required by the offered mint's transfer hook
     InitEscrow {
-        /// The amount party A expects to receive of token Y
+        /// The amount of token X party A offers. With a transfer fee the escrow offers what arrives in the vault
+        offered_amount: u64,
+        /// The amount party A expects to receive of token Y, what an auction starts at or the floor
+        /// under an oracle's price
         amount: u64,
+        /// Unix timestamp from which the trade can no longer be taken, if any
+        expires_at: Option<UnixTimestamp>,
+        /// The only taker allowed to accept the trade, if any
+        allowed_taker: Option<Pubkey>,
+        /// Sells through a Dutch auction that starts at `amount` instead of at a fixed price, if any
+        auction: Option<Auction>,
+        /// Sells at an oracle's price, no lower than `amount`, instead of at a fixed price, if any.
+        /// Can't be combined with an auction
+        oracle: Option<OraclePricing>,
     },
-    /// Accepts a trade
+    /// Accepts a trade, in full or in part. The taker pays the pro-rata share of the
+    /// escrow's expected amount, rounded up, and the escrow is only closed once fully filled.
+    /// For an auction the expected amount is the auction's price at the current clock time, and
+    /// for an oracle priced escrow the taker pays the oracle's price if that is above it
     ///
     ///
     /// Accounts expected:
     ///
-    /// 0. `[signer]` The account of the person taking the trade
-    /// 1. `[writable]` The taker's token account for the token they send
-    /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through
-    /// 3. `[writable]` The PDA's temp token account to get tokens from and eventually close
+    /// 0. `[signer]` The account of the person taking the trade, writable when it pays in SOL
+    /// 1. `[writable]` The taker's token account for the token they send, or the taker's account itself when SOL is requested
+    /// 2. `[writable]` The taker's token account for the token they will receive should the trade go through,
+    ///    or the taker's account itself when SOL is offered
+    /// 3. `[writable]` The PDA's vault token account to get tokens from and close once fully filled, or the PDA itself when SOL is offered
     /// 4. `[writable]` The initializer's main account to send their rent fees to
-    /// 5. `[writable]` The initializer's token account that will receive tokens
+    /// 5. `[writable]` The initializer's token account that will receive tokens, or the account receiving lamports when SOL is requested
     /// 6. `[writable]` The escrow account holding the escrow info
-    /// 7. `[]` The token program
+    /// 7. `[]` The token program of the offered mint
     /// 8. `[]` The PDA account
+    /// 9. `[]` The system program, only used when SOL is requested
+    /// 10. `[writable]` The offered mint, writable so transfer fees withheld in the PDA's vault token account can be harvested
+    /// 11. `[]` The requested mint
+    /// 12. `[]` The token program of the requested mint
+    /// 13. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 14. `[writable]` The treasury's account for the offered mint, only used while a protocol fee is configured
+    /// 15. `[writable]` The treasury's account for the requested mint, only used while a protocol fee is configured
+    /// 16. `[]` The escrow's oracle price account when it is priced off an oracle, and any extra
+    ///     accounts required by the mints' transfer hooks, in any order
+    ///
+    /// Any transfer fee on the requested mint is added on top of the taker's payment so the
+    /// initializer receives the full amount. Fees on the offered mint are taken from what the taker receives.
+    /// The configured protocol fee is skimmed off both legs: from the taker's payment before it
+    /// reaches the initializer and from the offered tokens before they reach the taker.
+    /// Anything sent to the vault on top of the escrowed amount goes back to the initializer once
+    /// fully filled: lamports when the vault is closed, and tokens by handing them the vault.
     Exchange {
-        /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token
+        /// the amount the taker expects to be paid in the other token, as a u64 because that's the max possible supply of a token.
+        /// Must not exceed the escrow's remaining amount
+        amount: u64,
+        /// The most the initializer may receive for `amount`, transfer fees aside. Guards the taker
+        /// against the escrow being amended to a worse price before the exchange lands
+        max_payment: u64,
+        /// The highest protocol fee the taker accepts, guarding them against the admin raising it
+        /// before the exchange lands
+        max_fee_basis_points: u16,
+    },
+    /// Cancels a trade that nobody has taken, returning the tokens to the initializer.
+    /// Once the escrow has expired anyone may submit it on the initializer's behalf.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow, it will receive the rent fees.
+    ///    Only needs to sign while the escrow has not expired
+    /// 1. `[writable]` The PDA's vault token account to get tokens from and eventually close, or the PDA itself when SOL is offered
+    /// 2. `[writable]` The initializer's token account that will get the tokens back, owned by the initializer.
+    ///    Ignored when SOL is offered, the lamports go to account 0
+    /// 3. `[writable]` The escrow account holding the escrow info
+    /// 4. `[]` The token program of the offered mint
+    /// 5. `[]` The PDA account
+    /// 6. `[writable]` The offered mint, ignored when SOL is offered
+    /// 7. `[]` Any extra accounts required by the offered mint's transfer hook
+    Cancel,
+    /// Starts a trade where one side is native SOL instead of an SPL token.
+    ///
+    /// When SOL is offered the initializer's lamports are moved into the escrow's PDA, which the
+    /// program takes ownership of and pays takers out of directly. When SOL is requested the offered
+    /// tokens are moved into a vault as with `InitEscrow`, and takers pay lamports straight to
+    /// account 3.
+    ///
+    ///
+    /// Accounts expected when SOL is offered:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the escrow
+    /// 1. `[writable]` The PDA derived from `[ESCROW_PDA_SEED, escrow account, initializer]` that will hold the lamports
+    /// 2. `[]` The initializer's token account for the token they will receive should the trade go through
+    /// 3. `[writable]` The escrow account, it will hold all necessary info about the trade.
+    /// 4. `[]` The rent sysvar
+    /// 5. `[]` The system program
+    /// 6. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    ///
+    /// Accounts expected when SOL is requested:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, it pays for the vault
+    /// 1. `[writable]` The initializer's token account holding the offered tokens
+    /// 2. `[writable]` The vault token account to create
+    /// 3. `[]` The account that will receive the lamports should the trade go through
+    /// 4. `[writable]` The escrow account, it will hold all necessary info about the trade.
+    /// 5. `[]` The rent sysvar
+    /// 6. `[]` The token program of the offered mint
+    /// 7. `[]` The offered mint
+    /// 8. `[]` The system program
+    /// 9. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 10. `[]` Any extra accounts required by the offered mint's transfer hook
+    InitNativeEscrow {
+        /// Whether party A offers SOL, otherwise party A offers tokens and requests SOL
+        offers_sol: bool,
+        /// The amount party A offers, in lamports when SOL is offered
+        offered_amount: u64,
+        /// The amount party A expects to receive, in lamports when SOL is requested, what an
+        /// auction starts at or the floor under an oracle's price
+        amount: u64,
+        /// Unix timestamp from which the trade can no longer be taken, if any
+        expires_at: Option<UnixTimestamp>,
+        /// The only taker allowed to accept the trade, if any
+        allowed_taker: Option<Pubkey>,
+        /// Sells through a Dutch auction that starts at `amount` instead of at a fixed price, if any
+        auction: Option<Auction>,
+        /// Sells at an oracle's price, no lower than `amount`, instead of at a fixed price, if any.
+        /// Can't be combined with an auction
+        oracle: Option<OraclePricing>,
+    },
+    /// Starts a bundle trade offering several tokens for several others, creating one vault token
+    /// account per offered token at the address derived from `[VAULT_PDA_SEED, bundle account, index]`
+    /// owned by the PDA derived from `[ESCROW_PDA_SEED, bundle account, initializer]`.
+    /// At most `MAX_BUNDLE_OFFERED_LEGS` tokens can be offered and `MAX_BUNDLE_REQUESTED_LEGS` requested.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the bundle, it pays for the vaults
+    /// 1. `[writable]` The bundle account, sized with `Bundle::get_packed_len` for the number of legs
+    /// 2. `[]` The system program
+    /// 3. For each offered token, in order:
+    ///    0. `[writable]` The initializer's token account holding the offered tokens
+    ///    1. `[writable]` The vault token account to create
+    ///    2. `[]` The offered mint
+    ///    3. `[]` The token program of the offered mint
+    /// 4. For each requested token, in order:
+    ///    0. `[]` The initializer's token account for the token they will receive
+    /// 5. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 6. `[]` Any extra accounts required by the offered mints' transfer hooks
+    InitBundle {
+        /// The amount of each offered token. With a transfer fee the bundle offers what arrives in the vault
+        offered_amounts: Vec<u64>,
+        /// The amount of each requested token the initializer expects to receive
+        requested_amounts: Vec<u64>,
+        /// Unix timestamp from which the trade can no longer be taken, if any
+        expires_at: Option<UnixTimestamp>,
+        /// The only taker allowed to accept the trade, if any
+        allowed_taker: Option<Pubkey>,
+    },
+    /// Changes the price of a trade nobody has fully taken yet, optionally adding offered tokens to
+    /// the vault or taking some back. Only the initializer can amend, and exchanges built
+    /// against the old terms fail on their `max_payment` if the new terms are worse for the taker
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow
+    /// 1. `[writable]` The escrow account holding the escrow info
+    /// 2. `[writable]` The PDA's vault token account, or the PDA itself when SOL is offered
+    /// 3. `[writable]` The initializer's token account the tokens are added from or returned to.
+    ///    Ignored when SOL is offered, the lamports come from and go to account 0
+    /// 4. `[]` The token program of the offered mint
+    /// 5. `[]` The PDA account
+    /// 6. `[]` The offered mint
+    /// 7. `[]` The system program
+    /// 8. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 9. `[]` Any extra accounts required by the offered mint's transfer hook
+    Amend {
+        /// The amount of the requested token the initializer now expects for everything left
+        new_expected_amount: u64,
+        /// How much more of the offered token to move into the vault. With a transfer fee the
+        /// escrow gains what arrives
+        top_up_amount: u64,
+        /// How much of the offered token to take back out of the vault, which has to leave some
+        /// behind. Use `Cancel` to take back everything. At most one of the two can be non zero
+        withdraw_amount: u64,
+    },
+    /// Accepts a bundle trade in full. Every requested token is paid to the initializer and every
+    /// vault is emptied to the taker and closed, or none of it happens
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person taking the trade
+    /// 1. `[writable]` The initializer's main account to send their rent fees to
+    /// 2. `[writable]` The bundle account
+    /// 3. `[]` The PDA account
+    /// 4. For each offered token, in order:
+    ///    0. `[writable]` The PDA's vault token account
+    ///    1. `[writable]` The taker's token account that will receive the tokens
+    ///    2. `[writable]` The offered mint, writable so withheld transfer fees can be harvested
+    ///    3. `[]` The token program of the offered mint
+    /// 5. For each requested token, in order:
+    ///    0. `[writable]` The taker's token account sending the tokens
+    ///    1. `[writable]` The initializer's token account that will receive the tokens
+    ///    2. `[]` The requested mint
+    ///    3. `[]` The token program of the requested mint
+    /// 6. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 7. `[]` Any extra accounts required by the mints' transfer hooks
+    ///
+    /// As with `Exchange`, the taker covers transfer fees on the requested tokens.
+    ExchangeBundle,
+    /// Cancels a bundle trade, returning every offered token to the initializer.
+    /// Once the bundle has expired anyone may submit it on the initializer's behalf.
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the bundle, it will receive the rent fees.
+    ///    Only needs to sign while the bundle has not expired
+    /// 1. `[writable]` The bundle account
+    /// 2. `[]` The PDA account
+    /// 3. For each offered token, in order:
+    ///    0. `[writable]` The PDA's vault token account
+    ///    1. `[writable]` The initializer's token account that will get the tokens back
+    ///    2. `[writable]` The offered mint
+    ///    3. `[]` The token program of the offered mint
+    /// 4. `[]` Any extra accounts required by the offered mints' transfer hooks
+    CancelBundle,
+    /// Starts a payment for a service held in a vault until two of the initializer, the recipient
+    /// and the arbiter agree to release it to the recipient or refund it. The vault is created at
+    /// the address derived from `[VAULT_PDA_SEED, escrow account]` and owned by the PDA derived from
+    /// `[ESCROW_PDA_SEED, escrow account, initializer]`, as with `InitEscrow`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, it pays for the vault
+    /// 1. `[writable]` The initializer's token account holding the payment
+    /// 2. `[writable]` The vault token account to create
+    /// 3. `[writable]` The arbiter escrow account, sized `state::ArbiterEscrow::LEN`
+    /// 4. `[]` The token program of the mint
+    /// 5. `[]` The mint
+    /// 6. `[]` The system program
+    /// 7. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 8. `[]` Any extra accounts required by the mint's transfer hook
+    InitArbiterEscrow {
+        /// The amount of the payment. With a transfer fee the escrow holds what arrives in the vault
+        amount: u64,
+        /// Who the payment is released to
+        recipient: Pubkey,
+        /// Who settles the escrow alone once it is disputed
+        arbiter: Pubkey,
+    },
+    /// Flags an arbiter escrow as disputed, letting the arbiter settle it without either party
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The initializer or the recipient of the escrow
+    /// 1. `[writable]` The arbiter escrow account
+    Dispute,
+    /// Pays the whole vault of an arbiter escrow to its recipient and closes the escrow. Needs the
+    /// signatures of two of the three parties, or of the arbiter alone once disputed
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` The initializer, it will receive the rent fees. Signs to approve
+    /// 1. `[]` The recipient. Signs to approve
+    /// 2. `[]` The arbiter. Signs to approve
+    /// 3. `[writable]` The arbiter escrow account
+    /// 4. `[writable]` The PDA's vault token account
+    /// 5. `[writable]` The recipient's token account that will receive the tokens
+    /// 6. `[writable]` The mint, writable so withheld transfer fees can be harvested
+    /// 7. `[]` The token program of the mint
+    /// 8. `[]` The PDA account
+    /// 9. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 10. `[]` Any extra accounts required by the mint's transfer hook
+    Release,
+    /// Returns the whole vault of an arbiter escrow to its initializer and closes the escrow. Takes
+    /// the same approvals and accounts as `Release` but the config account, except that account 5
+    /// is the initializer's token account that will get the tokens back
+    Refund,
+    /// Starts a payment for a recipient held in a vault and paid out in milestones, each released
+    /// once the initializer approves it. The vault is created at the address derived from
+    /// `[VAULT_PDA_SEED, escrow account]` and owned by the PDA derived from
+    /// `[ESCROW_PDA_SEED, escrow account, initializer]`, as with `InitEscrow`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person initializing the escrow, it pays for the vault
+    /// 1. `[writable]` The initializer's token account holding the payment
+    /// 2. `[writable]` The vault token account to create
+    /// 3. `[writable]` The milestone escrow account, sized `state::MilestoneEscrow::get_packed_len`
+    /// 4. `[]` The token program of the mint
+    /// 5. `[]` The mint
+    /// 6. `[]` The system program
+    /// 7. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 8. `[]` Any extra accounts required by the mint's transfer hook
+    ///
+    /// Any transfer fee is added on top so the vault holds the sum of the milestones.
+    InitMilestoneEscrow {
+        /// The amount of each milestone, in the order they are released
+        amounts: Vec<u64>,
+        /// Unix timestamp from which the unreleased milestones can be refunded
+        deadline: UnixTimestamp,
+        /// Who the milestones are paid to
+        recipient: Pubkey,
+    },
+    /// Pays the next milestone to the recipient, closing the escrow after the last one
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who initialized the escrow, it will receive
+    ///    the rent fees after the last milestone
+    /// 1. `[writable]` The milestone escrow account
+    /// 2. `[writable]` The PDA's vault token account
+    /// 3. `[writable]` The recipient's token account that will receive the tokens
+    /// 4. `[writable]` The mint, writable so withheld transfer fees can be harvested
+    /// 5. `[]` The token program of the mint
+    /// 6. `[]` The PDA account
+    /// 7. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 8. `[]` Any extra accounts required by the mint's transfer hook
+    ApproveMilestone,
+    /// Returns every unreleased milestone to the initializer once the deadline has passed and
+    /// closes the escrow. Anyone may submit it on the initializer's behalf
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[writable]` The account of the person who initialized the escrow, it will receive the rent fees
+    /// 1. `[writable]` The milestone escrow account
+    /// 2. `[writable]` The PDA's vault token account
+    /// 3. `[writable]` The initializer's token account that will get the tokens back
+    /// 4. `[writable]` The mint
+    /// 5. `[]` The token program of the mint
+    /// 6. `[]` The PDA account
+    /// 7. `[]` Any extra accounts required by the mint's transfer hook
+    RefundMilestones,
+    /// Starts a stream of tokens to a beneficiary, vesting linearly between a start and an end
+    /// time. The vault is created at the address derived from `[VAULT_PDA_SEED, stream account]`
+    /// and owned by the PDA derived from `[ESCROW_PDA_SEED, stream account, funder]`, as with
+    /// `InitEscrow`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person funding the stream, it pays for the vault
+    /// 1. `[writable]` The funder's token account holding the tokens to stream
+    /// 2. `[writable]` The vault token account to create
+    /// 3. `[writable]` The stream account, it must be rent exempt
+    /// 4. `[]` The token program of the mint
+    /// 5. `[]` The mint
+    /// 6. `[]` The system program
+    /// 7. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 8. `[]` Any extra accounts required by the mint's transfer hook
+    InitStream {
+        /// The amount of tokens to stream
         amount: u64,
+        /// Unix timestamp the tokens start vesting at
+        start_time: UnixTimestamp,
+        /// Unix timestamp by which every token has vested
+        end_time: UnixTimestamp,
+        /// Unix timestamp before which nothing can be claimed, if any
+        cliff_time: Option<UnixTimestamp>,
+        /// Who the tokens vest for
+        beneficiary: Pubkey,
+    },
+    /// Pays the beneficiary everything vested and not claimed yet, closing the stream once all of
+    /// it has been claimed
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The beneficiary of the stream
+    /// 1. `[writable]` The account of the person who funded the stream, it will receive the rent
+    ///    fees once the stream is closed
+    /// 2. `[writable]` The stream account
+    /// 3. `[writable]` The PDA's vault token account
+    /// 4. `[writable]` The beneficiary's token account that will receive the tokens
+    /// 5. `[writable]` The mint, writable so withheld transfer fees can be harvested
+    /// 6. `[]` The token program of the mint
+    /// 7. `[]` The PDA account
+    /// 8. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 9. `[]` Any extra accounts required by the mint's transfer hook
+    Claim,
+    /// Ends a stream early. The beneficiary is paid whatever has vested and not been claimed,
+    /// the funder gets the unvested remainder back and the stream is closed. While the program
+    /// is paused only the unvested remainder goes back: the stream is cut off at the current
+    /// time and what had vested stays in the vault for the beneficiary to claim after `Unpause`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The account of the person who funded the stream, it will receive
+    ///    the rent fees
+    /// 1. `[writable]` The stream account
+    /// 2. `[writable]` The PDA's vault token account
+    /// 3. `[writable]` The funder's token account that will get the unvested tokens back
+    /// 4. `[writable]` The beneficiary's token account that will receive the vested tokens
+    /// 5. `[writable]` The mint
+    /// 6. `[]` The token program of the mint
+    /// 7. `[]` The PDA account
+    /// 8. `[]` The program's config account at `state::find_config_address`, which may not exist yet
+    /// 9. `[]` Any extra accounts required by the mint's transfer hook
+    CancelStream,
+    /// Creates the program's config account, making the signer its admin. Fails once the config
+    /// exists, so it should be run right after deploying the program
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer, writable]` The admin, it pays for the config account and has to be the
+    ///    program's upgrade authority
+    /// 1. `[writable]` The config account at `state::find_config_address`
+    /// 2. `[]` The system program
+    /// 3. `[]` The program's ProgramData account at `state::find_program_data_address`
+    InitializeConfig {
+        /// Share of both legs of every exchange taken as a protocol fee, at most
+        /// `state::MAX_PROTOCOL_FEE_BASIS_POINTS`
+        fee_basis_points: u16,
+        /// Owner of the token accounts fees are paid into
+        treasury: Pubkey,
+    },
+    /// Replaces the program's config
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The current admin
+    /// 1. `[writable]` The config account
+    UpdateConfig {
+        /// Who may update the config from now on
+        admin: Pubkey,
+        fee_basis_points: u16,
+        treasury: Pubkey,
+    },
+    /// Stops new trades and payouts until `Unpause`. Cancels, refunds, disputes and the other
+    /// ways a depositor gets their tokens back keep working
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The admin
+    /// 1. `[writable]` The config account
+    Pause,
+    /// Lets new trades through again after `Pause`
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The admin
+    /// 1. `[writable]` The config account
+    Unpause,
+    /// Takes several escrows asking for the same token in full, all of them or none. Each escrow
+    /// is settled as with `Exchange`, except that escrows offering SOL are refused
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[signer]` The account of the person taking the trades, writable when it pays in SOL
+    /// 1. For each escrow, in order, accounts 1 to 15 of `Exchange`
+    /// 2. `[]` The price accounts of the oracle priced escrows and any extra accounts required by
+    ///    the mints' transfer hooks, in any order
+    ExchangeMany {
+        /// How many escrows are taken, each with its own group of `EXCHANGE_ESCROW_ACCOUNTS`
+        count: u8,
+        /// The most the initializers may receive in total, transfer fees aside
+        max_total: u64,
+        /// As with `Exchange`
+        max_fee_basis_points: u16,
+    },
+    /// Accepts a trade on behalf of a taker who signed an intent for it rather than the
+    /// transaction, letting a relayer pay the transaction fees. The intent is the taker's ed25519
+    /// signature over `taker_intent_message`, checked by the ed25519 program in the instruction
+    /// right before this one. The taker pays through the PDA at
+    /// `state::find_intent_nonce_address`, which they approve as the delegate of their sending
+    /// token account beforehand, so escrows requesting SOL can't be taken this way
+    ///
+    ///
+    /// Accounts expected:
+    ///
+    /// 0. `[]` The account of the person taking the trade, writable when SOL is offered
+    /// 1. Accounts 1 to 15 of `Exchange`
+    /// 2. `[signer, writable]` The relayer, it pays for the nonce account if it doesn't exist yet
+    /// 3. `[writable]` The taker's nonce account at `state::find_intent_nonce_address`
+    /// 4. `[]` The instructions sysvar
+    /// 5. `[]` The escrow's oracle price account when it is priced off an oracle, and any extra
+    ///    accounts required by the mints' transfer hooks, in any order
+    ExchangeWithIntent {
+        /// As with `Exchange`
+        amount: u64,
+        /// As with `Exchange`
+        max_payment: u64,
+        /// As with `Exchange`
+        max_fee_basis_points: u16,
+        /// At least the taker's next nonce, which becomes one past it
+        nonce: u64,
+        /// Unix timestamp from which the intent can no longer be used
+        expires_at: UnixTimestamp,
     },
 }
 
+/// Number of accounts `ExchangeMany` takes for each escrow
+pub const EXCHANGE_ESCROW_ACCOUNTS: usize = 15;
+
+/// Start of every taker intent. No transaction message starts with 0xff, so a signed intent
+/// can't be replayed as a transaction
+pub const TAKER_INTENT_PREFIX: &[u8] = b"\xffescrow intent";
+
+/// Where the ed25519 program instruction built by `verify_taker_intent` puts its data, after the
+/// signature count, a padding byte and the offsets of its single signature
+pub(crate) const ED25519_OFFSETS_START: usize = 2;
+pub(crate) const ED25519_DATA_START: usize = 16;
+
 impl EscrowInstruction {
     /// Unpacks a byte buffer into a [EscrowInstruction](enum.EscrowInstruction.html).
     pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
         let (tag, rest) = input.split_first().ok_or(InvalidInstruction)?;
 
         Ok(match tag {
-            0 => Self::InitEscrow {
-                amount: Self::unpack_amount(rest)?,
-            },
-            1 => Self::Exchange {
-                amount: Self::unpack_amount(rest)?,
-            },
+            0 => {
+                let (offered_amount, rest) = Self::unpack_amount(rest)?;
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (expires_at, rest) = Self::unpack_timestamp_option(rest)?;
+                let (allowed_taker, rest) = Self::unpack_pubkey_option(rest)?;
+                let (auction, rest) = Self::unpack_auction_option(rest)?;
+                let (oracle, _rest) = Self::unpack_oracle_option(rest)?;
+                Self::InitEscrow {
+                    offered_amount,
+                    amount,
+                    expires_at,
+                    allowed_taker,
+                    auction,
+                    oracle,
+                }
+            }
+            1 => {
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (max_payment, rest) = Self::unpack_amount(rest)?;
+                let (max_fee_basis_points, _rest) = Self::unpack_basis_points(rest)?;
+                Self::Exchange {
+                    amount,
+                    max_payment,
+                    max_fee_basis_points,
+                }
+            }
+            2 => Self::Cancel,
+            3 => {
+                let (offers_sol, rest) = Self::unpack_bool(rest)?;
+                let (offered_amount, rest) = Self::unpack_amount(rest)?;
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (expires_at, rest) = Self::unpack_timestamp_option(rest)?;
+                let (allowed_taker, rest) = Self::unpack_pubkey_option(rest)?;
+                let (auction, rest) = Self::unpack_auction_option(rest)?;
+                let (oracle, _rest) = Self::unpack_oracle_option(rest)?;
+                Self::InitNativeEscrow {
+                    offers_sol,
+                    offered_amount,
+                    amount,
+                    expires_at,
+                    allowed_taker,
+                    auction,
+                    oracle,
+                }
+            }
+            4 => {
+                let (offered_amounts, rest) = Self::unpack_amounts(rest)?;
+                let (requested_amounts, rest) = Self::unpack_amounts(rest)?;
+                let (expires_at, rest) = Self::unpack_timestamp_option(rest)?;
+                let (allowed_taker, _rest) = Self::unpack_pubkey_option(rest)?;
+                Self::InitBundle {
+                    offered_amounts,
+                    requested_amounts,
+                    expires_at,
+                    allowed_taker,
+                }
+            }
+            5 => Self::ExchangeBundle,
+            6 => Self::CancelBundle,
+            7 => {
+                let (new_expected_amount, rest) = Self::unpack_amount(rest)?;
+                let (top_up_amount, rest) = Self::unpack_amount(rest)?;
+                let (withdraw_amount, _rest) = Self::unpack_amount(rest)?;
+                Self::Amend {
+                    new_expected_amount,
+                    top_up_amount,
+                    withdraw_amount,
+                }
+            }
+            8 => {
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (recipient, rest) = Self::unpack_pubkey(rest)?;
+                let (arbiter, _rest) = Self::unpack_pubkey(rest)?;
+                Self::InitArbiterEscrow {
+                    amount,
+                    recipient,
+                    arbiter,
+                }
+            }
+            9 => Self::Dispute,
+            10 => Self::Release,
+            11 => Self::Refund,
+            12 => {
+                let (amounts, rest) = Self::unpack_amounts(rest)?;
+                let (deadline, rest) = Self::unpack_amount(rest)?;
+                let (recipient, _rest) = Self::unpack_pubkey(rest)?;
+                Self::InitMilestoneEscrow {
+                    amounts,
+                    deadline: deadline as UnixTimestamp,
+                    recipient,
+                }
+            }
+            13 => Self::ApproveMilestone,
+            14 => Self::RefundMilestones,
+            15 => {
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (start_time, rest) = Self::unpack_amount(rest)?;
+                let (end_time, rest) = Self::unpack_amount(rest)?;
+                let (cliff_time, rest) = Self::unpack_timestamp_option(rest)?;
+                let (beneficiary, _rest) = Self::unpack_pubkey(rest)?;
+                Self::InitStream {
+                    amount,
+                    start_time: start_time as UnixTimestamp,
+                    end_time: end_time as UnixTimestamp,
+                    cliff_time,
+                    beneficiary,
+                }
+            }
+            16 => Self::Claim,
+            17 => Self::CancelStream,
+            18 => {
+                let (fee_basis_points, rest) = Self::unpack_basis_points(rest)?;
+                let (treasury, _rest) = Self::unpack_pubkey(rest)?;
+                Self::InitializeConfig {
+                    fee_basis_points,
+                    treasury,
+                }
+            }
+            19 => {
+                let (admin, rest) = Self::unpack_pubkey(rest)?;
+                let (fee_basis_points, rest) = Self::unpack_basis_points(rest)?;
+                let (treasury, _rest) = Self::unpack_pubkey(rest)?;
+                Self::UpdateConfig {
+                    admin,
+                    fee_basis_points,
+                    treasury,
+                }
+            }
+            20 => Self::Pause,
+            21 => Self::Unpause,
+            22 => {
+                let (&count, rest) = rest.split_first().ok_or(InvalidInstruction)?;
+                let (max_total, rest) = Self::unpack_amount(rest)?;
+                let (max_fee_basis_points, _rest) = Self::unpack_basis_points(rest)?;
+                Self::ExchangeMany {
+                    count,
+                    max_total,
+                    max_fee_basis_points,
+                }
+            }
+            23 => {
+                let (amount, rest) = Self::unpack_amount(rest)?;
+                let (max_payment, rest) = Self::unpack_amount(rest)?;
+                let (max_fee_basis_points, rest) = Self::unpack_basis_points(rest)?;
+                let (nonce, rest) = Self::unpack_amount(rest)?;
+                let (expires_at, _rest) = Self::unpack_amount(rest)?;
+                Self::ExchangeWithIntent {
+                    amount,
+                    max_payment,
+                    max_fee_basis_points,
+                    nonce,
+                    expires_at: expires_at as UnixTimestamp,
+                }
+            }
             _ => return Err(InvalidInstruction.into()),
         })
     }
 
-    fn unpack_amount(input: &[u8]) -> Result<u64, ProgramError> {
+    /// Packs a [EscrowInstruction](enum.EscrowInstruction.html) into a byte buffer.
+    pub fn pack(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(size_of::<Self>());
+        match self {
+            &Self::InitEscrow {
+                offered_amount,
+                amount,
+                expires_at,
+                ref allowed_taker,
+                auction,
+                ref oracle,
+            } => {
+                buf.push(0);
+                buf.extend_from_slice(&offered_amount.to_le_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_timestamp_option(expires_at, &mut buf);
+                Self::pack_pubkey_option(allowed_taker, &mut buf);
+                Self::pack_auction_option(auction, &mut buf);
+                Self::pack_oracle_option(oracle, &mut buf);
+            }
+            &Self::Exchange {
+                amount,
+                max_payment,
+                max_fee_basis_points,
+            } => {
+                buf.push(1);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&max_payment.to_le_bytes());
+                buf.extend_from_slice(&max_fee_basis_points.to_le_bytes());
+            }
+            Self::Cancel => buf.push(2),
+            &Self::InitNativeEscrow {
+                offers_sol,
+                offered_amount,
+                amount,
+                expires_at,
+                ref allowed_taker,
+                auction,
+                ref oracle,
+            } => {
+                buf.push(3);
+                buf.push(offers_sol.into());
+                buf.extend_from_slice(&offered_amount.to_le_bytes());
+                buf.extend_from_slice(&amount.to_le_bytes());
+                Self::pack_timestamp_option(expires_at, &mut buf);
+                Self::pack_pubkey_option(allowed_taker, &mut buf);
+                Self::pack_auction_option(auction, &mut buf);
+                Self::pack_oracle_option(oracle, &mut buf);
+            }
+            Self::InitBundle {
+                offered_amounts,
+                requested_amounts,
+                expires_at,
+                allowed_taker,
+            } => {
+                buf.push(4);
+                Self::pack_amounts(offered_amounts, &mut buf);
+                Self::pack_amounts(requested_amounts, &mut buf);
+                Self::pack_timestamp_option(*expires_at, &mut buf);
+                Self::pack_pubkey_option(allowed_taker, &mut buf);
+            }
+            Self::ExchangeBundle => buf.push(5),
+            Self::CancelBundle => buf.push(6),
+            &Self::Amend {
+                new_expected_amount,
+                top_up_amount,
+                withdraw_amount,
+            } => {
+                buf.push(7);
+                buf.extend_from_slice(&new_expected_amount.to_le_bytes());
+                buf.extend_from_slice(&top_up_amount.to_le_bytes());
+                buf.extend_from_slice(&withdraw_amount.to_le_bytes());
+            }
+            &Self::InitArbiterEscrow {
+                amount,
+                ref recipient,
+                ref arbiter,
+            } => {
+                buf.push(8);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(recipient.as_ref());
+                buf.extend_from_slice(arbiter.as_ref());
+            }
+            Self::Dispute => buf.push(9),
+            Self::Release => buf.push(10),
+            Self::Refund => buf.push(11),
+            Self::InitMilestoneEscrow {
+                amounts,
+                deadline,
+                recipient,
+            } => {
+                buf.push(12);
+                Self::pack_amounts(amounts, &mut buf);
+                buf.extend_from_slice(&deadline.to_le_bytes());
+                buf.extend_from_slice(recipient.as_ref());
+            }
+            Self::ApproveMilestone => buf.push(13),
+            Self::RefundMilestones => buf.push(14),
+            Self::InitStream {
+                amount,
+                start_time,
+                end_time,
+                cliff_time,
+                beneficiary,
+            } => {
+                buf.push(15);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&start_time.to_le_bytes());
+                buf.extend_from_slice(&end_time.to_le_bytes());
+                Self::pack_timestamp_option(*cliff_time, &mut buf);
+                buf.extend_from_slice(beneficiary.as_ref());
+            }
+            Self::Claim => buf.push(16),
+            Self::CancelStream => buf.push(17),
+            Self::InitializeConfig {
+                fee_basis_points,
+                treasury,
+            } => {
+                buf.push(18);
+                buf.extend_from_slice(&fee_basis_points.to_le_bytes());
+                buf.extend_from_slice(treasury.as_ref());
+            }
+            Self::UpdateConfig {
+                admin,
+                fee_basis_points,
+                treasury,
+            } => {
+                buf.push(19);
+                buf.extend_from_slice(admin.as_ref());
+                buf.extend_from_slice(&fee_basis_points.to_le_bytes());
+                buf.extend_from_slice(treasury.as_ref());
+            }
+            Self::Pause => buf.push(20),
+            Self::Unpause => buf.push(21),
+            &Self::ExchangeMany {
+                count,
+                max_total,
+                max_fee_basis_points,
+            } => {
+                buf.push(22);
+                buf.push(count);
+                buf.extend_from_slice(&max_total.to_le_bytes());
+                buf.extend_from_slice(&max_fee_basis_points.to_le_bytes());
+            }
+            &Self::ExchangeWithIntent {
+                amount,
+                max_payment,
+                max_fee_basis_points,
+                nonce,
+                expires_at,
+            } => {
+                buf.push(23);
+                buf.extend_from_slice(&amount.to_le_bytes());
+                buf.extend_from_slice(&max_payment.to_le_bytes());
+                buf.extend_from_slice(&max_fee_basis_points.to_le_bytes());
+                buf.extend_from_slice(&nonce.to_le_bytes());
+                buf.extend_from_slice(&expires_at.to_le_bytes());
+            }
+        }
+        buf
+    }
+
+    /// Whether the instruction may run while the program is paused: the ways a depositor gets
+    /// their tokens back out and managing the config. Anything that starts or fills a trade, or
+    /// pays out to anyone else, waits for `Unpause`. `CancelStream` checks the pause itself and
+    /// leaves what has vested to be claimed later
+    pub fn is_allowed_while_paused(&self) -> bool {
+        match self {
+            Self::InitEscrow { .. }
+            | Self::Exchange { .. }
+            | Self::InitNativeEscrow { .. }
+            | Self::InitBundle { .. }
+            | Self::Amend { .. }
+            | Self::ExchangeBundle
+            | Self::InitArbiterEscrow { .. }
+            | Self::Release
+            | Self::InitMilestoneEscrow { .. }
+            | Self::ApproveMilestone
+            | Self::InitStream { .. }
+            | Self::Claim
+            | Self::ExchangeMany { .. }
+            | Self::ExchangeWithIntent { .. } => false,
+            Self::Cancel
+            | Self::CancelBundle
+            | Self::Dispute
+            | Self::Refund
+            | Self::RefundMilestones
+            | Self::CancelStream
+            | Self::InitializeConfig { .. }
+            | Self::UpdateConfig { .. }
+            | Self::Pause
+            | Self::Unpause => true,
+        }
+    }
+
+    fn unpack_amount(input: &[u8]) -> Result<(u64, &[u8]), ProgramError> {
         let amount = input
             .get(..8)
             .and_then(|slice| slice.try_into().ok())
             .map(u64::from_le_bytes)
             .ok_or(InvalidInstruction)?;
-        Ok(amount)
+        Ok((amount, &input[8..]))
+    }
+
+    /// Unpacks a little endian u16 in basis points
+    fn unpack_basis_points(input: &[u8]) -> Result<(u16, &[u8]), ProgramError> {
+        let basis_points = input
+            .get(..2)
+            .and_then(|slice| slice.try_into().ok())
+            .map(u16::from_le_bytes)
+            .ok_or(InvalidInstruction)?;
+        Ok((basis_points, &input[2..]))
+    }
+
+    /// Unpacks a one byte count followed by that many amounts
+    fn unpack_amounts(input: &[u8]) -> Result<(Vec<u64>, &[u8]), ProgramError> {
+        let (&count, mut rest) = input.split_first().ok_or(InvalidInstruction)?;
+        let mut amounts = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let (amount, tail) = Self::unpack_amount(rest)?;
+            amounts.push(amount);
+            rest = tail;
+        }
+        Ok((amounts, rest))
+    }
+
+    fn pack_amounts(amounts: &[u64], buf: &mut Vec<u8>) {
+        buf.push(amounts.len() as u8);
+        for amount in amounts {
+            buf.extend_from_slice(&amount.to_le_bytes());
+        }
+    }
+
+    fn unpack_bool(input: &[u8]) -> Result<(bool, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((false, rest)),
+            Some((&1, rest)) => Ok((true, rest)),
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn unpack_timestamp_option(
+        input: &[u8],
+    ) -> Result<(Option<UnixTimestamp>, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((None, rest)),
+            Some((&1, rest)) => {
+                let timestamp = rest
+                    .get(..8)
+                    .and_then(|slice| slice.try_into().ok())
+                    .map(UnixTimestamp::from_le_bytes)
+                    .ok_or(InvalidInstruction)?;
+                Ok((Some(timestamp), &rest[8..]))
+            }
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn unpack_pubkey(input: &[u8]) -> Result<(Pubkey, &[u8]), ProgramError> {
+        if input.len() < 32 {
+            return Err(InvalidInstruction.into());
+        }
+        let (key, rest) = input.split_at(32);
+        let pk = Pubkey::new_from_array(key.try_into().map_err(|_| InvalidInstruction)?);
+        Ok((pk, rest))
+    }
+
+    fn unpack_pubkey_option(input: &[u8]) -> Result<(Option<Pubkey>, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((None, rest)),
+            Some((&1, rest)) if rest.len() >= 32 => {
+                let (key, rest) = rest.split_at(32);
+                let pk = Pubkey::new_from_array(key.try_into().map_err(|_| InvalidInstruction)?);
+                Ok((Some(pk), rest))
+            }
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn unpack_auction_option(input: &[u8]) -> Result<(Option<Auction>, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((None, rest)),
+            Some((&1, rest)) => {
+                let (end_amount, rest) = Self::unpack_amount(rest)?;
+                let (start_time, rest) = Self::unpack_amount(rest)?;
+                let (end_time, rest) = Self::unpack_amount(rest)?;
+                let auction = Auction {
+                    end_amount,
+                    start_time: start_time as UnixTimestamp,
+                    end_time: end_time as UnixTimestamp,
+                };
+                Ok((Some(auction), rest))
+            }
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn pack_auction_option(value: Option<Auction>, buf: &mut Vec<u8>) {
+        match value {
+            Some(auction) => {
+                buf.push(1);
+                buf.extend_from_slice(&auction.end_amount.to_le_bytes());
+                buf.extend_from_slice(&auction.start_time.to_le_bytes());
+                buf.extend_from_slice(&auction.end_time.to_le_bytes());
+            }
+            None => buf.push(0),
+        }
+    }
+
+    fn unpack_oracle_option(input: &[u8]) -> Result<(Option<OraclePricing>, &[u8]), ProgramError> {
+        match input.split_first() {
+            Some((&0, rest)) => Ok((None, rest)),
+            Some((&1, rest)) => {
+                let (&kind, rest) = rest.split_first().ok_or(InvalidInstruction)?;
+                let (price_account, rest) = Self::unpack_pubkey(rest)?;
+                let (feed_id, rest) = Self::unpack_pubkey(rest)?;
+                let (offset_basis_points, rest) = Self::unpack_basis_points(rest)?;
+                let (max_age, rest) = Self::unpack_amount(rest)?;
+                let (max_confidence_basis_points, rest) = Self::unpack_basis_points(rest)?;
+                let oracle = OraclePricing {
+                    kind: OracleKind::from_u8(kind).ok_or(InvalidInstruction)?,
+                    price_account,
+                    feed_id: feed_id.to_bytes(),
+                    offset_basis_points: offset_basis_points as i16,
+                    max_age,
+                    max_confidence_basis_points,
+                };
+                Ok((Some(oracle), rest))
+            }
+            _ => Err(InvalidInstruction.into()),
+        }
+    }
+
+    fn pack_oracle_option(value: &Option<OraclePricing>, buf: &mut Vec<u8>) {
+        match value {
+            Some(oracle) => {
+                buf.push(1);
+                buf.push(oracle.kind as u8);
+                buf.extend_from_slice(oracle.price_account.as_ref());
+                buf.extend_from_slice(&oracle.feed_id);
+                buf.extend_from_slice(&oracle.offset_basis_points.to_le_bytes());
+                buf.extend_from_slice(&oracle.max_age.to_le_bytes());
+                buf.extend_from_slice(&oracle.max_confidence_basis_points.to_le_bytes());
+            }
+            None => buf.push(0),
+        }
+    }
+
+    fn pack_pubkey_option(value: &Option<Pubkey>, buf: &mut Vec<u8>) {
+        match value {
+            Some(key) => {
+                buf.push(1);
+                buf.extend_from_slice(key.as_ref());
+            }
+            None => buf.push(0),
+        }
+    }
+
+    fn pack_timestamp_option(value: Option<UnixTimestamp>, buf: &mut Vec<u8>) {
+        match value {
+            Some(timestamp) => {
+                buf.push(1);
+                buf.extend_from_slice(&timestamp.to_le_bytes());
+            }
+            None => buf.push(0),
+        }
+    }
+}
+
+/// Creates an `InitEscrow` instruction offering `offered_amount` of `offered_mint` from
+/// `initializers_token_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_escrow(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_account: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+    offered_amount: u64,
+    amount: u64,
+    expires_at: Option<UnixTimestamp>,
+    allowed_taker: Option<Pubkey>,
+    auction: Option<Auction>,
+    oracle: Option<OraclePricing>,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: init_with_vault_accounts(
+            program_id,
+            token_program_id,
+            initializer,
+            initializers_token_account,
+            token_to_receive_account,
+            escrow_account,
+            offered_mint,
+        ),
+        data: EscrowInstruction::InitEscrow {
+            offered_amount,
+            amount,
+            expires_at,
+            allowed_taker,
+            auction,
+            oracle,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `InitNativeEscrow` instruction offering `lamports` for `amount` of the token held
+/// by `token_to_receive_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_sol_offer(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    escrow_account: &Pubkey,
+    lamports: u64,
+    amount: u64,
+    expires_at: Option<UnixTimestamp>,
+    allowed_taker: Option<Pubkey>,
+    auction: Option<Auction>,
+    oracle: Option<OraclePricing>,
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(pda, false),
+        AccountMeta::new_readonly(*token_to_receive_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitNativeEscrow {
+            offers_sol: true,
+            offered_amount: lamports,
+            amount,
+            expires_at,
+            allowed_taker,
+            auction,
+            oracle,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `InitNativeEscrow` instruction offering `offered_amount` of `offered_mint` from
+/// `initializers_token_account` for `lamports` paid to `lamports_recipient`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_sol_request(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_account: &Pubkey,
+    lamports_recipient: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+    offered_amount: u64,
+    lamports: u64,
+    expires_at: Option<UnixTimestamp>,
+    allowed_taker: Option<Pubkey>,
+    auction: Option<Auction>,
+    oracle: Option<OraclePricing>,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: init_with_vault_accounts(
+            program_id,
+            token_program_id,
+            initializer,
+            initializers_token_account,
+            lamports_recipient,
+            escrow_account,
+            offered_mint,
+        ),
+        data: EscrowInstruction::InitNativeEscrow {
+            offers_sol: false,
+            offered_amount,
+            amount: lamports,
+            expires_at,
+            allowed_taker,
+            auction,
+            oracle,
+        }
+        .pack(),
+    }
+}
+
+fn init_with_vault_accounts(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_account: &Pubkey,
+    token_to_receive_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+) -> Vec<AccountMeta> {
+    let (vault, _bump_seed) = find_vault_address(program_id, escrow_account);
+    vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*initializers_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new_readonly(*token_to_receive_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*offered_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ]
+}
+
+/// Creates an `Exchange` instruction. For a native SOL leg pass [`NATIVE_MINT`] as the mint and
+/// the system program as its token program. Extra accounts needed by transfer hooks can be
+/// appended to the returned instruction. `max_payment` is usually what [`Escrow::payment_for`]
+/// returns for `amount` when the taker reads the escrow, and `max_fee_basis_points` the
+/// config's fee at that time. `treasury_accounts` are the
+/// treasury's accounts for the offered and requested mints, needed once a protocol fee is
+/// configured; without them the program id stands in. `price_account` is the escrow's oracle
+/// price account when it is priced off an oracle, appended after the fixed accounts.
+///
+/// [`Escrow::payment_for`]: crate::state::Escrow::payment_for
+#[allow(clippy::too_many_arguments)]
+pub fn exchange(
+    program_id: &Pubkey,
+    offered_token_program_id: &Pubkey,
+    requested_token_program_id: &Pubkey,
+    taker: &Pubkey,
+    takers_sending_token_account: &Pubkey,
+    takers_token_to_receive_account: &Pubkey,
+    pdas_temp_token_account: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_to_receive_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+    requested_mint: &Pubkey,
+    amount: u64,
+    max_payment: u64,
+    max_fee_basis_points: u16,
+    treasury_accounts: Option<(&Pubkey, &Pubkey)>,
+    price_account: Option<&Pubkey>,
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    let (config, _bump_seed) = find_config_address(program_id);
+    let (treasury_offered_account, treasury_requested_account) =
+        treasury_accounts.unwrap_or((program_id, program_id));
+    let mut accounts = vec![
+        AccountMeta::new(*taker, true),
+        AccountMeta::new(*takers_sending_token_account, false),
+        AccountMeta::new(*takers_token_to_receive_account, false),
+        AccountMeta::new(*pdas_temp_token_account, false),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*initializers_token_to_receive_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*offered_token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        mint_meta(offered_mint),
+        AccountMeta::new_readonly(*requested_mint, false),
+        AccountMeta::new_readonly(*requested_token_program_id, false),
+        AccountMeta::new_readonly(config, false),
+        treasury_meta(program_id, treasury_offered_account),
+        treasury_meta(program_id, treasury_requested_account),
+    ];
+    if let Some(price_account) = price_account {
+        accounts.push(AccountMeta::new_readonly(*price_account, false));
+    }
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Exchange {
+            amount,
+            max_payment,
+            max_fee_basis_points,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `ExchangeMany` instruction out of `exchanges` built with [`exchange`] for the same
+/// `taker`, each taking an escrow in full. Their amounts and maximum payments are dropped in
+/// favour of `max_total` and their fee limits in favour of `max_fee_basis_points`, and any
+/// transfer hook accounts appended to them are kept.
+///
+/// # Panics
+///
+/// If there are more than `u8::MAX` exchanges
+pub fn exchange_many(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    exchanges: &[Instruction],
+    max_total: u64,
+    max_fee_basis_points: u16,
+) -> Instruction {
+    let mut accounts = vec![AccountMeta::new(*taker, true)];
+    for exchange in exchanges {
+        accounts.extend_from_slice(&exchange.accounts[1..=EXCHANGE_ESCROW_ACCOUNTS]);
+    }
+    for exchange in exchanges {
+        accounts.extend_from_slice(&exchange.accounts[EXCHANGE_ESCROW_ACCOUNTS + 1..]);
+    }
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeMany {
+            count: exchanges.len().try_into().unwrap(),
+            max_total,
+            max_fee_basis_points,
+        }
+        .pack(),
+    }
+}
+
+/// The message a taker signs to let a relayer take `amount` of the escrow for at most
+/// `max_payment` and a protocol fee of at most `max_fee_basis_points` on their behalf with
+/// `ExchangeWithIntent`.
+pub fn taker_intent_message(
+    escrow_account: &Pubkey,
+    amount: u64,
+    max_payment: u64,
+    max_fee_basis_points: u16,
+    nonce: u64,
+    expires_at: UnixTimestamp,
+) -> Vec<u8> {
+    let mut message = TAKER_INTENT_PREFIX.to_vec();
+    message.extend_from_slice(escrow_account.as_ref());
+    message.extend_from_slice(&amount.to_le_bytes());
+    message.extend_from_slice(&max_payment.to_le_bytes());
+    message.extend_from_slice(&max_fee_basis_points.to_le_bytes());
+    message.extend_from_slice(&nonce.to_le_bytes());
+    message.extend_from_slice(&expires_at.to_le_bytes());
+    message
+}
+
+/// Creates the ed25519 program instruction checking the taker's `signature` over `message`,
+/// which has to go right before the `ExchangeWithIntent` it authorizes.
+pub fn verify_taker_intent(taker: &Pubkey, signature: &[u8; 64], message: &[u8]) -> Instruction {
+    let public_key_offset = ED25519_DATA_START;
+    let signature_offset = public_key_offset + taker.as_ref().len();
+    let message_offset = signature_offset + signature.len();
+    let mut data = vec![1, 0];
+    // Offsets of the signature, the public key and the message, each within this instruction
+    for field in [
+        signature_offset as u16,
+        u16::MAX,
+        public_key_offset as u16,
+        u16::MAX,
+        message_offset as u16,
+        message.len() as u16,
+        u16::MAX,
+    ] {
+        data.extend_from_slice(&field.to_le_bytes());
+    }
+    data.extend_from_slice(taker.as_ref());
+    data.extend_from_slice(signature);
+    data.extend_from_slice(message);
+
+    Instruction {
+        program_id: ed25519_program::id(),
+        accounts: vec![],
+        data,
+    }
+}
+
+/// Creates an `ExchangeWithIntent` instruction out of an `exchange` built with [`exchange`] for
+/// the taker, submitted and signed by `relayer` instead. Any transfer hook accounts appended to
+/// `exchange` are kept.
+///
+/// # Panics
+///
+/// If `exchange` is not an `Exchange` instruction
+pub fn exchange_with_intent(
+    program_id: &Pubkey,
+    exchange: &Instruction,
+    relayer: &Pubkey,
+    nonce: u64,
+    expires_at: UnixTimestamp,
+) -> Instruction {
+    let Ok(EscrowInstruction::Exchange {
+        amount,
+        max_payment,
+        max_fee_basis_points,
+    }) = EscrowInstruction::unpack(&exchange.data)
+    else {
+        panic!("not an Exchange instruction");
+    };
+    let taker = exchange.accounts[0].pubkey;
+    let (nonce_account, _bump_seed) = find_intent_nonce_address(program_id, &taker);
+    let mut accounts = vec![AccountMeta::new(taker, false)];
+    accounts.extend_from_slice(&exchange.accounts[1..=EXCHANGE_ESCROW_ACCOUNTS]);
+    accounts.extend([
+        AccountMeta::new(*relayer, true),
+        AccountMeta::new(nonce_account, false),
+        AccountMeta::new_readonly(sysvar::instructions::id(), false),
+    ]);
+    accounts.extend_from_slice(&exchange.accounts[EXCHANGE_ESCROW_ACCOUNTS + 1..]);
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeWithIntent {
+            amount,
+            max_payment,
+            max_fee_basis_points,
+            nonce,
+            expires_at,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `Amend` instruction. `initializers_token_account` is ignored when SOL is offered.
+#[allow(clippy::too_many_arguments)]
+pub fn amend(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    pdas_temp_token_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+    new_expected_amount: u64,
+    top_up_amount: u64,
+    withdraw_amount: u64,
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new(*pdas_temp_token_account, false),
+        AccountMeta::new(*initializers_token_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        AccountMeta::new_readonly(*offered_mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Amend {
+            new_expected_amount,
+            top_up_amount,
+            withdraw_amount,
+        }
+        .pack(),
+    }
+}
+
+/// Creates a `Cancel` instruction signed by the initializer.
+pub fn cancel(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    pdas_temp_token_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+) -> Instruction {
+    cancel_with_signer(
+        program_id,
+        token_program_id,
+        initializer,
+        pdas_temp_token_account,
+        initializers_token_account,
+        escrow_account,
+        offered_mint,
+        true,
+    )
+}
+
+/// Creates a `Cancel` instruction refunding an expired escrow, which any fee payer can submit
+/// without the initializer's signature.
+pub fn refund_expired(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    pdas_temp_token_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+) -> Instruction {
+    cancel_with_signer(
+        program_id,
+        token_program_id,
+        initializer,
+        pdas_temp_token_account,
+        initializers_token_account,
+        escrow_account,
+        offered_mint,
+        false,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn cancel_with_signer(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    pdas_temp_token_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    offered_mint: &Pubkey,
+    initializer_is_signer: bool,
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    let accounts = vec![
+        AccountMeta::new(*initializer, initializer_is_signer),
+        AccountMeta::new(*pdas_temp_token_account, false),
+        AccountMeta::new(*initializers_token_account, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        mint_meta(offered_mint),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Cancel.pack(),
+    }
+}
+
+/// A token leg of a bundle as passed to the bundle instruction builders
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BundleLeg {
+    pub mint: Pubkey,
+    pub token_program_id: Pubkey,
+    /// The token account on the caller's side of the leg, see each builder
+    pub token_account: Pubkey,
+}
+
+/// Creates an `InitBundle` instruction. Each offered leg's token account is the initializer's
+/// account the tokens are taken from, and each requested token is paid into the matching
+/// `initializers_token_to_receive_accounts` entry.
+#[allow(clippy::too_many_arguments)]
+pub fn init_bundle(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    bundle_account: &Pubkey,
+    offered: &[BundleLeg],
+    offered_amounts: &[u64],
+    initializers_token_to_receive_accounts: &[Pubkey],
+    requested_amounts: &[u64],
+    expires_at: Option<UnixTimestamp>,
+    allowed_taker: Option<Pubkey>,
+) -> Instruction {
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*bundle_account, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ];
+    for (index, leg) in offered.iter().enumerate() {
+        let (vault, _bump_seed) =
+            find_bundle_vault_address(program_id, bundle_account, index as u8);
+        accounts.extend([
+            AccountMeta::new(leg.token_account, false),
+            AccountMeta::new(vault, false),
+            AccountMeta::new_readonly(leg.mint, false),
+            AccountMeta::new_readonly(leg.token_program_id, false),
+        ]);
+    }
+    accounts.extend(
+        initializers_token_to_receive_accounts
+            .iter()
+            .map(|account| AccountMeta::new_readonly(*account, false)),
+    );
+    accounts.push(config_meta(program_id));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitBundle {
+            offered_amounts: offered_amounts.to_vec(),
+            requested_amounts: requested_amounts.to_vec(),
+            expires_at,
+            allowed_taker,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `ExchangeBundle` instruction. Each offered leg's token account is the taker's
+/// account receiving the tokens, each requested leg's the taker's account paying them into the
+/// matching `initializers_token_to_receive_accounts` entry.
+pub fn exchange_bundle(
+    program_id: &Pubkey,
+    taker: &Pubkey,
+    initializer: &Pubkey,
+    bundle_account: &Pubkey,
+    offered: &[BundleLeg],
+    requested: &[BundleLeg],
+    initializers_token_to_receive_accounts: &[Pubkey],
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, bundle_account, initializer);
+    let mut accounts = vec![
+        AccountMeta::new_readonly(*taker, true),
+        AccountMeta::new(*initializer, false),
+        AccountMeta::new(*bundle_account, false),
+        AccountMeta::new_readonly(pda, false),
+    ];
+    accounts.extend(bundle_vault_accounts(program_id, bundle_account, offered));
+    for (leg, initializers_account) in requested.iter().zip(initializers_token_to_receive_accounts)
+    {
+        accounts.extend([
+            AccountMeta::new(leg.token_account, false),
+            AccountMeta::new(*initializers_account, false),
+            AccountMeta::new_readonly(leg.mint, false),
+            AccountMeta::new_readonly(leg.token_program_id, false),
+        ]);
+    }
+    accounts.push(config_meta(program_id));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ExchangeBundle.pack(),
+    }
+}
+
+/// Creates a `CancelBundle` instruction. Each offered leg's token account is the initializer's
+/// account getting the tokens back. Without `initializer_is_signer` it only succeeds once the
+/// bundle has expired.
+pub fn cancel_bundle(
+    program_id: &Pubkey,
+    initializer: &Pubkey,
+    bundle_account: &Pubkey,
+    offered: &[BundleLeg],
+    initializer_is_signer: bool,
+) -> Instruction {
+    let (pda, _bump_seed) = find_escrow_pda(program_id, bundle_account, initializer);
+    let mut accounts = vec![
+        AccountMeta::new(*initializer, initializer_is_signer),
+        AccountMeta::new(*bundle_account, false),
+        AccountMeta::new_readonly(pda, false),
+    ];
+    accounts.extend(bundle_vault_accounts(program_id, bundle_account, offered));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::CancelBundle.pack(),
+    }
+}
+
+fn bundle_vault_accounts(
+    program_id: &Pubkey,
+    bundle_account: &Pubkey,
+    offered: &[BundleLeg],
+) -> Vec<AccountMeta> {
+    offered
+        .iter()
+        .enumerate()
+        .flat_map(|(index, leg)| {
+            let (vault, _bump_seed) =
+                find_bundle_vault_address(program_id, bundle_account, index as u8);
+            [
+                AccountMeta::new(vault, false),
+                AccountMeta::new(leg.token_account, false),
+                AccountMeta::new(leg.mint, false),
+                AccountMeta::new_readonly(leg.token_program_id, false),
+            ]
+        })
+        .collect()
+}
+
+/// The offered mint is writable so withheld transfer fees can be harvested into it, except for
+/// the system program standing in for native SOL
+fn mint_meta(mint: &Pubkey) -> AccountMeta {
+    if *mint == NATIVE_MINT {
+        AccountMeta::new_readonly(*mint, false)
+    } else {
+        AccountMeta::new(*mint, false)
+    }
+}
+
+/// The program's config account, read by every instruction that is refused or changes course
+/// while paused
+fn config_meta(program_id: &Pubkey) -> AccountMeta {
+    AccountMeta::new_readonly(find_config_address(program_id).0, false)
+}
+
+/// The program id stands in for a treasury account and can't be writable
+fn treasury_meta(program_id: &Pubkey, account: &Pubkey) -> AccountMeta {
+    if account == program_id {
+        AccountMeta::new_readonly(*account, false)
+    } else {
+        AccountMeta::new(*account, false)
+    }
+}
+
+/// Creates an `InitArbiterEscrow` instruction paying `amount` of `mint` from
+/// `initializers_token_account` into a new vault held for `recipient`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_arbiter_escrow(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    recipient: &Pubkey,
+    arbiter: &Pubkey,
+) -> Instruction {
+    let (vault, _bump_seed) = find_vault_address(program_id, escrow_account);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*initializers_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitArbiterEscrow {
+            amount,
+            recipient: *recipient,
+            arbiter: *arbiter,
+        }
+        .pack(),
+    }
+}
+
+/// Creates a `Dispute` instruction signed by `disputing_party`, the initializer or the recipient.
+pub fn dispute(
+    program_id: &Pubkey,
+    disputing_party: &Pubkey,
+    escrow_account: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*disputing_party, true),
+            AccountMeta::new(*escrow_account, false),
+        ],
+        data: EscrowInstruction::Dispute.pack(),
+    }
+}
+
+/// Creates a `Release` instruction signed by the parties in `approvers`, paying the vault into
+/// `recipients_token_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn release(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    recipient: &Pubkey,
+    arbiter: &Pubkey,
+    escrow_account: &Pubkey,
+    recipients_token_account: &Pubkey,
+    mint: &Pubkey,
+    approvers: &[Pubkey],
+) -> Instruction {
+    let mut accounts = settle_accounts(
+        program_id,
+        token_program_id,
+        initializer,
+        recipient,
+        arbiter,
+        escrow_account,
+        recipients_token_account,
+        mint,
+        approvers,
+    );
+    accounts.push(config_meta(program_id));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Release.pack(),
+    }
+}
+
+/// Creates a `Refund` instruction signed by the parties in `approvers`, returning the vault to
+/// `initializers_token_account`.
+#[allow(clippy::too_many_arguments)]
+pub fn refund(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    recipient: &Pubkey,
+    arbiter: &Pubkey,
+    escrow_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    mint: &Pubkey,
+    approvers: &[Pubkey],
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: settle_accounts(
+            program_id,
+            token_program_id,
+            initializer,
+            recipient,
+            arbiter,
+            escrow_account,
+            initializers_token_account,
+            mint,
+            approvers,
+        ),
+        data: EscrowInstruction::Refund.pack(),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn settle_accounts(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    recipient: &Pubkey,
+    arbiter: &Pubkey,
+    escrow_account: &Pubkey,
+    destination: &Pubkey,
+    mint: &Pubkey,
+    approvers: &[Pubkey],
+) -> Vec<AccountMeta> {
+    let (vault, _bump_seed) = find_vault_address(program_id, escrow_account);
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    vec![
+        AccountMeta::new(*initializer, approvers.contains(initializer)),
+        AccountMeta::new_readonly(*recipient, approvers.contains(recipient)),
+        AccountMeta::new_readonly(*arbiter, approvers.contains(arbiter)),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new(*mint, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+    ]
+}
+
+/// Creates an `InitMilestoneEscrow` instruction paying the sum of `amounts` of `mint` from
+/// `initializers_token_account` into a new vault held for `recipient`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_milestone_escrow(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializers_token_account: &Pubkey,
+    escrow_account: &Pubkey,
+    mint: &Pubkey,
+    amounts: &[u64],
+    deadline: UnixTimestamp,
+    recipient: &Pubkey,
+) -> Instruction {
+    let (vault, _bump_seed) = find_vault_address(program_id, escrow_account);
+    let accounts = vec![
+        AccountMeta::new(*initializer, true),
+        AccountMeta::new(*initializers_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitMilestoneEscrow {
+            amounts: amounts.to_vec(),
+            deadline,
+            recipient: *recipient,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `ApproveMilestone` instruction signed by the initializer, paying the next milestone
+/// into `recipients_token_account`.
+pub fn approve_milestone(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    recipients_token_account: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    let mut accounts = milestone_vault_accounts(
+        program_id,
+        token_program_id,
+        initializer,
+        true,
+        escrow_account,
+        recipients_token_account,
+        mint,
+    );
+    accounts.push(config_meta(program_id));
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::ApproveMilestone.pack(),
+    }
+}
+
+/// Creates a `RefundMilestones` instruction returning the unreleased milestones to
+/// `initializers_token_account`, which any fee payer can submit once the deadline has passed.
+pub fn refund_milestones(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    escrow_account: &Pubkey,
+    initializers_token_account: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *program_id,
+        accounts: milestone_vault_accounts(
+            program_id,
+            token_program_id,
+            initializer,
+            false,
+            escrow_account,
+            initializers_token_account,
+            mint,
+        ),
+        data: EscrowInstruction::RefundMilestones.pack(),
+    }
+}
+
+fn milestone_vault_accounts(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    initializer: &Pubkey,
+    initializer_is_signer: bool,
+    escrow_account: &Pubkey,
+    destination: &Pubkey,
+    mint: &Pubkey,
+) -> Vec<AccountMeta> {
+    let (vault, _bump_seed) = find_vault_address(program_id, escrow_account);
+    let (pda, _bump_seed) = find_escrow_pda(program_id, escrow_account, initializer);
+    vec![
+        AccountMeta::new(*initializer, initializer_is_signer),
+        AccountMeta::new(*escrow_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*destination, false),
+        AccountMeta::new(*mint, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+    ]
+}
+
+/// Creates an `InitStream` instruction streaming `amount` of `mint` from
+/// `funders_token_account` to `beneficiary` between `start_time` and `end_time`.
+#[allow(clippy::too_many_arguments)]
+pub fn init_stream(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    funder: &Pubkey,
+    funders_token_account: &Pubkey,
+    stream_account: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    start_time: UnixTimestamp,
+    end_time: UnixTimestamp,
+    cliff_time: Option<UnixTimestamp>,
+    beneficiary: &Pubkey,
+) -> Instruction {
+    let (vault, _bump_seed) = find_vault_address(program_id, stream_account);
+    let accounts = vec![
+        AccountMeta::new(*funder, true),
+        AccountMeta::new(*funders_token_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*stream_account, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitStream {
+            amount,
+            start_time,
+            end_time,
+            cliff_time,
+            beneficiary: *beneficiary,
+        }
+        .pack(),
+    }
+}
+
+/// Creates a `Claim` instruction paying what has vested into `beneficiarys_token_account`.
+pub fn claim(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    beneficiary: &Pubkey,
+    funder: &Pubkey,
+    stream_account: &Pubkey,
+    beneficiarys_token_account: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    let (vault, _bump_seed) = find_vault_address(program_id, stream_account);
+    let (pda, _bump_seed) = find_escrow_pda(program_id, stream_account, funder);
+    let accounts = vec![
+        AccountMeta::new_readonly(*beneficiary, true),
+        AccountMeta::new(*funder, false),
+        AccountMeta::new(*stream_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*beneficiarys_token_account, false),
+        AccountMeta::new(*mint, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::Claim.pack(),
+    }
+}
+
+/// Creates a `CancelStream` instruction splitting the vault between
+/// `beneficiarys_token_account` and `funders_token_account`.
+pub fn cancel_stream(
+    program_id: &Pubkey,
+    token_program_id: &Pubkey,
+    funder: &Pubkey,
+    stream_account: &Pubkey,
+    funders_token_account: &Pubkey,
+    beneficiarys_token_account: &Pubkey,
+    mint: &Pubkey,
+) -> Instruction {
+    let (vault, _bump_seed) = find_vault_address(program_id, stream_account);
+    let (pda, _bump_seed) = find_escrow_pda(program_id, stream_account, funder);
+    let accounts = vec![
+        AccountMeta::new(*funder, true),
+        AccountMeta::new(*stream_account, false),
+        AccountMeta::new(vault, false),
+        AccountMeta::new(*funders_token_account, false),
+        AccountMeta::new(*beneficiarys_token_account, false),
+        AccountMeta::new(*mint, false),
+        AccountMeta::new_readonly(*token_program_id, false),
+        AccountMeta::new_readonly(pda, false),
+        config_meta(program_id),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::CancelStream.pack(),
+    }
+}
+
+/// Creates an `InitializeConfig` instruction making `admin`, the program's upgrade authority,
+/// the admin of the program's config.
+pub fn initialize_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    fee_basis_points: u16,
+    treasury: &Pubkey,
+) -> Instruction {
+    let (config, _bump_seed) = find_config_address(program_id);
+    let (program_data, _bump_seed) = find_program_data_address(program_id);
+    let accounts = vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new(config, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        AccountMeta::new_readonly(program_data, false),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::InitializeConfig {
+            fee_basis_points,
+            treasury: *treasury,
+        }
+        .pack(),
+    }
+}
+
+/// Creates an `UpdateConfig` instruction signed by the current `admin`, handing the config to
+/// `new_admin`.
+pub fn update_config(
+    program_id: &Pubkey,
+    admin: &Pubkey,
+    new_admin: &Pubkey,
+    fee_basis_points: u16,
+    treasury: &Pubkey,
+) -> Instruction {
+    let (config, _bump_seed) = find_config_address(program_id);
+    let accounts = vec![
+        AccountMeta::new_readonly(*admin, true),
+        AccountMeta::new(config, false),
+    ];
+
+    Instruction {
+        program_id: *program_id,
+        accounts,
+        data: EscrowInstruction::UpdateConfig {
+            admin: *new_admin,
+            fee_basis_points,
+            treasury: *treasury,
+        }
+        .pack(),
+    }
+}
+
+/// Creates a `Pause` instruction signed by the `admin`.
+pub fn pause(program_id: &Pubkey, admin: &Pubkey) -> Instruction {
+    set_paused(program_id, admin, EscrowInstruction::Pause)
+}
+
+/// Creates an `Unpause` instruction signed by the `admin`.
+pub fn unpause(program_id: &Pubkey, admin: &Pubkey) -> Instruction {
+    set_paused(program_id, admin, EscrowInstruction::Unpause)
+}
+
+fn set_paused(program_id: &Pubkey, admin: &Pubkey, instruction: EscrowInstruction) -> Instruction {
+    let (config, _bump_seed) = find_config_address(program_id);
+    Instruction {
+        program_id: *program_id,
+        accounts: vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(config, false),
+        ],
+        data: instruction.pack(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instruction_packing() {
+        let check = EscrowInstruction::InitEscrow {
+            offered_amount: 10,
+            amount: 42,
+            expires_at: None,
+            allowed_taker: None,
+            auction: None,
+            oracle: None,
+        };
+        let packed = check.pack();
+        let mut expect = vec![0u8];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let allowed_taker = Pubkey::new_unique();
+        let check = EscrowInstruction::InitEscrow {
+            offered_amount: 10,
+            amount: 42,
+            expires_at: Some(-1),
+            allowed_taker: Some(allowed_taker),
+            auction: None,
+            oracle: None,
+        };
+        let packed = check.pack();
+        let mut expect = vec![0u8];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.push(1);
+        expect.extend_from_slice(&(-1i64).to_le_bytes());
+        expect.push(1);
+        expect.extend_from_slice(allowed_taker.as_ref());
+        expect.extend_from_slice(&[0, 0]);
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::Exchange {
+            amount: u64::MAX,
+            max_payment: 42,
+            max_fee_basis_points: 30,
+        };
+        let packed = check.pack();
+        let mut expect = vec![1u8];
+        expect.extend_from_slice(&u64::MAX.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(&30u16.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::Cancel;
+        let packed = check.pack();
+        let expect = vec![2u8];
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitNativeEscrow {
+            offers_sol: true,
+            offered_amount: 1_000_000,
+            amount: 42,
+            expires_at: None,
+            allowed_taker: None,
+            auction: None,
+            oracle: None,
+        };
+        let packed = check.pack();
+        let mut expect = vec![3u8, 1];
+        expect.extend_from_slice(&1_000_000u64.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(&[0, 0, 0, 0]);
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitNativeEscrow {
+            offers_sol: false,
+            offered_amount: 10,
+            amount: 42,
+            expires_at: Some(7),
+            allowed_taker: None,
+            auction: Some(Auction {
+                end_amount: 21,
+                start_time: 7,
+                end_time: 107,
+            }),
+            oracle: None,
+        };
+        let packed = check.pack();
+        let mut expect = vec![3u8, 0];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.push(1);
+        expect.extend_from_slice(&7i64.to_le_bytes());
+        expect.push(0);
+        expect.push(1);
+        expect.extend_from_slice(&21u64.to_le_bytes());
+        expect.extend_from_slice(&7i64.to_le_bytes());
+        expect.extend_from_slice(&107i64.to_le_bytes());
+        expect.push(0);
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let price_account = Pubkey::new_unique();
+        let check = EscrowInstruction::InitEscrow {
+            offered_amount: 10,
+            amount: 42,
+            expires_at: None,
+            allowed_taker: None,
+            auction: None,
+            oracle: Some(OraclePricing {
+                kind: OracleKind::Mock,
+                price_account,
+                feed_id: [9; 32],
+                offset_basis_points: -100,
+                max_age: 60,
+                max_confidence_basis_points: 50,
+            }),
+        };
+        let packed = check.pack();
+        let mut expect = vec![0u8];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(&[0, 0, 0, 1, 0]);
+        expect.extend_from_slice(price_account.as_ref());
+        expect.extend_from_slice(&[9; 32]);
+        expect.extend_from_slice(&(-100i16).to_le_bytes());
+        expect.extend_from_slice(&60u64.to_le_bytes());
+        expect.extend_from_slice(&50u16.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitBundle {
+            offered_amounts: vec![10, 20],
+            requested_amounts: vec![42],
+            expires_at: None,
+            allowed_taker: None,
+        };
+        let packed = check.pack();
+        let mut expect = vec![4u8, 2];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&20u64.to_le_bytes());
+        expect.push(1);
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(&[0, 0]);
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::ExchangeBundle;
+        let packed = check.pack();
+        let expect = vec![5u8];
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::CancelBundle;
+        let packed = check.pack();
+        let expect = vec![6u8];
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::Amend {
+            new_expected_amount: 50,
+            top_up_amount: 0,
+            withdraw_amount: 3,
+        };
+        let packed = check.pack();
+        let mut expect = vec![7u8];
+        expect.extend_from_slice(&50u64.to_le_bytes());
+        expect.extend_from_slice(&0u64.to_le_bytes());
+        expect.extend_from_slice(&3u64.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let recipient = Pubkey::new_unique();
+        let arbiter = Pubkey::new_unique();
+        let check = EscrowInstruction::InitArbiterEscrow {
+            amount: 42,
+            recipient,
+            arbiter,
+        };
+        let packed = check.pack();
+        let mut expect = vec![8u8];
+        expect.extend_from_slice(&42u64.to_le_bytes());
+        expect.extend_from_slice(recipient.as_ref());
+        expect.extend_from_slice(arbiter.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitMilestoneEscrow {
+            amounts: vec![10, 20],
+            deadline: 1_000,
+            recipient,
+        };
+        let packed = check.pack();
+        let mut expect = vec![12u8, 2];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&20u64.to_le_bytes());
+        expect.extend_from_slice(&1_000i64.to_le_bytes());
+        expect.extend_from_slice(recipient.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitStream {
+            amount: 1_000,
+            start_time: 100,
+            end_time: 200,
+            cliff_time: Some(150),
+            beneficiary: recipient,
+        };
+        let packed = check.pack();
+        let mut expect = vec![15u8];
+        expect.extend_from_slice(&1_000u64.to_le_bytes());
+        expect.extend_from_slice(&100i64.to_le_bytes());
+        expect.extend_from_slice(&200i64.to_le_bytes());
+        expect.push(1);
+        expect.extend_from_slice(&150i64.to_le_bytes());
+        expect.extend_from_slice(recipient.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::InitializeConfig {
+            fee_basis_points: 30,
+            treasury: recipient,
+        };
+        let packed = check.pack();
+        let mut expect = vec![18u8];
+        expect.extend_from_slice(&30u16.to_le_bytes());
+        expect.extend_from_slice(recipient.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let admin = Pubkey::new_unique();
+        let check = EscrowInstruction::UpdateConfig {
+            admin,
+            fee_basis_points: 30,
+            treasury: recipient,
+        };
+        let packed = check.pack();
+        let mut expect = vec![19u8];
+        expect.extend_from_slice(admin.as_ref());
+        expect.extend_from_slice(&30u16.to_le_bytes());
+        expect.extend_from_slice(recipient.as_ref());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::ExchangeMany {
+            count: 3,
+            max_total: 90,
+            max_fee_basis_points: 30,
+        };
+        let packed = check.pack();
+        let mut expect = vec![22u8, 3];
+        expect.extend_from_slice(&90u64.to_le_bytes());
+        expect.extend_from_slice(&30u16.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        let check = EscrowInstruction::ExchangeWithIntent {
+            amount: 10,
+            max_payment: 30,
+            max_fee_basis_points: 30,
+            nonce: 4,
+            expires_at: 1_000,
+        };
+        let packed = check.pack();
+        let mut expect = vec![23u8];
+        expect.extend_from_slice(&10u64.to_le_bytes());
+        expect.extend_from_slice(&30u64.to_le_bytes());
+        expect.extend_from_slice(&30u16.to_le_bytes());
+        expect.extend_from_slice(&4u64.to_le_bytes());
+        expect.extend_from_slice(&1_000i64.to_le_bytes());
+        assert_eq!(packed, expect);
+        let unpacked = EscrowInstruction::unpack(&expect).unwrap();
+        assert_eq!(unpacked, check);
+
+        for (check, tag) in [
+            (EscrowInstruction::Dispute, 9u8),
+            (EscrowInstruction::Release, 10),
+            (EscrowInstruction::Refund, 11),
+            (EscrowInstruction::ApproveMilestone, 13),
+            (EscrowInstruction::RefundMilestones, 14),
+            (EscrowInstruction::Claim, 16),
+            (EscrowInstruction::CancelStream, 17),
+            (EscrowInstruction::Pause, 20),
+            (EscrowInstruction::Unpause, 21),
+        ] {
+            assert_eq!(check.pack(), vec![tag]);
+            assert_eq!(EscrowInstruction::unpack(&[tag]).unwrap(), check);
+        }
+    }
+
+    #[test]
+    fn test_instruction_unpack_invalid() {
+        assert!(EscrowInstruction::unpack(&[]).is_err());
+        assert!(EscrowInstruction::unpack(&[0, 1, 2, 3]).is_err());
+        assert!(EscrowInstruction::unpack(&[1]).is_err());
+        // Exchanges without a maximum payment predate `Amend` and are refused
+        let mut no_max_payment = vec![1u8];
+        no_max_payment.extend_from_slice(&10u64.to_le_bytes());
+        assert!(EscrowInstruction::unpack(&no_max_payment).is_err());
+        let mut missing_expiry = vec![0u8];
+        missing_expiry.extend_from_slice(&10u64.to_le_bytes());
+        missing_expiry.extend_from_slice(&42u64.to_le_bytes());
+        assert!(EscrowInstruction::unpack(&missing_expiry).is_err());
+        missing_expiry.extend_from_slice(&[1, 0, 0]);
+        assert!(EscrowInstruction::unpack(&missing_expiry).is_err());
+        let mut short_taker = vec![0u8];
+        short_taker.extend_from_slice(&10u64.to_le_bytes());
+        short_taker.extend_from_slice(&42u64.to_le_bytes());
+        short_taker.extend_from_slice(&[0, 1]);
+        short_taker.extend_from_slice(&[7; 31]);
+        assert!(EscrowInstruction::unpack(&short_taker).is_err());
+        let mut missing_auction = vec![0u8];
+        missing_auction.extend_from_slice(&10u64.to_le_bytes());
+        missing_auction.extend_from_slice(&42u64.to_le_bytes());
+        missing_auction.extend_from_slice(&[0, 0]);
+        assert!(EscrowInstruction::unpack(&missing_auction).is_err());
+        missing_auction.push(1);
+        missing_auction.extend_from_slice(&[0; 23]);
+        assert!(EscrowInstruction::unpack(&missing_auction).is_err());
+        let mut bad_oracle = vec![0u8];
+        bad_oracle.extend_from_slice(&10u64.to_le_bytes());
+        bad_oracle.extend_from_slice(&42u64.to_le_bytes());
+        bad_oracle.extend_from_slice(&[0, 0, 0]);
+        assert!(EscrowInstruction::unpack(&bad_oracle).is_err());
+        bad_oracle.extend_from_slice(&[1, 2]);
+        bad_oracle.extend_from_slice(&[0; 76]);
+        assert!(EscrowInstruction::unpack(&bad_oracle).is_err());
+        let kind = bad_oracle.len() - 77;
+        bad_oracle[kind] = 0;
+        assert!(EscrowInstruction::unpack(&bad_oracle).is_ok());
+        bad_oracle.pop();
+        assert!(EscrowInstruction::unpack(&bad_oracle).is_err());
+        assert!(EscrowInstruction::unpack(&[3]).is_err());
+        let mut bad_flag = vec![3u8, 2];
+        bad_flag.extend_from_slice(&[0; 18]);
+        assert!(EscrowInstruction::unpack(&bad_flag).is_err());
+        assert!(EscrowInstruction::unpack(&[4]).is_err());
+        let mut short_amounts = vec![4u8, 2];
+        short_amounts.extend_from_slice(&10u64.to_le_bytes());
+        short_amounts.extend_from_slice(&[0, 0, 0]);
+        assert!(EscrowInstruction::unpack(&short_amounts).is_err());
+        assert!(EscrowInstruction::unpack(&[4, 0, 0]).is_err());
+        let mut short_amend = vec![7u8];
+        short_amend.extend_from_slice(&[0; 23]);
+        assert!(EscrowInstruction::unpack(&short_amend).is_err());
+        let mut short_arbiter = vec![8u8];
+        short_arbiter.extend_from_slice(&42u64.to_le_bytes());
+        short_arbiter.extend_from_slice(&[1; 63]);
+        assert!(EscrowInstruction::unpack(&short_arbiter).is_err());
+        let mut short_milestones = vec![12u8, 2];
+        short_milestones.extend_from_slice(&10u64.to_le_bytes());
+        short_milestones.extend_from_slice(&1_000i64.to_le_bytes());
+        short_milestones.extend_from_slice(&[1; 32]);
+        assert!(EscrowInstruction::unpack(&short_milestones).is_err());
+        let mut bad_cliff = vec![15u8];
+        bad_cliff.extend_from_slice(&[0; 24]);
+        bad_cliff.push(2);
+        bad_cliff.extend_from_slice(&[1; 32]);
+        assert!(EscrowInstruction::unpack(&bad_cliff).is_err());
+        assert!(EscrowInstruction::unpack(&[18, 30]).is_err());
+        assert!(EscrowInstruction::unpack(&[22, 2]).is_err());
+        let mut missing_expiry = vec![23u8];
+        missing_expiry.extend_from_slice(&[0; 26]);
+        assert!(EscrowInstruction::unpack(&missing_expiry).is_err());
+        assert!(EscrowInstruction::unpack(&[24]).is_err());
+    }
+
+    #[test]
+    fn test_exchange_account_order() {
+        let program_id = Pubkey::new_unique();
+        let initializer = Pubkey::new_unique();
+        let escrow_account = Pubkey::new_unique();
+        let offered_mint = Pubkey::new_unique();
+        let price_account = Pubkey::new_unique();
+        let build = |price_account| {
+            exchange(
+                &program_id,
+                &spl_token_2022::id(),
+                &system_program::id(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &initializer,
+                &Pubkey::new_unique(),
+                &escrow_account,
+                &offered_mint,
+                &NATIVE_MINT,
+                7,
+                21,
+                30,
+                None,
+                price_account,
+            )
+        };
+        let ix = build(None);
+        let (pda, _) = find_escrow_pda(&program_id, &escrow_account, &initializer);
+        assert_eq!(ix.accounts.len(), 1 + EXCHANGE_ESCROW_ACCOUNTS);
+        assert!(ix.accounts[0].is_signer);
+        assert_eq!(ix.accounts[4].pubkey, initializer);
+        assert_eq!(ix.accounts[6].pubkey, escrow_account);
+        assert_eq!(ix.accounts[7].pubkey, spl_token_2022::id());
+        assert_eq!(ix.accounts[8].pubkey, pda);
+        assert_eq!(ix.accounts[9].pubkey, system_program::id());
+        assert_eq!(ix.accounts[10].pubkey, offered_mint);
+        assert!(ix.accounts[10].is_writable);
+        assert_eq!(ix.accounts[11].pubkey, NATIVE_MINT);
+        assert!(!ix.accounts[11].is_writable);
+        assert_eq!(ix.accounts[12].pubkey, system_program::id());
+        assert_eq!(ix.accounts[13].pubkey, find_config_address(&program_id).0);
+        // Without a treasury the program id stands in, read only
+        assert_eq!(ix.accounts[14].pubkey, program_id);
+        assert!(!ix.accounts[15].is_writable);
+        // An oracle priced escrow's price account trails the fixed accounts
+        let with_price = build(Some(&price_account));
+        assert_eq!(
+            with_price.accounts[16..],
+            [AccountMeta::new_readonly(price_account, false)]
+        );
+        assert_eq!(
+            EscrowInstruction::unpack(&ix.data).unwrap(),
+            EscrowInstruction::Exchange {
+                amount: 7,
+                max_payment: 21,
+                max_fee_basis_points: 30
+            }
+        );
+    }
+
+    #[test]
+    fn test_exchange_many_account_order() {
+        let program_id = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+        let hook_account = Pubkey::new_unique();
+        let exchanges = [0, 1].map(|_| {
+            let escrow_account = Pubkey::new_unique();
+            let mut ix = exchange(
+                &program_id,
+                &spl_token_2022::id(),
+                &spl_token_2022::id(),
+                &taker,
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                &escrow_account,
+                &Pubkey::new_unique(),
+                &Pubkey::new_unique(),
+                1,
+                3,
+                30,
+                None,
+                None,
+            );
+            ix.accounts
+                .push(AccountMeta::new_readonly(hook_account, false));
+            ix
+        });
+        let ix = exchange_many(&program_id, &taker, &exchanges, 6, 20);
+        assert_eq!(ix.accounts.len(), 1 + 2 * EXCHANGE_ESCROW_ACCOUNTS + 2);
+        assert_eq!(ix.accounts[0], AccountMeta::new(taker, true));
+        for (group, exchange) in ix.accounts[1..]
+            .chunks(EXCHANGE_ESCROW_ACCOUNTS)
+            .zip(&exchanges)
+        {
+            assert_eq!(group, &exchange.accounts[1..=EXCHANGE_ESCROW_ACCOUNTS]);
+        }
+        assert_eq!(
+            ix.accounts[1 + 2 * EXCHANGE_ESCROW_ACCOUNTS].pubkey,
+            hook_account
+        );
+        assert_eq!(
+            EscrowInstruction::unpack(&ix.data).unwrap(),
+            EscrowInstruction::ExchangeMany {
+                count: 2,
+                max_total: 6,
+                max_fee_basis_points: 20
+            }
+        );
+    }
+
+    #[test]
+    fn test_exchange_with_intent_account_order() {
+        let program_id = Pubkey::new_unique();
+        let taker = Pubkey::new_unique();
+        let relayer = Pubkey::new_unique();
+        let hook_account = Pubkey::new_unique();
+        let mut exchange = exchange(
+            &program_id,
+            &spl_token::id(),
+            &spl_token::id(),
+            &taker,
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            &Pubkey::new_unique(),
+            1,
+            3,
+            30,
+            None,
+            None,
+        );
+        exchange
+            .accounts
+            .push(AccountMeta::new_readonly(hook_account, false));
+        let ix = exchange_with_intent(&program_id, &exchange, &relayer, 5, 1_000);
+        assert_eq!(ix.accounts.len(), EXCHANGE_ESCROW_ACCOUNTS + 5);
+        assert_eq!(ix.accounts[0], AccountMeta::new(taker, false));
+        assert_eq!(
+            ix.accounts[1..=EXCHANGE_ESCROW_ACCOUNTS],
+            exchange.accounts[1..=EXCHANGE_ESCROW_ACCOUNTS]
+        );
+        let rest = &ix.accounts[EXCHANGE_ESCROW_ACCOUNTS + 1..];
+        assert_eq!(rest[0], AccountMeta::new(relayer, true));
+        assert_eq!(
+            rest[1],
+            AccountMeta::new(find_intent_nonce_address(&program_id, &taker).0, false)
+        );
+        assert_eq!(rest[2].pubkey, sysvar::instructions::id());
+        assert_eq!(rest[3].pubkey, hook_account);
+        assert_eq!(
+            EscrowInstruction::unpack(&ix.data).unwrap(),
+            EscrowInstruction::ExchangeWithIntent {
+                amount: 1,
+                max_payment: 3,
+                max_fee_basis_points: 30,
+                nonce: 5,
+                expires_at: 1_000
+            }
+        );
     }
 }
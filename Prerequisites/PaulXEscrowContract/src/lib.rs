@@ -1,5 +1,7 @@
-pub mod entrypoint;
+pub mod error;
+pub mod event;
 pub mod instruction;
+pub mod oracle;
 pub mod processor;
 pub mod state;
 
@@ -0,0 +1,264 @@
+use num_derive::FromPrimitive;
+use solana_program::{
+    account_info::AccountInfo, clock::UnixTimestamp, program_error::ProgramError,
+};
+
+use arrayref::{array_ref, array_refs};
+
+use crate::error::EscrowError;
+
+/// The Pyth receiver program, which owns the price update accounts `PythPriceUpdate` reads
+pub mod pyth_receiver {
+    solana_program::declare_id!("rec5EKMGg6MxZYaMdyBfgwp4d5rB9T1VQH5pJv5LtFJ");
+}
+
+/// A price read from an oracle: one base unit of the offered token is worth
+/// `price * 10^exponent` base units of the requested token, give or take `confidence` in the
+/// same units
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OraclePrice {
+    pub price: i64,
+    pub confidence: u64,
+    pub exponent: i32,
+    pub publish_time: UnixTimestamp,
+}
+
+/// An oracle's price account format. Supporting another oracle means implementing this and
+/// adding it to `OracleKind`
+pub trait PriceOracle {
+    /// Reads the latest price of the feed `feed_id` out of the oracle's price account
+    fn read_price(account: &AccountInfo, feed_id: &[u8; 32]) -> Result<OraclePrice, ProgramError>;
+}
+
+/// The price account formats an oracle priced escrow can be read from, stored as a single byte
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, FromPrimitive)]
+pub enum OracleKind {
+    /// A `MockPriceAccount`, only built into tests and the `mock-oracle` feature
+    #[cfg(any(test, feature = "mock-oracle"))]
+    Mock = 0,
+    /// A `PythPriceUpdate`
+    Pyth = 1,
+}
+
+impl OracleKind {
+    /// Reads the latest price of the feed `feed_id` out of `account` in this format
+    pub fn read_price(
+        self,
+        account: &AccountInfo,
+        feed_id: &[u8; 32],
+    ) -> Result<OraclePrice, ProgramError> {
+        match self {
+            #[cfg(any(test, feature = "mock-oracle"))]
+            Self::Mock => MockPriceAccount::read_price(account, feed_id),
+            Self::Pyth => PythPriceUpdate::read_price(account, feed_id),
+        }
+    }
+}
+
+/// A Pyth `PriceUpdateV2` account, as posted by the Pyth receiver program. Only fully verified
+/// updates of the expected feed are accepted, as whoever writes the account can post any feed
+pub struct PythPriceUpdate;
+
+impl PythPriceUpdate {
+    pub const LEN: usize = 134;
+    /// The Anchor discriminator of `PriceUpdateV2`, the start of `sha256("account:PriceUpdateV2")`
+    pub const DISCRIMINATOR: [u8; 8] = [34, 241, 35, 99, 157, 126, 244, 205];
+    /// The Borsh tag of `VerificationLevel::Full`
+    pub const FULL_VERIFICATION: u8 = 1;
+}
+
+impl PriceOracle for PythPriceUpdate {
+    fn read_price(account: &AccountInfo, feed_id: &[u8; 32]) -> Result<OraclePrice, ProgramError> {
+        if *account.owner != pyth_receiver::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let data = account.try_borrow_data()?;
+        if data.len() != Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![data, 0, PythPriceUpdate::LEN];
+        #[rustfmt::skip]
+        let (
+            discriminator,
+            _write_authority,
+            verification_level,
+            price_feed_id,
+            price,
+            confidence,
+            exponent,
+            publish_time,
+            _rest,
+        ) = array_refs![src, 8, 32, 1, 32, 8, 8, 4, 8, 33];
+        if *discriminator != Self::DISCRIMINATOR || verification_level[0] != Self::FULL_VERIFICATION
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        if price_feed_id != feed_id {
+            return Err(EscrowError::WrongOracleFeed.into());
+        }
+        Ok(OraclePrice {
+            price: i64::from_le_bytes(*price),
+            confidence: u64::from_le_bytes(*confidence),
+            exponent: i32::from_le_bytes(*exponent),
+            publish_time: UnixTimestamp::from_le_bytes(*publish_time),
+        })
+    }
+}
+
+/// A price account holding a bare `OraclePrice` in whatever the account's owner last wrote, for
+/// tests and local clusters without a real oracle. The escrow trusts whoever can write to it,
+/// and it carries no feed id to check
+#[cfg(any(test, feature = "mock-oracle"))]
+pub struct MockPriceAccount;
+
+#[cfg(any(test, feature = "mock-oracle"))]
+impl MockPriceAccount {
+    pub const LEN: usize = 28;
+
+    /// Lays `price` out the way `read_price` expects it
+    pub fn pack(price: &OraclePrice) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::LEN);
+        data.extend_from_slice(&price.price.to_le_bytes());
+        data.extend_from_slice(&price.confidence.to_le_bytes());
+        data.extend_from_slice(&price.exponent.to_le_bytes());
+        data.extend_from_slice(&price.publish_time.to_le_bytes());
+        data
+    }
+}
+
+#[cfg(any(test, feature = "mock-oracle"))]
+impl PriceOracle for MockPriceAccount {
+    fn read_price(account: &AccountInfo, _feed_id: &[u8; 32]) -> Result<OraclePrice, ProgramError> {
+        let data = account.try_borrow_data()?;
+        if data.len() != Self::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![data, 0, MockPriceAccount::LEN];
+        let (price, confidence, exponent, publish_time) = array_refs![src, 8, 8, 4, 8];
+        Ok(OraclePrice {
+            price: i64::from_le_bytes(*price),
+            confidence: u64::from_le_bytes(*confidence),
+            exponent: i32::from_le_bytes(*exponent),
+            publish_time: UnixTimestamp::from_le_bytes(*publish_time),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::FromPrimitive;
+    use solana_program::pubkey::Pubkey;
+
+    #[test]
+    fn test_mock_price_account() {
+        let price = OraclePrice {
+            price: -3,
+            confidence: 7,
+            exponent: -9,
+            publish_time: 1_700_000_000,
+        };
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = MockPriceAccount::pack(&price);
+        assert_eq!(data.len(), MockPriceAccount::LEN);
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+        assert_eq!(OracleKind::Mock.read_price(&account, &[0; 32]), Ok(price));
+
+        let mut short = vec![0; MockPriceAccount::LEN - 1];
+        let mut lamports = 0;
+        let account = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut short,
+            &owner,
+            false,
+            0,
+        );
+        assert_eq!(
+            OracleKind::Mock.read_price(&account, &[0; 32]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    const FEED_ID: [u8; 32] = [9; 32];
+
+    #[test]
+    fn test_pyth_price_update() {
+        let price = OraclePrice {
+            price: 15_000_000_000,
+            confidence: 7_000_000,
+            exponent: -8,
+            publish_time: 1_700_000_000,
+        };
+        let mut data = PythPriceUpdate::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(Pubkey::new_unique().as_ref());
+        data.push(PythPriceUpdate::FULL_VERIFICATION);
+        data.extend_from_slice(&FEED_ID);
+        data.extend_from_slice(&price.price.to_le_bytes());
+        data.extend_from_slice(&price.confidence.to_le_bytes());
+        data.extend_from_slice(&price.exponent.to_le_bytes());
+        data.extend_from_slice(&price.publish_time.to_le_bytes());
+        data.resize(PythPriceUpdate::LEN, 0);
+        let key = Pubkey::new_unique();
+        let read = |data: &[u8], owner: &Pubkey| {
+            let mut lamports = 0;
+            let mut data = data.to_vec();
+            let account = AccountInfo::new(
+                &key,
+                false,
+                false,
+                &mut lamports,
+                &mut data,
+                owner,
+                false,
+                0,
+            );
+            OracleKind::Pyth.read_price(&account, &FEED_ID)
+        };
+        assert_eq!(read(&data, &pyth_receiver::id()), Ok(price));
+        assert_eq!(
+            read(&data, &Pubkey::new_unique()),
+            Err(ProgramError::IncorrectProgramId)
+        );
+
+        // A partially verified update holds its number of signatures after the level
+        let mut partial = data.clone();
+        partial[40] = 0;
+        assert_eq!(
+            read(&partial, &pyth_receiver::id()),
+            Err(ProgramError::InvalidAccountData)
+        );
+        let mut other_account = data.clone();
+        other_account[0] ^= 1;
+        assert_eq!(
+            read(&other_account, &pyth_receiver::id()),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            read(&data[1..], &pyth_receiver::id()),
+            Err(ProgramError::InvalidAccountData)
+        );
+        // A verified update of another feed, posted by whoever writes the account
+        let mut other_feed = data.clone();
+        other_feed[41] ^= 1;
+        assert_eq!(
+            read(&other_feed, &pyth_receiver::id()),
+            Err(EscrowError::WrongOracleFeed.into())
+        );
+        assert_eq!(OracleKind::from_u8(1), Some(OracleKind::Pyth));
+    }
+}
@@ -1,17 +1,63 @@
 use solana_program::{
-    account_info::{next_account_info, AccountInfo},
+    account_info::{next_account_info, next_account_infos, AccountInfo},
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    clock::UnixTimestamp,
+    ed25519_program,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack},
     pubkey::Pubkey,
-    sysvar::{rent::Rent, Sysvar},
+    system_instruction,
+    sysvar::{
+        clock::Clock,
+        instructions::{load_current_index_checked, load_instruction_at_checked},
+        rent::Rent,
+        Sysvar,
+    },
 };
 
-use spl_token::state::Account as TokenAccount;
+use spl_token_2022::{
+    check_spl_token_program_account,
+    extension::{
+        transfer_fee::{TransferFeeAmount, TransferFeeConfig},
+        BaseStateWithExtensions, ExtensionType, StateWithExtensions,
+    },
+    instruction::AuthorityType,
+    onchain::invoke_transfer_checked,
+    state::{Account as TokenAccount, Mint},
+};
+
+use arrayref::{array_ref, array_refs};
+
+use crate::{
+    error::EscrowError,
+    event::{
+        ArbiterEscrowCreated, ArbiterEscrowDisputed, ArbiterEscrowSettled, BundleAmount,
+        BundleClosed, BundleCreated, BundleExchanged, CloseReason, ConfigUpdated, EscrowAmended,
+        EscrowClosed, EscrowCreated, EscrowEvent, EscrowExchanged, FeesCollected,
+        MilestoneEscrowCreated, MilestoneEscrowRefunded, MilestoneReleased, PauseToggled,
+        Settlement, StreamCancelled, StreamClaimed, StreamCreated, StreamCutOff,
+    },
+    instruction::{
+        taker_intent_message, EscrowInstruction, ED25519_DATA_START, ED25519_OFFSETS_START,
+        EXCHANGE_ESCROW_ACCOUNTS,
+    },
+    oracle::OraclePrice,
+    state::{
+        find_bundle_vault_address, find_config_address, find_escrow_pda, find_intent_nonce_address,
+        find_program_data_address, find_vault_address, ArbiterEscrow, Auction, Bundle, BundleOffer,
+        BundleRequest, Config, Escrow, IntentNonce, MilestoneEscrow, OraclePricing, Stream,
+        CONFIG_PDA_SEED, ESCROW_PDA_SEED, INTENT_NONCE_PDA_SEED, MAX_BUNDLE_OFFERED_LEGS,
+        MAX_BUNDLE_REQUESTED_LEGS, MAX_FEE_BASIS_POINTS, MAX_MILESTONES,
+        MAX_PROTOCOL_FEE_BASIS_POINTS, NATIVE_MINT, VAULT_PDA_SEED,
+    },
+};
 
-use crate::{error::EscrowError, instruction::EscrowInstruction, state::Escrow};
+/// Variant index of `UpgradeableLoaderState::ProgramData`, the first four bytes of a
+/// ProgramData account
+const PROGRAM_DATA_STATE: u32 = 3;
 
 pub struct Processor;
 impl Processor {
@@ -21,24 +67,260 @@ impl Processor {
         instruction_data: &[u8],
     ) -> ProgramResult {
         let instruction = EscrowInstruction::unpack(instruction_data)?;
+        if !instruction.is_allowed_while_paused() {
+            Self::check_not_paused(accounts, program_id)?;
+        }
 
         match instruction {
-            EscrowInstruction::InitEscrow { amount } => {
+            EscrowInstruction::InitEscrow {
+                offered_amount,
+                amount,
+                expires_at,
+                allowed_taker,
+                auction,
+                oracle,
+            } => {
                 msg!("Instruction: InitEscrow");
-                Self::process_init_escrow(accounts, amount, program_id)
+                Self::process_init_escrow(
+                    accounts,
+                    offered_amount,
+                    amount,
+                    expires_at,
+                    allowed_taker,
+                    auction,
+                    oracle,
+                    false,
+                    program_id,
+                )
             }
-            EscrowInstruction::Exchange { amount } => {
+            EscrowInstruction::Exchange {
+                amount,
+                max_payment,
+                max_fee_basis_points,
+            } => {
                 msg!("Instruction: Exchange");
-                Self::process_exchange(accounts, amount, program_id)
+                Self::process_exchange(
+                    accounts,
+                    amount,
+                    max_payment,
+                    max_fee_basis_points,
+                    program_id,
+                )
+            }
+            EscrowInstruction::Cancel => {
+                msg!("Instruction: Cancel");
+                Self::process_cancel(accounts, program_id)
+            }
+            EscrowInstruction::InitNativeEscrow {
+                offers_sol,
+                offered_amount,
+                amount,
+                expires_at,
+                allowed_taker,
+                auction,
+                oracle,
+            } => {
+                msg!("Instruction: InitNativeEscrow");
+                if offers_sol {
+                    Self::process_init_sol_offer(
+                        accounts,
+                        offered_amount,
+                        amount,
+                        expires_at,
+                        allowed_taker,
+                        auction,
+                        oracle,
+                        program_id,
+                    )
+                } else {
+                    Self::process_init_escrow(
+                        accounts,
+                        offered_amount,
+                        amount,
+                        expires_at,
+                        allowed_taker,
+                        auction,
+                        oracle,
+                        true,
+                        program_id,
+                    )
+                }
+            }
+            EscrowInstruction::InitBundle {
+                offered_amounts,
+                requested_amounts,
+                expires_at,
+                allowed_taker,
+            } => {
+                msg!("Instruction: InitBundle");
+                Self::process_init_bundle(
+                    accounts,
+                    &offered_amounts,
+                    &requested_amounts,
+                    expires_at,
+                    allowed_taker,
+                    program_id,
+                )
+            }
+            EscrowInstruction::ExchangeBundle => {
+                msg!("Instruction: ExchangeBundle");
+                Self::process_exchange_bundle(accounts, program_id)
+            }
+            EscrowInstruction::CancelBundle => {
+                msg!("Instruction: CancelBundle");
+                Self::process_cancel_bundle(accounts, program_id)
+            }
+            EscrowInstruction::Amend {
+                new_expected_amount,
+                top_up_amount,
+                withdraw_amount,
+            } => {
+                msg!("Instruction: Amend");
+                Self::process_amend(
+                    accounts,
+                    new_expected_amount,
+                    top_up_amount,
+                    withdraw_amount,
+                    program_id,
+                )
+            }
+            EscrowInstruction::InitArbiterEscrow {
+                amount,
+                recipient,
+                arbiter,
+            } => {
+                msg!("Instruction: InitArbiterEscrow");
+                Self::process_init_arbiter_escrow(accounts, amount, recipient, arbiter, program_id)
+            }
+            EscrowInstruction::Dispute => {
+                msg!("Instruction: Dispute");
+                Self::process_dispute(accounts, program_id)
+            }
+            EscrowInstruction::Release => {
+                msg!("Instruction: Release");
+                Self::process_settle(accounts, Settlement::Released, program_id)
+            }
+            EscrowInstruction::Refund => {
+                msg!("Instruction: Refund");
+                Self::process_settle(accounts, Settlement::Refunded, program_id)
+            }
+            EscrowInstruction::InitMilestoneEscrow {
+                amounts,
+                deadline,
+                recipient,
+            } => {
+                msg!("Instruction: InitMilestoneEscrow");
+                Self::process_init_milestone_escrow(
+                    accounts, &amounts, deadline, recipient, program_id,
+                )
+            }
+            EscrowInstruction::ApproveMilestone => {
+                msg!("Instruction: ApproveMilestone");
+                Self::process_approve_milestone(accounts, program_id)
+            }
+            EscrowInstruction::RefundMilestones => {
+                msg!("Instruction: RefundMilestones");
+                Self::process_refund_milestones(accounts, program_id)
+            }
+            EscrowInstruction::InitStream {
+                amount,
+                start_time,
+                end_time,
+                cliff_time,
+                beneficiary,
+            } => {
+                msg!("Instruction: InitStream");
+                Self::process_init_stream(
+                    accounts,
+                    amount,
+                    start_time,
+                    end_time,
+                    cliff_time,
+                    beneficiary,
+                    program_id,
+                )
+            }
+            EscrowInstruction::Claim => {
+                msg!("Instruction: Claim");
+                Self::process_claim(accounts, program_id)
+            }
+            EscrowInstruction::CancelStream => {
+                msg!("Instruction: CancelStream");
+                Self::process_cancel_stream(accounts, program_id)
+            }
+            EscrowInstruction::InitializeConfig {
+                fee_basis_points,
+                treasury,
+            } => {
+                msg!("Instruction: InitializeConfig");
+                Self::process_initialize_config(accounts, fee_basis_points, treasury, program_id)
+            }
+            EscrowInstruction::UpdateConfig {
+                admin,
+                fee_basis_points,
+                treasury,
+            } => {
+                msg!("Instruction: UpdateConfig");
+                Self::process_update_config(accounts, admin, fee_basis_points, treasury, program_id)
+            }
+            EscrowInstruction::Pause => {
+                msg!("Instruction: Pause");
+                Self::process_set_paused(accounts, true, program_id)
+            }
+            EscrowInstruction::Unpause => {
+                msg!("Instruction: Unpause");
+                Self::process_set_paused(accounts, false, program_id)
+            }
+            EscrowInstruction::ExchangeMany {
+                count,
+                max_total,
+                max_fee_basis_points,
+            } => {
+                msg!("Instruction: ExchangeMany");
+                Self::process_exchange_many(
+                    accounts,
+                    count,
+                    max_total,
+                    max_fee_basis_points,
+                    program_id,
+                )
+            }
+            EscrowInstruction::ExchangeWithIntent {
+                amount,
+                max_payment,
+                max_fee_basis_points,
+                nonce,
+                expires_at,
+            } => {
+                msg!("Instruction: ExchangeWithIntent");
+                Self::process_exchange_with_intent(
+                    accounts,
+                    amount,
+                    max_payment,
+                    max_fee_basis_points,
+                    nonce,
+                    expires_at,
+                    program_id,
+                )
             }
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn process_init_escrow(
         accounts: &[AccountInfo],
+        offered_amount: u64,
         amount: u64,
+        expires_at: Option<UnixTimestamp>,
+        allowed_taker: Option<Pubkey>,
+        auction: Option<Auction>,
+        oracle: Option<OraclePricing>,
+        requests_sol: bool,
         program_id: &Pubkey,
     ) -> ProgramResult {
+        Self::check_auction(auction)?;
+        Self::check_oracle(oracle, auction)?;
+
         let account_info_iter = &mut accounts.iter();
         let initializer = next_account_info(account_info_iter)?;
 
@@ -46,13 +328,128 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
-        let temp_token_account = next_account_info(account_info_iter)?;
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let initializers_token_account_info =
+            Self::unpack_token_account(initializers_token_account)?;
 
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+
+        // When SOL is requested this is the wallet the taker's lamports are sent to
         let token_to_receive_account = next_account_info(account_info_iter)?;
-        if *token_to_receive_account.owner != spl_token::id() {
-            return Err(ProgramError::IncorrectProgramId);
+        let requested_mint = if requests_sol {
+            NATIVE_MINT
+        } else {
+            Self::unpack_token_account(token_to_receive_account)?.mint
+        };
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
+
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
+        if escrow_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let offered_mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let decimals = Self::check_mint(
+            offered_mint,
+            &initializers_token_account_info.mint,
+            token_program,
+            initializers_token_account,
+        )?;
+
+        let (vault, vault_bump_seed) = find_vault_address(program_id, escrow_account.key);
+        if vault != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let (pda, bump_seed) = find_escrow_pda(program_id, escrow_account.key, initializer.key);
+
+        Self::create_vault(
+            initializer,
+            pdas_vault_account,
+            offered_mint,
+            token_program,
+            system_program,
+            rent,
+            &pda,
+            &[
+                VAULT_PDA_SEED,
+                escrow_account.key.as_ref(),
+                &[vault_bump_seed],
+            ],
+        )?;
+
+        msg!("Calling the token program to transfer tokens to the pda's vault...");
+        invoke_transfer_checked(
+            token_program.key,
+            initializers_token_account.clone(),
+            offered_mint.clone(),
+            pdas_vault_account.clone(),
+            initializer.clone(),
+            account_info_iter.as_slice(),
+            offered_amount,
+            decimals,
+            &[],
+        )?;
+
+        escrow_info.is_initialized = true;
+        escrow_info.initializer_pubkey = *initializer.key;
+        escrow_info.temp_token_account_pubkey = vault;
+        escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
+        escrow_info.offered_mint_pubkey = *offered_mint.key;
+        escrow_info.requested_mint_pubkey = requested_mint;
+        escrow_info.expected_amount = amount;
+        // What actually arrived, net of any transfer fee
+        escrow_info.remaining_amount = Self::unpack_token_account(pdas_vault_account)?.amount;
+        escrow_info.expires_at = expires_at;
+        escrow_info.allowed_taker = allowed_taker;
+        escrow_info.auction = auction;
+        escrow_info.oracle = oracle;
+        escrow_info.bump_seed = bump_seed;
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::Expired.into());
+        }
+
+        Self::emit_created(escrow_account.key, &escrow_info);
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_init_sol_offer(
+        accounts: &[AccountInfo],
+        lamports: u64,
+        amount: u64,
+        expires_at: Option<UnixTimestamp>,
+        allowed_taker: Option<Pubkey>,
+        auction: Option<Auction>,
+        oracle: Option<OraclePricing>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        Self::check_auction(auction)?;
+        Self::check_oracle(oracle, auction)?;
+
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
         }
 
+        let pdas_sol_vault = next_account_info(account_info_iter)?;
+
+        let token_to_receive_account = next_account_info(account_info_iter)?;
+        let token_to_receive_account_info = Self::unpack_token_account(token_to_receive_account)?;
+
         let escrow_account = next_account_info(account_info_iter)?;
         let rent = &Rent::from_account_info(next_account_info(account_info_iter)?)?;
 
@@ -60,38 +457,68 @@ impl Processor {
             return Err(EscrowError::NotRentExempt.into());
         }
 
+        Self::check_program_account(escrow_account, program_id)?;
         let mut escrow_info = Escrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
         if escrow_info.is_initialized() {
             return Err(ProgramError::AccountAlreadyInitialized);
         }
 
+        let (pda, bump_seed) = find_escrow_pda(program_id, escrow_account.key, initializer.key);
+        if pda != *pdas_sol_vault.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
         escrow_info.is_initialized = true;
         escrow_info.initializer_pubkey = *initializer.key;
-        escrow_info.temp_token_account_pubkey = *temp_token_account.key;
+        escrow_info.temp_token_account_pubkey = pda;
         escrow_info.initializer_token_to_receive_account_pubkey = *token_to_receive_account.key;
+        escrow_info.offered_mint_pubkey = NATIVE_MINT;
+        escrow_info.requested_mint_pubkey = token_to_receive_account_info.mint;
         escrow_info.expected_amount = amount;
+        escrow_info.remaining_amount = lamports;
+        escrow_info.expires_at = expires_at;
+        escrow_info.allowed_taker = allowed_taker;
+        escrow_info.auction = auction;
+        escrow_info.oracle = oracle;
+        escrow_info.bump_seed = bump_seed;
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::Expired.into());
+        }
 
+        Self::emit_created(escrow_account.key, &escrow_info);
         Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
-        let (pda, _nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
 
-        let token_program = next_account_info(account_info_iter)?;
-        let owner_change_ix = spl_token::instruction::set_authority(
-            token_program.key,
-            temp_token_account.key,
-            Some(&pda),
-            spl_token::instruction::AuthorityType::AccountOwner,
-            initializer.key,
-            &[&initializer.key],
-        )?;
+        let system_program = next_account_info(account_info_iter)?;
 
-        msg!("Calling the token program to transfer token account ownership...");
-        invoke(
-            &owner_change_ix,
-            &[
-                temp_token_account.clone(),
-                initializer.clone(),
-                token_program.clone(),
-            ],
+        // Anyone can send lamports to the vault address beforehand, so top it up rather
+        // than creating it from scratch
+        let vault_lamports = rent
+            .minimum_balance(0)
+            .checked_add(lamports)
+            .ok_or(EscrowError::AmountOverflow)?;
+        let top_up = vault_lamports.saturating_sub(pdas_sol_vault.lamports());
+        if top_up > 0 {
+            msg!("Calling the system program to fund the pda's vault...");
+            invoke(
+                &system_instruction::transfer(initializer.key, pdas_sol_vault.key, top_up),
+                &[
+                    initializer.clone(),
+                    pdas_sol_vault.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+        }
+
+        msg!("Calling the system program to hand the vault over to the escrow program...");
+        invoke_signed(
+            &system_instruction::assign(pdas_sol_vault.key, program_id),
+            &[pdas_sol_vault.clone(), system_program.clone()],
+            &[&[
+                ESCROW_PDA_SEED,
+                escrow_account.key.as_ref(),
+                initializer.key.as_ref(),
+                &[bump_seed],
+            ]],
         )?;
 
         Ok(())
@@ -100,6 +527,8 @@ impl Processor {
     fn process_exchange(
         accounts: &[AccountInfo],
         amount_expected_by_taker: u64,
+        max_payment: u64,
+        max_fee_basis_points: u16,
         program_id: &Pubkey,
     ) -> ProgramResult {
         let account_info_iter = &mut accounts.iter();
@@ -109,24 +538,304 @@ impl Processor {
             return Err(ProgramError::MissingRequiredSignature);
         }
 
+        let escrow_accounts = next_account_infos(account_info_iter, EXCHANGE_ESCROW_ACCOUNTS)?;
+        // Whatever is left is handed to the token program for transfer hooks
+        let additional_accounts = account_info_iter.as_slice();
+
+        let (_payment, is_fully_filled) = Self::fill_escrow(
+            taker,
+            taker,
+            &[],
+            escrow_accounts,
+            additional_accounts,
+            amount_expected_by_taker,
+            max_payment,
+            max_fee_basis_points,
+            program_id,
+        )?;
+        if is_fully_filled {
+            Self::close_filled_escrow(escrow_accounts)?;
+        }
+        Ok(())
+    }
+
+    fn process_exchange_many(
+        accounts: &[AccountInfo],
+        count: u8,
+        max_total: u64,
+        max_fee_basis_points: u16,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if count == 0 {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let groups = next_account_infos(
+            account_info_iter,
+            EXCHANGE_ESCROW_ACCOUNTS * usize::from(count),
+        )?;
+        // Whatever is left is handed to the token program for transfer hooks
+        let additional_accounts = account_info_iter.as_slice();
+
+        // Any escrow failing to fill fails the transaction, so the taker never ends up with only
+        // part of the sweep. Each one is taken in full and may cost at most what is left of the
+        // cap, which only adds up because every escrow has to ask for the same token
+        let mut requested_mint = None;
+        let mut total: u64 = 0;
+        for (index, escrow_accounts) in groups.chunks_exact(EXCHANGE_ESCROW_ACCOUNTS).enumerate() {
+            let escrow_account = &escrow_accounts[5];
+            // A filled escrow is only closed at the end, so it could be filled again until then
+            if groups[..index * EXCHANGE_ESCROW_ACCOUNTS]
+                .chunks_exact(EXCHANGE_ESCROW_ACCOUNTS)
+                .any(|earlier| earlier[5].key == escrow_account.key)
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            Self::check_program_account(escrow_account, program_id)?;
+            let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+            if *requested_mint.get_or_insert(escrow_info.requested_mint_pubkey)
+                != escrow_info.requested_mint_pubkey
+            {
+                return Err(EscrowError::MintMismatch.into());
+            }
+            // SOL is paid out of the PDA without a CPI, see `close_filled_escrow`
+            if escrow_info.offers_sol() {
+                return Err(EscrowError::InvalidInstruction.into());
+            }
+
+            let (payment, _is_fully_filled) = Self::fill_escrow(
+                taker,
+                taker,
+                &[],
+                escrow_accounts,
+                additional_accounts,
+                escrow_info.remaining_amount,
+                max_total - total,
+                max_fee_basis_points,
+                program_id,
+            )?;
+            total = total
+                .checked_add(payment)
+                .ok_or(EscrowError::AmountOverflow)?;
+        }
+
+        for escrow_accounts in groups.chunks_exact(EXCHANGE_ESCROW_ACCOUNTS) {
+            Self::close_filled_escrow(escrow_accounts)?;
+        }
+
+        msg!("Filled {} escrows for {} in total", count, total);
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_exchange_with_intent(
+        accounts: &[AccountInfo],
+        amount_expected_by_taker: u64,
+        max_payment: u64,
+        max_fee_basis_points: u16,
+        nonce: u64,
+        expires_at: UnixTimestamp,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+        let escrow_accounts = next_account_infos(account_info_iter, EXCHANGE_ESCROW_ACCOUNTS)?;
+        let relayer = next_account_info(account_info_iter)?;
+
+        if !relayer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let nonce_account = next_account_info(account_info_iter)?;
+        let instructions_sysvar = next_account_info(account_info_iter)?;
+        // Whatever is left is handed to the token program for transfer hooks
+        let additional_accounts = account_info_iter.as_slice();
+
+        if Clock::get()?.unix_timestamp >= expires_at {
+            return Err(EscrowError::Expired.into());
+        }
+
+        let escrow_account = &escrow_accounts[5];
+        let message = taker_intent_message(
+            escrow_account.key,
+            amount_expected_by_taker,
+            max_payment,
+            max_fee_basis_points,
+            nonce,
+            expires_at,
+        );
+        Self::check_ed25519_signature(instructions_sysvar, taker.key, &message)?;
+
+        Self::check_program_account(escrow_account, program_id)?;
+        // Lamports can only leave the taker's wallet with their signature on the transaction
+        if Escrow::unpack(&escrow_account.try_borrow_data()?)?.requests_sol() {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let (nonce_address, bump_seed) = find_intent_nonce_address(program_id, taker.key);
+        if nonce_address != *nonce_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let nonce_seeds: &[&[u8]] = &[INTENT_NONCE_PDA_SEED, taker.key.as_ref(), &[bump_seed]];
+        let mut nonce_info = if nonce_account.owner == program_id {
+            IntentNonce::unpack(&nonce_account.try_borrow_data()?)?
+        } else {
+            let system_program = &escrow_accounts[8];
+            msg!("Calling the system program to create the taker's nonce account...");
+            Self::create_pda_account(
+                relayer,
+                nonce_account,
+                system_program,
+                &Rent::get()?,
+                IntentNonce::LEN,
+                program_id,
+                nonce_seeds,
+            )?;
+            IntentNonce {
+                is_initialized: true,
+                taker_pubkey: *taker.key,
+                next_nonce: 0,
+                bump_seed,
+            }
+        };
+
+        if nonce < nonce_info.next_nonce {
+            return Err(EscrowError::NonceAlreadyUsed.into());
+        }
+        nonce_info.next_nonce = nonce.checked_add(1).ok_or(EscrowError::AmountOverflow)?;
+        IntentNonce::pack(nonce_info, &mut nonce_account.try_borrow_mut_data()?)?;
+
+        let (_payment, is_fully_filled) = Self::fill_escrow(
+            taker,
+            nonce_account,
+            &[nonce_seeds],
+            escrow_accounts,
+            additional_accounts,
+            amount_expected_by_taker,
+            max_payment,
+            max_fee_basis_points,
+            program_id,
+        )?;
+        if is_fully_filled {
+            Self::close_filled_escrow(escrow_accounts)?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the instruction right before this one has the ed25519 program verify
+    /// `signer`'s signature over `message`. Everything it verifies has to be in its own data,
+    /// as `verify_taker_intent` lays it out, or it could point at bytes nobody signed for this
+    fn check_ed25519_signature(
+        instructions_sysvar: &AccountInfo,
+        signer: &Pubkey,
+        message: &[u8],
+    ) -> ProgramResult {
+        let current_index = load_current_index_checked(instructions_sysvar)?;
+        let index = current_index
+            .checked_sub(1)
+            .ok_or(ProgramError::MissingRequiredSignature)?;
+        let instruction = load_instruction_at_checked(index.into(), instructions_sysvar)?;
+        if instruction.program_id != ed25519_program::id() {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let data = &instruction.data;
+        let offsets = data
+            .get(ED25519_OFFSETS_START..ED25519_DATA_START)
+            .ok_or(ProgramError::MissingRequiredSignature)?;
+        // Offset and instruction index of the signature, then of the public key, then offset,
+        // size and instruction index of the message
+        let field = |index: usize| {
+            usize::from(u16::from_le_bytes([
+                offsets[2 * index],
+                offsets[2 * index + 1],
+            ]))
+        };
+        if data[0] != 1 || [1, 3, 6].map(field) != [usize::from(u16::MAX); 3] {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let (public_key_offset, message_offset, message_size) = (field(2), field(4), field(5));
+        let public_key = data.get(public_key_offset..public_key_offset + 32);
+        let signed_message = data.get(message_offset..message_offset + message_size);
+        if public_key != Some(signer.as_ref()) || signed_message != Some(message) {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+        Ok(())
+    }
+
+    /// Closes the escrow account of a fully filled escrow in `accounts`, laid out as accounts 1
+    /// to 15 of `Exchange`. Lamports moved outside of a CPI only add up for the runtime once
+    /// every account they moved between is handed to the same CPI, so this has to come after
+    /// the instruction's last CPI
+    fn close_filled_escrow(accounts: &[AccountInfo]) -> ProgramResult {
+        let (initializers_main_account, escrow_account) = (&accounts[3], &accounts[5]);
+        msg!("Closing the escrow account...");
+        Self::close_program_account(escrow_account, initializers_main_account)
+    }
+
+    /// Fills `amount_expected_by_taker` of the escrow in `accounts`, laid out as accounts 1 to
+    /// 15 of `Exchange`, and returns what the taker paid for it before transfer fees and whether
+    /// that filled it. The escrow account of a filled escrow is left for `close_filled_escrow`.
+    /// The taker pays with `authority` as in `pay_initializer`, and no more than
+    /// `max_fee_basis_points` in protocol fees
+    #[allow(clippy::too_many_arguments)]
+    fn fill_escrow<'a>(
+        taker: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        authority_seeds: &[&[&[u8]]],
+        accounts: &[AccountInfo<'a>],
+        additional_accounts: &[AccountInfo<'a>],
+        amount_expected_by_taker: u64,
+        max_payment: u64,
+        max_fee_basis_points: u16,
+        program_id: &Pubkey,
+    ) -> Result<(u64, bool), ProgramError> {
+        let account_info_iter = &mut accounts.iter();
         let takers_sending_token_account = next_account_info(account_info_iter)?;
 
         let takers_token_to_receive_account = next_account_info(account_info_iter)?;
 
         let pdas_temp_token_account = next_account_info(account_info_iter)?;
-        let pdas_temp_token_account_info =
-            TokenAccount::unpack(&pdas_temp_token_account.try_borrow_data()?)?;
-        let (pda, nonce) = Pubkey::find_program_address(&[b"escrow"], program_id);
-
-        if amount_expected_by_taker != pdas_temp_token_account_info.amount {
-            return Err(EscrowError::ExpectedAmountMismatch.into());
-        }
 
         let initializers_main_account = next_account_info(account_info_iter)?;
         let initializers_token_to_receive_account = next_account_info(account_info_iter)?;
         let escrow_account = next_account_info(account_info_iter)?;
 
-        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        let now = Clock::get()?.unix_timestamp;
+        if escrow_info.is_expired(now) {
+            return Err(EscrowError::Expired.into());
+        }
+
+        let mut payment = escrow_info
+            .payment_for(amount_expected_by_taker, now)
+            .ok_or(EscrowError::ExpectedAmountMismatch)?;
+        if let Some(oracle) = escrow_info.oracle {
+            let price = Self::read_oracle_price(&oracle, additional_accounts, now)?;
+            let oracle_payment = oracle
+                .payment_for(amount_expected_by_taker, &price)
+                .ok_or(EscrowError::AmountOverflow)?;
+            payment = payment.max(oracle_payment);
+        }
+        // The escrow was amended to worse terms than the taker agreed to, an auction's price has
+        // not come down to what the taker is willing to pay yet or the oracle's price moved
+        if payment > max_payment {
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+
+        if !escrow_info.is_taker_allowed(taker.key) {
+            return Err(EscrowError::TakerNotAllowed.into());
+        }
 
         if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
             return Err(ProgramError::InvalidAccountData);
@@ -142,76 +851,2445 @@ impl Processor {
             return Err(ProgramError::InvalidAccountData);
         }
 
-        let token_program = next_account_info(account_info_iter)?;
-
-        let transfer_to_initializer_ix = spl_token::instruction::transfer(
-            token_program.key,
-            takers_sending_token_account.key,
-            initializers_token_to_receive_account.key,
+        Self::check_taker_account(
+            takers_sending_token_account,
+            &escrow_info.requested_mint_pubkey,
             taker.key,
-            &[&taker.key],
-            escrow_info.expected_amount,
         )?;
-        msg!("Calling the token program to transfer tokens to the escrow's initializer...");
-        invoke(
-            &transfer_to_initializer_ix,
-            &[
-                takers_sending_token_account.clone(),
-                initializers_token_to_receive_account.clone(),
-                taker.clone(),
-                token_program.clone(),
-            ],
+        Self::check_taker_account(
+            takers_token_to_receive_account,
+            &escrow_info.offered_mint_pubkey,
+            taker.key,
         )?;
 
+        let token_program = next_account_info(account_info_iter)?;
         let pda_account = next_account_info(account_info_iter)?;
 
-        let transfer_to_taker_ix = spl_token::instruction::transfer(
-            token_program.key,
-            pdas_temp_token_account.key,
-            takers_token_to_receive_account.key,
-            &pda,
-            &[&pda],
-            pdas_temp_token_account_info.amount,
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            initializers_main_account.key.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let system_program = next_account_info(account_info_iter)?;
+        let offered_mint = next_account_info(account_info_iter)?;
+        let requested_mint = next_account_info(account_info_iter)?;
+        let requested_token_program = next_account_info(account_info_iter)?;
+        let config_account = next_account_info(account_info_iter)?;
+        let treasury_offered_account = next_account_info(account_info_iter)?;
+        let treasury_requested_account = next_account_info(account_info_iter)?;
+
+        let config = Self::load_config(config_account, program_id)?;
+        // The admin raised the fee since the taker looked
+        if config.is_some_and(|config| config.fee_basis_points > max_fee_basis_points) {
+            return Err(EscrowError::ProtocolFeeTooHigh.into());
+        }
+        let requested_fee = Self::protocol_fee(
+            config.as_ref(),
+            payment,
+            treasury_requested_account,
+            &escrow_info.requested_mint_pubkey,
         )?;
-        msg!("Calling the token program to transfer tokens to the taker...");
-        invoke_signed(
-            &transfer_to_taker_ix,
-            &[
+        let initializers_payment = payment
+            .checked_sub(requested_fee)
+            .ok_or(EscrowError::AmountOverflow)?;
+
+        let received = if escrow_info.requests_sol() {
+            msg!("Calling the system program to transfer lamports to the escrow's initializer...");
+            invoke(
+                &system_instruction::transfer(
+                    taker.key,
+                    initializers_token_to_receive_account.key,
+                    initializers_payment,
+                ),
+                &[
+                    taker.clone(),
+                    initializers_token_to_receive_account.clone(),
+                    system_program.clone(),
+                ],
+            )?;
+            if requested_fee > 0 {
+                msg!("Calling the system program to transfer the fee to the treasury...");
+                invoke(
+                    &system_instruction::transfer(
+                        taker.key,
+                        treasury_requested_account.key,
+                        requested_fee,
+                    ),
+                    &[
+                        taker.clone(),
+                        treasury_requested_account.clone(),
+                        system_program.clone(),
+                    ],
+                )?;
+            }
+            initializers_payment
+        } else {
+            let decimals = Self::check_mint(
+                requested_mint,
+                &escrow_info.requested_mint_pubkey,
+                requested_token_program,
+                initializers_token_to_receive_account,
+            )?;
+            if requested_fee > 0 {
+                msg!("Calling the token program to transfer the fee to the treasury...");
+                invoke_transfer_checked(
+                    requested_token_program.key,
+                    takers_sending_token_account.clone(),
+                    requested_mint.clone(),
+                    treasury_requested_account.clone(),
+                    authority.clone(),
+                    additional_accounts,
+                    requested_fee,
+                    decimals,
+                    authority_seeds,
+                )?;
+            }
+            Self::pay_initializer(
+                requested_token_program,
+                takers_sending_token_account,
+                requested_mint,
+                initializers_token_to_receive_account,
+                authority,
+                authority_seeds,
+                additional_accounts,
+                initializers_payment,
+                decimals,
+            )?
+        };
+
+        escrow_info
+            .record_fill(amount_expected_by_taker, payment)
+            .ok_or(EscrowError::AmountOverflow)?;
+        let is_fully_filled = escrow_info.remaining_amount == 0;
+
+        // The taker gets what they took and no more, whatever else was sent to the vault stays
+        // the initializer's
+        let (payout, offered_fee) = if escrow_info.offers_sol() {
+            let offered_fee = Self::protocol_fee(
+                config.as_ref(),
+                amount_expected_by_taker,
+                treasury_offered_account,
+                &escrow_info.offered_mint_pubkey,
+            )?;
+            let takers_payout = amount_expected_by_taker
+                .checked_sub(offered_fee)
+                .ok_or(EscrowError::AmountOverflow)?;
+            msg!("Transferring lamports from the pda's vault to the taker...");
+            Self::transfer_lamports(
+                pdas_temp_token_account,
+                takers_token_to_receive_account,
+                takers_payout,
+            )?;
+            if offered_fee > 0 {
+                msg!("Transferring the fee from the pda's vault to the treasury...");
+                Self::transfer_lamports(
+                    pdas_temp_token_account,
+                    treasury_offered_account,
+                    offered_fee,
+                )?;
+            }
+            (takers_payout, offered_fee)
+        } else {
+            let decimals = Self::check_mint(
+                offered_mint,
+                &escrow_info.offered_mint_pubkey,
+                token_program,
+                pdas_temp_token_account,
+            )?;
+            let offered_fee = Self::protocol_fee(
+                config.as_ref(),
+                amount_expected_by_taker,
+                treasury_offered_account,
+                &escrow_info.offered_mint_pubkey,
+            )?;
+            let takers_payout = amount_expected_by_taker
+                .checked_sub(offered_fee)
+                .ok_or(EscrowError::AmountOverflow)?;
+
+            msg!("Calling the token program to transfer tokens to the taker...");
+            invoke_transfer_checked(
+                token_program.key,
                 pdas_temp_token_account.clone(),
+                offered_mint.clone(),
                 takers_token_to_receive_account.clone(),
                 pda_account.clone(),
-                token_program.clone(),
-            ],
-            &[&[&b"escrow"[..], &[nonce]]],
+                additional_accounts,
+                takers_payout,
+                decimals,
+                &[pda_seeds],
+            )?;
+            if offered_fee > 0 {
+                msg!("Calling the token program to transfer the fee to the treasury...");
+                invoke_transfer_checked(
+                    token_program.key,
+                    pdas_temp_token_account.clone(),
+                    offered_mint.clone(),
+                    treasury_offered_account.clone(),
+                    pda_account.clone(),
+                    additional_accounts,
+                    offered_fee,
+                    decimals,
+                    &[pda_seeds],
+                )?;
+            }
+            (takers_payout, offered_fee)
+        };
+
+        EscrowEvent::from(EscrowExchanged {
+            escrow: *escrow_account.key,
+            taker: *taker.key,
+            offered_mint: escrow_info.offered_mint_pubkey,
+            requested_mint: escrow_info.requested_mint_pubkey,
+            amount: payout,
+            payment: received,
+            remaining_amount: escrow_info.remaining_amount,
+            remaining_expected_amount: escrow_info.expected_amount,
+        })
+        .emit();
+        if offered_fee > 0 || requested_fee > 0 {
+            EscrowEvent::from(FeesCollected {
+                escrow: *escrow_account.key,
+                offered_fee,
+                requested_fee,
+            })
+            .emit();
+        }
+
+        if !is_fully_filled {
+            msg!(
+                "Escrow partially filled, {} tokens remaining",
+                escrow_info.remaining_amount
+            );
+            Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+            return Ok((payment, false));
+        }
+
+        let excess = if escrow_info.offers_sol() {
+            let excess = pdas_temp_token_account
+                .lamports()
+                .saturating_sub(Rent::get()?.minimum_balance(0));
+            msg!("Closing the pda's vault...");
+            Self::close_program_account(pdas_temp_token_account, initializers_main_account)?;
+            excess
+        } else {
+            let excess = Self::unpack_token_account(pdas_temp_token_account)?.amount;
+            if excess > 0 {
+                // A token account can't be closed while it holds tokens
+                Self::hand_over_token_account(
+                    token_program,
+                    pdas_temp_token_account,
+                    initializers_main_account,
+                    pda_account,
+                    pda_seeds,
+                )?;
+            } else {
+                Self::close_token_account(
+                    token_program,
+                    pdas_temp_token_account,
+                    offered_mint,
+                    initializers_main_account,
+                    pda_account,
+                    pda_seeds,
+                )?;
+            }
+            excess
+        };
+
+        EscrowEvent::from(EscrowClosed {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            reason: CloseReason::Filled,
+            refunded_amount: excess,
+        })
+        .emit();
+
+        Ok((payment, true))
+    }
+
+    fn process_cancel(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+
+        Self::check_program_account(escrow_account, program_id)?;
+        let escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if !initializer.is_signer && !escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            escrow_info.initializer_pubkey.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let reason = if initializer.is_signer {
+            CloseReason::Cancelled
+        } else {
+            CloseReason::Expired
+        };
+
+        if escrow_info.offers_sol() {
+            msg!("Returning the pda's vault lamports to the escrow's initializer...");
+            Self::close_program_account(pdas_temp_token_account, initializer)?;
+
+            EscrowEvent::from(EscrowClosed {
+                escrow: *escrow_account.key,
+                initializer: escrow_info.initializer_pubkey,
+                reason,
+                refunded_amount: escrow_info.remaining_amount,
+            })
+            .emit();
+
+            msg!("Closing the escrow account...");
+            return Self::close_program_account(escrow_account, initializer);
+        }
+
+        Self::check_token_account(
+            initializers_token_account,
+            &escrow_info.offered_mint_pubkey,
+            &escrow_info.initializer_pubkey,
         )?;
 
-        let close_pdas_temp_acc_ix = spl_token::instruction::close_account(
-            token_program.key,
-            pdas_temp_token_account.key,
-            initializers_main_account.key,
-            &pda,
-            &[&pda],
+        let offered_mint = next_account_info(account_info_iter)?;
+        let decimals = Self::check_mint(
+            offered_mint,
+            &escrow_info.offered_mint_pubkey,
+            token_program,
+            pdas_temp_token_account,
         )?;
-        msg!("Calling the token program to close pda's temp account...");
+
+        let pdas_temp_token_account_info = Self::unpack_token_account(pdas_temp_token_account)?;
+        msg!("Calling the token program to return tokens to the escrow's initializer...");
+        invoke_transfer_checked(
+            token_program.key,
+            pdas_temp_token_account.clone(),
+            offered_mint.clone(),
+            initializers_token_account.clone(),
+            pda_account.clone(),
+            account_info_iter.as_slice(),
+            pdas_temp_token_account_info.amount,
+            decimals,
+            &[pda_seeds],
+        )?;
+
+        Self::close_token_account(
+            token_program,
+            pdas_temp_token_account,
+            offered_mint,
+            initializer,
+            pda_account,
+            pda_seeds,
+        )?;
+
+        EscrowEvent::from(EscrowClosed {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            reason,
+            refunded_amount: pdas_temp_token_account_info.amount,
+        })
+        .emit();
+
+        msg!("Closing the escrow account...");
+        Self::close_program_account(escrow_account, initializer)
+    }
+
+    fn process_amend(
+        accounts: &[AccountInfo],
+        new_expected_amount: u64,
+        top_up_amount: u64,
+        withdraw_amount: u64,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if top_up_amount > 0 && withdraw_amount > 0 {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        let pdas_temp_token_account = next_account_info(account_info_iter)?;
+        let initializers_token_account = next_account_info(account_info_iter)?;
+
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = Escrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.temp_token_account_pubkey != *pdas_temp_token_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::Expired.into());
+        }
+
+        // Emptying the escrow is what `Cancel` is for
+        if withdraw_amount > 0 && withdraw_amount >= escrow_info.remaining_amount {
+            return Err(ProgramError::InsufficientFunds);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            escrow_info.initializer_pubkey.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let offered_mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        if escrow_info.offers_sol() {
+            if top_up_amount > 0 {
+                msg!("Calling the system program to fund the pda's vault...");
+                invoke(
+                    &system_instruction::transfer(
+                        initializer.key,
+                        pdas_temp_token_account.key,
+                        top_up_amount,
+                    ),
+                    &[
+                        initializer.clone(),
+                        pdas_temp_token_account.clone(),
+                        system_program.clone(),
+                    ],
+                )?;
+            }
+            if withdraw_amount > 0 {
+                msg!("Returning lamports from the pda's vault to the escrow's initializer...");
+                Self::transfer_lamports(pdas_temp_token_account, initializer, withdraw_amount)?;
+            }
+            escrow_info.remaining_amount = escrow_info
+                .remaining_amount
+                .checked_add(top_up_amount)
+                .ok_or(EscrowError::AmountOverflow)?
+                - withdraw_amount;
+        } else if top_up_amount > 0 || withdraw_amount > 0 {
+            Self::check_token_account(
+                initializers_token_account,
+                &escrow_info.offered_mint_pubkey,
+                &escrow_info.initializer_pubkey,
+            )?;
+            let decimals = Self::check_mint(
+                offered_mint,
+                &escrow_info.offered_mint_pubkey,
+                token_program,
+                pdas_temp_token_account,
+            )?;
+
+            if top_up_amount > 0 {
+                let balance_before = Self::unpack_token_account(pdas_temp_token_account)?.amount;
+                msg!("Calling the token program to transfer tokens to the pda's vault...");
+                invoke_transfer_checked(
+                    token_program.key,
+                    initializers_token_account.clone(),
+                    offered_mint.clone(),
+                    pdas_temp_token_account.clone(),
+                    initializer.clone(),
+                    account_info_iter.as_slice(),
+                    top_up_amount,
+                    decimals,
+                    &[],
+                )?;
+                // What actually arrived, net of any transfer fee
+                let received = Self::unpack_token_account(pdas_temp_token_account)?
+                    .amount
+                    .saturating_sub(balance_before);
+                escrow_info.remaining_amount = escrow_info
+                    .remaining_amount
+                    .checked_add(received)
+                    .ok_or(EscrowError::AmountOverflow)?;
+            } else {
+                msg!("Calling the token program to return tokens to the escrow's initializer...");
+                invoke_transfer_checked(
+                    token_program.key,
+                    pdas_temp_token_account.clone(),
+                    offered_mint.clone(),
+                    initializers_token_account.clone(),
+                    pda_account.clone(),
+                    account_info_iter.as_slice(),
+                    withdraw_amount,
+                    decimals,
+                    &[pda_seeds],
+                )?;
+                escrow_info.remaining_amount -= withdraw_amount;
+            }
+        }
+
+        escrow_info.expected_amount = new_expected_amount;
+
+        EscrowEvent::from(EscrowAmended {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            remaining_amount: escrow_info.remaining_amount,
+            expected_amount: escrow_info.expected_amount,
+        })
+        .emit();
+        Escrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_init_bundle(
+        accounts: &[AccountInfo],
+        offered_amounts: &[u64],
+        requested_amounts: &[u64],
+        expires_at: Option<UnixTimestamp>,
+        allowed_taker: Option<Pubkey>,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if !(1..=MAX_BUNDLE_OFFERED_LEGS).contains(&offered_amounts.len())
+            || !(1..=MAX_BUNDLE_REQUESTED_LEGS).contains(&requested_amounts.len())
+        {
+            return Err(EscrowError::InvalidBundleSize.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let bundle_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        if !rent.is_exempt(bundle_account.lamports(), bundle_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        {
+            let data = bundle_account.try_borrow_data()?;
+            if data.len() != Bundle::get_packed_len(offered_amounts.len(), requested_amounts.len())
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if data[0] != 0 {
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
+        }
+
+        let system_program = next_account_info(account_info_iter)?;
+        let offered_accounts = next_account_infos(account_info_iter, 4 * offered_amounts.len())?;
+        let requested_accounts = next_account_infos(account_info_iter, requested_amounts.len())?;
+        // Whatever is left is handed to the token program for transfer hooks
+        let additional_accounts = account_info_iter.as_slice();
+
+        let (pda, bump_seed) = find_escrow_pda(program_id, bundle_account.key, initializer.key);
+
+        let mut offered = Vec::with_capacity(offered_amounts.len());
+        for (index, (leg, &offered_amount)) in offered_accounts
+            .chunks_exact(4)
+            .zip(offered_amounts)
+            .enumerate()
+        {
+            let [initializers_token_account, pdas_vault_account, offered_mint, token_program]: &[AccountInfo; 4] =
+                leg.try_into().map_err(|_| ProgramError::NotEnoughAccountKeys)?;
+
+            let initializers_token_account_info =
+                Self::unpack_token_account(initializers_token_account)?;
+            let decimals = Self::check_mint(
+                offered_mint,
+                &initializers_token_account_info.mint,
+                token_program,
+                initializers_token_account,
+            )?;
+
+            let index = index as u8;
+            let (vault, vault_bump_seed) =
+                find_bundle_vault_address(program_id, bundle_account.key, index);
+            if vault != *pdas_vault_account.key {
+                return Err(ProgramError::InvalidSeeds);
+            }
+            Self::create_vault(
+                initializer,
+                pdas_vault_account,
+                offered_mint,
+                token_program,
+                system_program,
+                rent,
+                &pda,
+                &[
+                    VAULT_PDA_SEED,
+                    bundle_account.key.as_ref(),
+                    &[index],
+                    &[vault_bump_seed],
+                ],
+            )?;
+
+            msg!("Calling the token program to transfer tokens to the pda's vault...");
+            invoke_transfer_checked(
+                token_program.key,
+                initializers_token_account.clone(),
+                offered_mint.clone(),
+                pdas_vault_account.clone(),
+                initializer.clone(),
+                additional_accounts,
+                offered_amount,
+                decimals,
+                &[],
+            )?;
+
+            offered.push(BundleOffer {
+                vault_pubkey: vault,
+                mint_pubkey: *offered_mint.key,
+                // What actually arrived, net of any transfer fee
+                amount: Self::unpack_token_account(pdas_vault_account)?.amount,
+            });
+        }
+
+        let requested = requested_accounts
+            .iter()
+            .zip(requested_amounts)
+            .map(|(token_to_receive_account, &amount)| {
+                Ok(BundleRequest {
+                    mint_pubkey: Self::unpack_token_account(token_to_receive_account)?.mint,
+                    initializer_token_to_receive_account_pubkey: *token_to_receive_account.key,
+                    amount,
+                })
+            })
+            .collect::<Result<Vec<_>, ProgramError>>()?;
+
+        let bundle_info = Bundle {
+            initializer_pubkey: *initializer.key,
+            expires_at,
+            allowed_taker,
+            bump_seed,
+            offered,
+            requested,
+        };
+        if bundle_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::Expired.into());
+        }
+
+        EscrowEvent::from(BundleCreated {
+            bundle: *bundle_account.key,
+            initializer: bundle_info.initializer_pubkey,
+            offered: bundle_info
+                .offered
+                .iter()
+                .map(|offer| BundleAmount {
+                    mint: offer.mint_pubkey,
+                    amount: offer.amount,
+                })
+                .collect(),
+            requested: bundle_info
+                .requested
+                .iter()
+                .map(|request| BundleAmount {
+                    mint: request.mint_pubkey,
+                    amount: request.amount,
+                })
+                .collect(),
+            expires_at: bundle_info.expires_at,
+            allowed_taker: bundle_info.allowed_taker,
+        })
+        .emit();
+        bundle_info.pack(&mut bundle_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_exchange_bundle(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let taker = next_account_info(account_info_iter)?;
+
+        if !taker.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let initializers_main_account = next_account_info(account_info_iter)?;
+        let bundle_account = next_account_info(account_info_iter)?;
+
+        Self::check_program_account(bundle_account, program_id)?;
+        let bundle_info = Bundle::unpack(&bundle_account.try_borrow_data()?)?;
+
+        if bundle_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::Expired.into());
+        }
+
+        if !bundle_info.is_taker_allowed(taker.key) {
+            return Err(EscrowError::TakerNotAllowed.into());
+        }
+
+        if bundle_info.initializer_pubkey != *initializers_main_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            bundle_account.key.as_ref(),
+            bundle_info.initializer_pubkey.as_ref(),
+            &[bundle_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let offered_accounts =
+            next_account_infos(account_info_iter, 4 * bundle_info.offered.len())?;
+        let requested_accounts =
+            next_account_infos(account_info_iter, 4 * bundle_info.requested.len())?;
+        // Whatever is left is handed to the token program for transfer hooks
+        let additional_accounts = account_info_iter.as_slice();
+
+        // Every leg settles here or the whole transaction fails, so a taker can never end up
+        // with only part of the bundle
+        let mut payments = Vec::with_capacity(bundle_info.requested.len());
+        for (request, leg) in bundle_info
+            .requested
+            .iter()
+            .zip(requested_accounts.chunks_exact(4))
+        {
+            let [takers_sending_token_account, initializers_token_to_receive_account, requested_mint, token_program]: &[AccountInfo; 4] =
+                leg.try_into().map_err(|_| ProgramError::NotEnoughAccountKeys)?;
+
+            if request.initializer_token_to_receive_account_pubkey
+                != *initializers_token_to_receive_account.key
+            {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            Self::check_token_account(
+                takers_sending_token_account,
+                &request.mint_pubkey,
+                taker.key,
+            )?;
+            let decimals = Self::check_mint(
+                requested_mint,
+                &request.mint_pubkey,
+                token_program,
+                initializers_token_to_receive_account,
+            )?;
+
+            let received = Self::pay_initializer(
+                token_program,
+                takers_sending_token_account,
+                requested_mint,
+                initializers_token_to_receive_account,
+                taker,
+                &[],
+                additional_accounts,
+                request.amount,
+                decimals,
+            )?;
+            payments.push(BundleAmount {
+                mint: request.mint_pubkey,
+                amount: received,
+            });
+        }
+
+        let mut amounts = Vec::with_capacity(bundle_info.offered.len());
+        for (offer, leg) in bundle_info
+            .offered
+            .iter()
+            .zip(offered_accounts.chunks_exact(4))
+        {
+            let [pdas_vault_account, takers_token_to_receive_account, offered_mint, token_program]: &[AccountInfo; 4] =
+                leg.try_into().map_err(|_| ProgramError::NotEnoughAccountKeys)?;
+
+            if offer.vault_pubkey != *pdas_vault_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            Self::check_token_account(
+                takers_token_to_receive_account,
+                &offer.mint_pubkey,
+                taker.key,
+            )?;
+
+            msg!("Calling the token program to transfer tokens to the taker...");
+            let amount = Self::empty_vault(
+                token_program,
+                pdas_vault_account,
+                offered_mint,
+                &offer.mint_pubkey,
+                takers_token_to_receive_account,
+                initializers_main_account,
+                pda_account,
+                additional_accounts,
+                pda_seeds,
+            )?;
+            amounts.push(BundleAmount {
+                mint: offer.mint_pubkey,
+                amount,
+            });
+        }
+
+        EscrowEvent::from(BundleExchanged {
+            bundle: *bundle_account.key,
+            taker: *taker.key,
+            amounts,
+            payments,
+        })
+        .emit();
+        EscrowEvent::from(BundleClosed {
+            bundle: *bundle_account.key,
+            initializer: bundle_info.initializer_pubkey,
+            reason: CloseReason::Filled,
+            refunded: vec![],
+        })
+        .emit();
+
+        msg!("Closing the bundle account...");
+        Self::close_program_account(bundle_account, initializers_main_account)
+    }
+
+    fn process_cancel_bundle(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+        let bundle_account = next_account_info(account_info_iter)?;
+
+        Self::check_program_account(bundle_account, program_id)?;
+        let bundle_info = Bundle::unpack(&bundle_account.try_borrow_data()?)?;
+
+        if !initializer.is_signer && !bundle_info.is_expired(Clock::get()?.unix_timestamp) {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if bundle_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            bundle_account.key.as_ref(),
+            bundle_info.initializer_pubkey.as_ref(),
+            &[bundle_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let offered_accounts =
+            next_account_infos(account_info_iter, 4 * bundle_info.offered.len())?;
+        let additional_accounts = account_info_iter.as_slice();
+
+        let mut refunded = Vec::with_capacity(bundle_info.offered.len());
+        for (offer, leg) in bundle_info
+            .offered
+            .iter()
+            .zip(offered_accounts.chunks_exact(4))
+        {
+            let [pdas_vault_account, initializers_token_account, offered_mint, token_program]: &[AccountInfo; 4] =
+                leg.try_into().map_err(|_| ProgramError::NotEnoughAccountKeys)?;
+
+            if offer.vault_pubkey != *pdas_vault_account.key {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            Self::check_token_account(
+                initializers_token_account,
+                &offer.mint_pubkey,
+                &bundle_info.initializer_pubkey,
+            )?;
+
+            msg!("Calling the token program to return tokens to the bundle's initializer...");
+            let amount = Self::empty_vault(
+                token_program,
+                pdas_vault_account,
+                offered_mint,
+                &offer.mint_pubkey,
+                initializers_token_account,
+                initializer,
+                pda_account,
+                additional_accounts,
+                pda_seeds,
+            )?;
+            refunded.push(BundleAmount {
+                mint: offer.mint_pubkey,
+                amount,
+            });
+        }
+
+        EscrowEvent::from(BundleClosed {
+            bundle: *bundle_account.key,
+            initializer: bundle_info.initializer_pubkey,
+            reason: if initializer.is_signer {
+                CloseReason::Cancelled
+            } else {
+                CloseReason::Expired
+            },
+            refunded,
+        })
+        .emit();
+
+        msg!("Closing the bundle account...");
+        Self::close_program_account(bundle_account, initializer)
+    }
+
+    fn process_init_arbiter_escrow(
+        accounts: &[AccountInfo],
+        amount: u64,
+        recipient: Pubkey,
+        arbiter: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        // Approvals are counted per party, so one key in two roles would count twice
+        if *initializer.key == recipient || *initializer.key == arbiter || recipient == arbiter {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let initializers_token_account_info =
+            Self::unpack_token_account(initializers_token_account)?;
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = ArbiterEscrow::unpack_unchecked(&escrow_account.try_borrow_data()?)?;
+        if escrow_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let decimals = Self::check_mint(
+            mint,
+            &initializers_token_account_info.mint,
+            token_program,
+            initializers_token_account,
+        )?;
+
+        let (vault, vault_bump_seed) = find_vault_address(program_id, escrow_account.key);
+        if vault != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let (pda, bump_seed) = find_escrow_pda(program_id, escrow_account.key, initializer.key);
+
+        Self::create_vault(
+            initializer,
+            pdas_vault_account,
+            mint,
+            token_program,
+            system_program,
+            rent,
+            &pda,
+            &[
+                VAULT_PDA_SEED,
+                escrow_account.key.as_ref(),
+                &[vault_bump_seed],
+            ],
+        )?;
+
+        msg!("Calling the token program to transfer tokens to the pda's vault...");
+        invoke_transfer_checked(
+            token_program.key,
+            initializers_token_account.clone(),
+            mint.clone(),
+            pdas_vault_account.clone(),
+            initializer.clone(),
+            account_info_iter.as_slice(),
+            amount,
+            decimals,
+            &[],
+        )?;
+
+        escrow_info.is_initialized = true;
+        escrow_info.initializer_pubkey = *initializer.key;
+        escrow_info.recipient_pubkey = recipient;
+        escrow_info.arbiter_pubkey = arbiter;
+        escrow_info.vault_pubkey = vault;
+        escrow_info.mint_pubkey = *mint.key;
+        // What actually arrived, net of any transfer fee
+        escrow_info.amount = Self::unpack_token_account(pdas_vault_account)?.amount;
+        escrow_info.is_disputed = false;
+        escrow_info.bump_seed = bump_seed;
+
+        EscrowEvent::from(ArbiterEscrowCreated {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            recipient,
+            arbiter,
+            mint: escrow_info.mint_pubkey,
+            amount: escrow_info.amount,
+        })
+        .emit();
+        ArbiterEscrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_dispute(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let disputing_party = next_account_info(account_info_iter)?;
+
+        if !disputing_party.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = ArbiterEscrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *disputing_party.key
+            && escrow_info.recipient_pubkey != *disputing_party.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if escrow_info.is_disputed {
+            return Err(EscrowError::AlreadyDisputed.into());
+        }
+        escrow_info.is_disputed = true;
+
+        EscrowEvent::from(ArbiterEscrowDisputed {
+            escrow: *escrow_account.key,
+            disputed_by: *disputing_party.key,
+        })
+        .emit();
+        ArbiterEscrow::pack(escrow_info, &mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Empties the vault of an arbiter escrow to its recipient or back to its initializer, and
+    /// closes both
+    fn process_settle(
+        accounts: &[AccountInfo],
+        settlement: Settlement,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+        let recipient = next_account_info(account_info_iter)?;
+        let arbiter = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        let destination_token_account = next_account_info(account_info_iter)?;
+
+        Self::check_program_account(escrow_account, program_id)?;
+        let escrow_info = ArbiterEscrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key
+            || escrow_info.recipient_pubkey != *recipient.key
+            || escrow_info.arbiter_pubkey != *arbiter.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if !escrow_info.is_settlement_approved(
+            initializer.is_signer,
+            recipient.is_signer,
+            arbiter.is_signer,
+        ) {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if escrow_info.vault_pubkey != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let destination_owner = match settlement {
+            Settlement::Released => &escrow_info.recipient_pubkey,
+            Settlement::Refunded => &escrow_info.initializer_pubkey,
+        };
+        Self::check_token_account(
+            destination_token_account,
+            &escrow_info.mint_pubkey,
+            destination_owner,
+        )?;
+
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            escrow_info.initializer_pubkey.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        msg!("Calling the token program to settle the arbiter escrow's vault...");
+        let amount = Self::empty_vault(
+            token_program,
+            pdas_vault_account,
+            mint,
+            &escrow_info.mint_pubkey,
+            destination_token_account,
+            initializer,
+            pda_account,
+            account_info_iter.as_slice(),
+            pda_seeds,
+        )?;
+
+        EscrowEvent::from(ArbiterEscrowSettled {
+            escrow: *escrow_account.key,
+            settlement,
+            amount,
+        })
+        .emit();
+
+        msg!("Closing the arbiter escrow account...");
+        Self::close_program_account(escrow_account, initializer)
+    }
+
+    fn process_init_milestone_escrow(
+        accounts: &[AccountInfo],
+        amounts: &[u64],
+        deadline: UnixTimestamp,
+        recipient: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if !(1..=MAX_MILESTONES).contains(&amounts.len()) || amounts.contains(&0) {
+            return Err(EscrowError::InvalidMilestones.into());
+        }
+        let total = amounts
+            .iter()
+            .try_fold(0u64, |total, &amount| total.checked_add(amount))
+            .ok_or(EscrowError::AmountOverflow)?;
+
+        if Clock::get()?.unix_timestamp >= deadline {
+            return Err(EscrowError::Expired.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        if *initializer.key == recipient {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        let initializers_token_account_info =
+            Self::unpack_token_account(initializers_token_account)?;
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        if !rent.is_exempt(escrow_account.lamports(), escrow_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        {
+            let data = escrow_account.try_borrow_data()?;
+            if data.len() != MilestoneEscrow::get_packed_len(amounts.len()) {
+                return Err(ProgramError::InvalidAccountData);
+            }
+            if data[0] != 0 {
+                return Err(ProgramError::AccountAlreadyInitialized);
+            }
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let decimals = Self::check_mint(
+            mint,
+            &initializers_token_account_info.mint,
+            token_program,
+            initializers_token_account,
+        )?;
+
+        let (vault, vault_bump_seed) = find_vault_address(program_id, escrow_account.key);
+        if vault != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let (pda, bump_seed) = find_escrow_pda(program_id, escrow_account.key, initializer.key);
+
+        Self::create_vault(
+            initializer,
+            pdas_vault_account,
+            mint,
+            token_program,
+            system_program,
+            rent,
+            &pda,
+            &[
+                VAULT_PDA_SEED,
+                escrow_account.key.as_ref(),
+                &[vault_bump_seed],
+            ],
+        )?;
+
+        // Every milestone is paid out of the vault in full, so it has to receive the whole total
+        let sent = Self::add_transfer_fee(mint, total)?;
+        msg!("Calling the token program to transfer tokens to the pda's vault...");
+        invoke_transfer_checked(
+            token_program.key,
+            initializers_token_account.clone(),
+            mint.clone(),
+            pdas_vault_account.clone(),
+            initializer.clone(),
+            account_info_iter.as_slice(),
+            sent,
+            decimals,
+            &[],
+        )?;
+
+        let escrow_info = MilestoneEscrow {
+            initializer_pubkey: *initializer.key,
+            recipient_pubkey: recipient,
+            vault_pubkey: vault,
+            mint_pubkey: *mint.key,
+            deadline,
+            bump_seed,
+            released_milestones: 0,
+            milestones: amounts.to_vec(),
+        };
+
+        EscrowEvent::from(MilestoneEscrowCreated {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            recipient,
+            mint: escrow_info.mint_pubkey,
+            milestones: escrow_info.milestones.clone(),
+            deadline,
+        })
+        .emit();
+        escrow_info.pack(&mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_approve_milestone(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+
+        if !initializer.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let escrow_account = next_account_info(account_info_iter)?;
+        Self::check_program_account(escrow_account, program_id)?;
+        let mut escrow_info = MilestoneEscrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        if escrow_info.vault_pubkey != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let recipients_token_account = next_account_info(account_info_iter)?;
+        Self::check_token_account(
+            recipients_token_account,
+            &escrow_info.mint_pubkey,
+            &escrow_info.recipient_pubkey,
+        )?;
+
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            initializer.key.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let index = escrow_info.released_milestones;
+        let amount = escrow_info
+            .next_milestone()
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        if escrow_info.is_last_milestone() {
+            msg!("Calling the token program to release the last milestone...");
+            Self::empty_vault(
+                token_program,
+                pdas_vault_account,
+                mint,
+                &escrow_info.mint_pubkey,
+                recipients_token_account,
+                initializer,
+                pda_account,
+                account_info_iter.as_slice(),
+                pda_seeds,
+            )?;
+        } else {
+            let decimals = Self::check_mint(
+                mint,
+                &escrow_info.mint_pubkey,
+                token_program,
+                pdas_vault_account,
+            )?;
+            msg!("Calling the token program to release the milestone...");
+            invoke_transfer_checked(
+                token_program.key,
+                pdas_vault_account.clone(),
+                mint.clone(),
+                recipients_token_account.clone(),
+                pda_account.clone(),
+                account_info_iter.as_slice(),
+                amount,
+                decimals,
+                &[pda_seeds],
+            )?;
+        }
+
+        EscrowEvent::from(MilestoneReleased {
+            escrow: *escrow_account.key,
+            index,
+            amount,
+        })
+        .emit();
+
+        if escrow_info.is_last_milestone() {
+            msg!("Closing the milestone escrow account...");
+            return Self::close_program_account(escrow_account, initializer);
+        }
+        escrow_info.released_milestones += 1;
+        escrow_info.pack(&mut escrow_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_refund_milestones(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let initializer = next_account_info(account_info_iter)?;
+        let escrow_account = next_account_info(account_info_iter)?;
+        Self::check_program_account(escrow_account, program_id)?;
+        let escrow_info = MilestoneEscrow::unpack(&escrow_account.try_borrow_data()?)?;
+
+        if escrow_info.initializer_pubkey != *initializer.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        if !escrow_info.is_past_deadline(Clock::get()?.unix_timestamp) {
+            return Err(EscrowError::DeadlineNotReached.into());
+        }
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        if escrow_info.vault_pubkey != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let initializers_token_account = next_account_info(account_info_iter)?;
+        Self::check_token_account(
+            initializers_token_account,
+            &escrow_info.mint_pubkey,
+            &escrow_info.initializer_pubkey,
+        )?;
+
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            escrow_account.key.as_ref(),
+            initializer.key.as_ref(),
+            &[escrow_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        msg!("Calling the token program to refund the unreleased milestones...");
+        let refunded = Self::empty_vault(
+            token_program,
+            pdas_vault_account,
+            mint,
+            &escrow_info.mint_pubkey,
+            initializers_token_account,
+            initializer,
+            pda_account,
+            account_info_iter.as_slice(),
+            pda_seeds,
+        )?;
+
+        EscrowEvent::from(MilestoneEscrowRefunded {
+            escrow: *escrow_account.key,
+            initializer: escrow_info.initializer_pubkey,
+            refunded,
+        })
+        .emit();
+
+        msg!("Closing the milestone escrow account...");
+        Self::close_program_account(escrow_account, initializer)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn process_init_stream(
+        accounts: &[AccountInfo],
+        amount: u64,
+        start_time: UnixTimestamp,
+        end_time: UnixTimestamp,
+        cliff_time: Option<UnixTimestamp>,
+        beneficiary: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if amount == 0
+            || start_time >= end_time
+            || matches!(cliff_time, Some(cliff_time) if cliff_time < start_time || cliff_time > end_time)
+        {
+            return Err(EscrowError::InvalidInstruction.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let funder = next_account_info(account_info_iter)?;
+
+        if !funder.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let funders_token_account = next_account_info(account_info_iter)?;
+        let funders_token_account_info = Self::unpack_token_account(funders_token_account)?;
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+        let rent = &Rent::get()?;
+
+        if !rent.is_exempt(stream_account.lamports(), stream_account.data_len()) {
+            return Err(EscrowError::NotRentExempt.into());
+        }
+
+        Self::check_program_account(stream_account, program_id)?;
+        let mut stream_info = Stream::unpack_unchecked(&stream_account.try_borrow_data()?)?;
+        if stream_info.is_initialized() {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let token_program = next_account_info(account_info_iter)?;
+        let mint = next_account_info(account_info_iter)?;
+        let system_program = next_account_info(account_info_iter)?;
+
+        let decimals = Self::check_mint(
+            mint,
+            &funders_token_account_info.mint,
+            token_program,
+            funders_token_account,
+        )?;
+
+        let (vault, vault_bump_seed) = find_vault_address(program_id, stream_account.key);
+        if vault != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        let (pda, bump_seed) = find_escrow_pda(program_id, stream_account.key, funder.key);
+
+        Self::create_vault(
+            funder,
+            pdas_vault_account,
+            mint,
+            token_program,
+            system_program,
+            rent,
+            &pda,
+            &[
+                VAULT_PDA_SEED,
+                stream_account.key.as_ref(),
+                &[vault_bump_seed],
+            ],
+        )?;
+
+        msg!("Calling the token program to transfer tokens to the pda's vault...");
+        invoke_transfer_checked(
+            token_program.key,
+            funders_token_account.clone(),
+            mint.clone(),
+            pdas_vault_account.clone(),
+            funder.clone(),
+            account_info_iter.as_slice(),
+            amount,
+            decimals,
+            &[],
+        )?;
+
+        stream_info.is_initialized = true;
+        stream_info.funder_pubkey = *funder.key;
+        stream_info.beneficiary_pubkey = beneficiary;
+        stream_info.vault_pubkey = vault;
+        stream_info.mint_pubkey = *mint.key;
+        // What actually arrived, net of any transfer fee
+        stream_info.total_amount = Self::unpack_token_account(pdas_vault_account)?.amount;
+        stream_info.claimed_amount = 0;
+        stream_info.start_time = start_time;
+        stream_info.end_time = end_time;
+        stream_info.cliff_time = cliff_time;
+        stream_info.bump_seed = bump_seed;
+
+        EscrowEvent::from(StreamCreated {
+            stream: *stream_account.key,
+            funder: stream_info.funder_pubkey,
+            beneficiary,
+            mint: stream_info.mint_pubkey,
+            amount: stream_info.total_amount,
+            start_time,
+            end_time,
+            cliff_time,
+        })
+        .emit();
+        Stream::pack(stream_info, &mut stream_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_claim(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let beneficiary = next_account_info(account_info_iter)?;
+
+        if !beneficiary.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let funder = next_account_info(account_info_iter)?;
+        let stream_account = next_account_info(account_info_iter)?;
+        Self::check_program_account(stream_account, program_id)?;
+        let mut stream_info = Stream::unpack(&stream_account.try_borrow_data()?)?;
+
+        if stream_info.beneficiary_pubkey != *beneficiary.key
+            || stream_info.funder_pubkey != *funder.key
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let amount = stream_info.claimable_amount_at(Clock::get()?.unix_timestamp);
+        if amount == 0 {
+            return Err(EscrowError::NothingToClaim.into());
+        }
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        if stream_info.vault_pubkey != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let beneficiarys_token_account = next_account_info(account_info_iter)?;
+        Self::check_token_account(
+            beneficiarys_token_account,
+            &stream_info.mint_pubkey,
+            &stream_info.beneficiary_pubkey,
+        )?;
+
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            stream_account.key.as_ref(),
+            funder.key.as_ref(),
+            &[stream_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        stream_info.claimed_amount = stream_info
+            .claimed_amount
+            .checked_add(amount)
+            .ok_or(EscrowError::AmountOverflow)?;
+        let is_fully_claimed = stream_info.claimed_amount == stream_info.total_amount;
+
+        if is_fully_claimed {
+            msg!("Calling the token program to pay out the rest of the stream...");
+            Self::empty_vault(
+                token_program,
+                pdas_vault_account,
+                mint,
+                &stream_info.mint_pubkey,
+                beneficiarys_token_account,
+                funder,
+                pda_account,
+                account_info_iter.as_slice(),
+                pda_seeds,
+            )?;
+        } else {
+            let decimals = Self::check_mint(
+                mint,
+                &stream_info.mint_pubkey,
+                token_program,
+                pdas_vault_account,
+            )?;
+            msg!("Calling the token program to pay out the vested tokens...");
+            invoke_transfer_checked(
+                token_program.key,
+                pdas_vault_account.clone(),
+                mint.clone(),
+                beneficiarys_token_account.clone(),
+                pda_account.clone(),
+                account_info_iter.as_slice(),
+                amount,
+                decimals,
+                &[pda_seeds],
+            )?;
+        }
+
+        EscrowEvent::from(StreamClaimed {
+            stream: *stream_account.key,
+            amount,
+            claimed_amount: stream_info.claimed_amount,
+        })
+        .emit();
+
+        if is_fully_claimed {
+            msg!("Closing the stream account...");
+            return Self::close_program_account(stream_account, funder);
+        }
+        Stream::pack(stream_info, &mut stream_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_cancel_stream(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let funder = next_account_info(account_info_iter)?;
+
+        if !funder.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let stream_account = next_account_info(account_info_iter)?;
+        Self::check_program_account(stream_account, program_id)?;
+        let stream_info = Stream::unpack(&stream_account.try_borrow_data()?)?;
+
+        if stream_info.funder_pubkey != *funder.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let pdas_vault_account = next_account_info(account_info_iter)?;
+        if stream_info.vault_pubkey != *pdas_vault_account.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let funders_token_account = next_account_info(account_info_iter)?;
+        Self::check_token_account(
+            funders_token_account,
+            &stream_info.mint_pubkey,
+            &stream_info.funder_pubkey,
+        )?;
+        let beneficiarys_token_account = next_account_info(account_info_iter)?;
+        Self::check_token_account(
+            beneficiarys_token_account,
+            &stream_info.mint_pubkey,
+            &stream_info.beneficiary_pubkey,
+        )?;
+
+        let mint = next_account_info(account_info_iter)?;
+        let token_program = next_account_info(account_info_iter)?;
+        let pda_account = next_account_info(account_info_iter)?;
+        let pda_seeds: &[&[u8]] = &[
+            ESCROW_PDA_SEED,
+            stream_account.key.as_ref(),
+            funder.key.as_ref(),
+            &[stream_info.bump_seed],
+        ];
+        let pda = Pubkey::create_program_address(pda_seeds, program_id)?;
+        if pda != *pda_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+
+        let is_paused = Self::is_paused(accounts, program_id)?;
+        let additional_accounts = account_info_iter.as_slice();
+        // What has vested belongs to the beneficiary whether or not it has been claimed yet
+        let now = Clock::get()?.unix_timestamp;
+        let vested = stream_info.claimable_amount_at(now);
+        if is_paused && vested > 0 {
+            return Self::cut_off_stream(
+                stream_account,
+                stream_info,
+                pdas_vault_account,
+                funders_token_account,
+                mint,
+                token_program,
+                pda_account,
+                additional_accounts,
+                pda_seeds,
+                now,
+            );
+        }
+        if vested > 0 {
+            let decimals = Self::check_mint(
+                mint,
+                &stream_info.mint_pubkey,
+                token_program,
+                pdas_vault_account,
+            )?;
+            msg!("Calling the token program to pay out the vested tokens...");
+            invoke_transfer_checked(
+                token_program.key,
+                pdas_vault_account.clone(),
+                mint.clone(),
+                beneficiarys_token_account.clone(),
+                pda_account.clone(),
+                additional_accounts,
+                vested,
+                decimals,
+                &[pda_seeds],
+            )?;
+        }
+
+        msg!("Calling the token program to refund the unvested tokens...");
+        let refunded = Self::empty_vault(
+            token_program,
+            pdas_vault_account,
+            mint,
+            &stream_info.mint_pubkey,
+            funders_token_account,
+            funder,
+            pda_account,
+            additional_accounts,
+            pda_seeds,
+        )?;
+
+        EscrowEvent::from(StreamCancelled {
+            stream: *stream_account.key,
+            vested,
+            refunded,
+        })
+        .emit();
+
+        msg!("Closing the stream account...");
+        Self::close_program_account(stream_account, funder)
+    }
+
+    /// Cancels a stream while payouts are paused: only the unvested tokens go back to the funder
+    /// and the stream now ends at `now`, so the beneficiary claims what had vested once unpaused
+    #[allow(clippy::too_many_arguments)]
+    fn cut_off_stream<'a>(
+        stream_account: &AccountInfo<'a>,
+        mut stream_info: Stream,
+        pdas_vault_account: &AccountInfo<'a>,
+        funders_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        additional_accounts: &[AccountInfo<'a>],
+        pda_seeds: &[&[u8]],
+        now: UnixTimestamp,
+    ) -> ProgramResult {
+        let vested = stream_info.claimable_amount_at(now);
+        let decimals = Self::check_mint(
+            mint,
+            &stream_info.mint_pubkey,
+            token_program,
+            pdas_vault_account,
+        )?;
+        let refunded = Self::unpack_token_account(pdas_vault_account)?
+            .amount
+            .saturating_sub(vested);
+        if refunded > 0 {
+            msg!("Calling the token program to refund the unvested tokens...");
+            invoke_transfer_checked(
+                token_program.key,
+                pdas_vault_account.clone(),
+                mint.clone(),
+                funders_token_account.clone(),
+                pda_account.clone(),
+                additional_accounts,
+                refunded,
+                decimals,
+                &[pda_seeds],
+            )?;
+        }
+
+        stream_info.total_amount = stream_info
+            .claimed_amount
+            .checked_add(vested)
+            .ok_or(EscrowError::AmountOverflow)?;
+        stream_info.end_time = now;
+
+        EscrowEvent::from(StreamCutOff {
+            stream: *stream_account.key,
+            vested,
+            refunded,
+        })
+        .emit();
+
+        Stream::pack(stream_info, &mut stream_account.try_borrow_mut_data()?)?;
+        Ok(())
+    }
+
+    fn process_initialize_config(
+        accounts: &[AccountInfo],
+        fee_basis_points: u16,
+        treasury: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if fee_basis_points > MAX_PROTOCOL_FEE_BASIS_POINTS {
+            return Err(EscrowError::InvalidFee.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config_account = next_account_info(account_info_iter)?;
+        let (config, bump_seed) = find_config_address(program_id);
+        if config != *config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if config_account.owner == program_id {
+            return Err(ProgramError::AccountAlreadyInitialized);
+        }
+
+        let system_program = next_account_info(account_info_iter)?;
+        let program_data = next_account_info(account_info_iter)?;
+
+        if Self::upgrade_authority(program_data, program_id)? != Some(*admin.key) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        msg!("Calling the system program to create the config account...");
+        Self::create_pda_account(
+            admin,
+            config_account,
+            system_program,
+            &Rent::get()?,
+            Config::LEN,
+            program_id,
+            &[CONFIG_PDA_SEED, &[bump_seed]],
+        )?;
+
+        let config_info = Config {
+            is_initialized: true,
+            admin_pubkey: *admin.key,
+            fee_basis_points,
+            treasury_pubkey: treasury,
+            bump_seed,
+            is_paused: false,
+        };
+        EscrowEvent::from(ConfigUpdated {
+            admin: config_info.admin_pubkey,
+            fee_basis_points,
+            treasury,
+        })
+        .emit();
+        Config::pack(config_info, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_update_config(
+        accounts: &[AccountInfo],
+        new_admin: Pubkey,
+        fee_basis_points: u16,
+        treasury: Pubkey,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        if fee_basis_points > MAX_PROTOCOL_FEE_BASIS_POINTS {
+            return Err(EscrowError::InvalidFee.into());
+        }
+
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config_account = next_account_info(account_info_iter)?;
+        let mut config_info = Self::load_config(config_account, program_id)?
+            .ok_or(ProgramError::UninitializedAccount)?;
+
+        if config_info.admin_pubkey != *admin.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        config_info.admin_pubkey = new_admin;
+        config_info.fee_basis_points = fee_basis_points;
+        config_info.treasury_pubkey = treasury;
+
+        EscrowEvent::from(ConfigUpdated {
+            admin: new_admin,
+            fee_basis_points,
+            treasury,
+        })
+        .emit();
+        Config::pack(config_info, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    fn process_set_paused(
+        accounts: &[AccountInfo],
+        is_paused: bool,
+        program_id: &Pubkey,
+    ) -> ProgramResult {
+        let account_info_iter = &mut accounts.iter();
+        let admin = next_account_info(account_info_iter)?;
+
+        if !admin.is_signer {
+            return Err(ProgramError::MissingRequiredSignature);
+        }
+
+        let config_account = next_account_info(account_info_iter)?;
+        let mut config_info = Self::load_config(config_account, program_id)?
+            .ok_or(ProgramError::UninitializedAccount)?;
+
+        if config_info.admin_pubkey != *admin.key {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        config_info.is_paused = is_paused;
+
+        EscrowEvent::from(PauseToggled {
+            admin: *admin.key,
+            is_paused,
+        })
+        .emit();
+        Config::pack(config_info, &mut config_account.try_borrow_mut_data()?)?;
+
+        Ok(())
+    }
+
+    /// Refuses to go on while the program is paused. The config account can sit anywhere among
+    /// `accounts`, since it follows a variable number of legs in bundle instructions
+    fn check_not_paused(accounts: &[AccountInfo], program_id: &Pubkey) -> ProgramResult {
+        if Self::is_paused(accounts, program_id)? {
+            return Err(EscrowError::ProgramPaused.into());
+        }
+        Ok(())
+    }
+
+    /// Whether the program is paused according to the config account among `accounts`
+    fn is_paused(accounts: &[AccountInfo], program_id: &Pubkey) -> Result<bool, ProgramError> {
+        let (config, _bump_seed) = find_config_address(program_id);
+        let config_account = accounts
+            .iter()
+            .find(|account| *account.key == config)
+            .ok_or(EscrowError::MissingConfigAccount)?;
+        Ok(Self::read_config(config_account, program_id)?.is_some_and(|config| config.is_paused))
+    }
+
+    /// Reads the program's config, or `None` while it has not been initialized
+    fn load_config(
+        config_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Config>, ProgramError> {
+        let (config, _bump_seed) = find_config_address(program_id);
+        if config != *config_account.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        Self::read_config(config_account, program_id)
+    }
+
+    /// Who may upgrade the program according to its ProgramData account, `None` once it is
+    /// immutable
+    fn upgrade_authority(
+        program_data: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Pubkey>, ProgramError> {
+        let (address, _bump_seed) = find_program_data_address(program_id);
+        if address != *program_data.key {
+            return Err(ProgramError::InvalidSeeds);
+        }
+        if *program_data.owner != bpf_loader_upgradeable::id() {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+
+        let data = program_data.try_borrow_data()?;
+        if data.len() < UpgradeableLoaderState::size_of_programdata_metadata() {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![data, 0, 45];
+        let (state, _slot, authority_option, authority) = array_refs![src, 4, 8, 1, 32];
+        if u32::from_le_bytes(*state) != PROGRAM_DATA_STATE {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        match authority_option {
+            [0] => Ok(None),
+            [1] => Ok(Some(Pubkey::new_from_array(*authority))),
+            _ => Err(ProgramError::InvalidAccountData),
+        }
+    }
+
+    /// Checks that a state account belongs to the program before its data is trusted
+    fn check_program_account(account: &AccountInfo, program_id: &Pubkey) -> ProgramResult {
+        if account.owner != program_id {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        Ok(())
+    }
+
+    /// Reads an account already known to be at the config's address
+    fn read_config(
+        config_account: &AccountInfo,
+        program_id: &Pubkey,
+    ) -> Result<Option<Config>, ProgramError> {
+        if config_account.owner != program_id {
+            return Ok(None);
+        }
+        Ok(Some(Config::unpack(&config_account.try_borrow_data()?)?))
+    }
+
+    /// The protocol fee on `amount` of `mint`, checking that `treasury_account` can receive it
+    /// when there is one to pay
+    fn protocol_fee(
+        config: Option<&Config>,
+        amount: u64,
+        treasury_account: &AccountInfo,
+        mint: &Pubkey,
+    ) -> Result<u64, ProgramError> {
+        let Some(config) = config else {
+            return Ok(0);
+        };
+        let fee = config.fee_for(amount).ok_or(EscrowError::AmountOverflow)?;
+        if fee > 0 {
+            Self::check_taker_account(treasury_account, mint, &config.treasury_pubkey)?;
+        }
+        Ok(fee)
+    }
+
+    fn emit_created(escrow_account: &Pubkey, escrow_info: &Escrow) {
+        EscrowEvent::from(EscrowCreated {
+            escrow: *escrow_account,
+            initializer: escrow_info.initializer_pubkey,
+            vault: escrow_info.temp_token_account_pubkey,
+            offered_mint: escrow_info.offered_mint_pubkey,
+            requested_mint: escrow_info.requested_mint_pubkey,
+            offered_amount: escrow_info.remaining_amount,
+            expected_amount: escrow_info.expected_amount,
+            expires_at: escrow_info.expires_at,
+            allowed_taker: escrow_info.allowed_taker,
+        })
+        .emit();
+    }
+
+    /// Checks that an auction's price has some time to move from its start to its end amount
+    fn check_auction(auction: Option<Auction>) -> ProgramResult {
+        match auction {
+            Some(auction) if auction.start_time >= auction.end_time => {
+                Err(EscrowError::InvalidInstruction.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks that an oracle priced escrow is not also an auction and that its offset leaves a
+    /// positive price
+    fn check_oracle(oracle: Option<OraclePricing>, auction: Option<Auction>) -> ProgramResult {
+        match oracle {
+            Some(oracle)
+                if auction.is_some()
+                    || i32::from(oracle.offset_basis_points)
+                        <= -i32::from(MAX_FEE_BASIS_POINTS) =>
+            {
+                Err(EscrowError::InvalidInstruction.into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Reads the price of an oracle priced escrow out of its price account, refusing prices that
+    /// are too old or too uncertain at the given unix timestamp
+    fn read_oracle_price(
+        oracle: &OraclePricing,
+        trailing_accounts: &[AccountInfo],
+        now: UnixTimestamp,
+    ) -> Result<OraclePrice, ProgramError> {
+        let price_account = trailing_accounts
+            .iter()
+            .find(|account| *account.key == oracle.price_account)
+            .ok_or(EscrowError::MissingPriceAccount)?;
+        let price = oracle.kind.read_price(price_account, &oracle.feed_id)?;
+        if oracle.is_stale(&price, now) {
+            return Err(EscrowError::StaleOraclePrice.into());
+        }
+        if !oracle.is_confident(&price) {
+            return Err(EscrowError::OraclePriceUncertain.into());
+        }
+        Ok(price)
+    }
+
+    /// Checks that a taker supplied account can hold `mint` on behalf of `taker`. For a
+    /// native SOL leg that is the taker's own wallet
+    fn check_taker_account(account: &AccountInfo, mint: &Pubkey, taker: &Pubkey) -> ProgramResult {
+        if *mint == NATIVE_MINT {
+            if account.key != taker {
+                return Err(EscrowError::TokenAccountOwnerMismatch.into());
+            }
+            return Ok(());
+        }
+        Self::check_token_account(account, mint, taker)
+    }
+
+    fn check_token_account(account: &AccountInfo, mint: &Pubkey, owner: &Pubkey) -> ProgramResult {
+        let account_info = Self::unpack_token_account(account)?;
+        if account_info.mint != *mint {
+            return Err(EscrowError::MintMismatch.into());
+        }
+        if account_info.owner != *owner {
+            return Err(EscrowError::TokenAccountOwnerMismatch.into());
+        }
+        Ok(())
+    }
+
+    /// Unpacks a token account owned by either SPL Token or Token-2022
+    fn unpack_token_account(account: &AccountInfo) -> Result<TokenAccount, ProgramError> {
+        check_spl_token_program_account(account.owner)?;
+        let data = account.try_borrow_data()?;
+        Ok(StateWithExtensions::<TokenAccount>::unpack(&data)?.base)
+    }
+
+    /// Checks that `mint` is the escrowed mint and that `token_program` is the program owning
+    /// both it and `token_account`, returning the mint's decimals for `transfer_checked`
+    fn check_mint(
+        mint: &AccountInfo,
+        expected_mint: &Pubkey,
+        token_program: &AccountInfo,
+        token_account: &AccountInfo,
+    ) -> Result<u8, ProgramError> {
+        if mint.key != expected_mint {
+            return Err(EscrowError::MintMismatch.into());
+        }
+        if mint.owner != token_program.key || token_account.owner != token_program.key {
+            return Err(ProgramError::IncorrectProgramId);
+        }
+        let data = mint.try_borrow_data()?;
+        Ok(StateWithExtensions::<Mint>::unpack(&data)?.base.decimals)
+    }
+
+    /// Returns how much has to be sent for `amount` to arrive after the mint's transfer fee
+    fn add_transfer_fee(mint: &AccountInfo, amount: u64) -> Result<u64, ProgramError> {
+        let data = mint.try_borrow_data()?;
+        let mint = StateWithExtensions::<Mint>::unpack(&data)?;
+        let fee = match mint.get_extension::<TransferFeeConfig>() {
+            Ok(transfer_fee_config) => transfer_fee_config
+                .calculate_inverse_epoch_fee(Clock::get()?.epoch, amount)
+                .ok_or(EscrowError::AmountOverflow)?,
+            Err(_) => 0,
+        };
+        amount
+            .checked_add(fee)
+            .ok_or_else(|| EscrowError::AmountOverflow.into())
+    }
+
+    /// Sends `amount` of the requested tokens from the taker to the initializer, with the taker
+    /// covering any transfer fee so the initializer is paid in full, and returns what arrived.
+    /// `authority` is the taker or a PDA signing with `authority_seeds` as their delegate
+    #[allow(clippy::too_many_arguments)]
+    fn pay_initializer<'a>(
+        token_program: &AccountInfo<'a>,
+        takers_sending_token_account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        initializers_token_to_receive_account: &AccountInfo<'a>,
+        authority: &AccountInfo<'a>,
+        authority_seeds: &[&[&[u8]]],
+        additional_accounts: &[AccountInfo<'a>],
+        amount: u64,
+        decimals: u8,
+    ) -> Result<u64, ProgramError> {
+        let amount_with_fee = Self::add_transfer_fee(mint, amount)?;
+        let balance_before =
+            Self::unpack_token_account(initializers_token_to_receive_account)?.amount;
+
+        msg!("Calling the token program to transfer tokens to the escrow's initializer...");
+        invoke_transfer_checked(
+            token_program.key,
+            takers_sending_token_account.clone(),
+            mint.clone(),
+            initializers_token_to_receive_account.clone(),
+            authority.clone(),
+            additional_accounts,
+            amount_with_fee,
+            decimals,
+            authority_seeds,
+        )?;
+
+        let received = Self::unpack_token_account(initializers_token_to_receive_account)?
+            .amount
+            .saturating_sub(balance_before);
+        if received < amount {
+            return Err(EscrowError::ExpectedAmountMismatch.into());
+        }
+        Ok(received)
+    }
+
+    /// Moves everything in one of the PDA's vaults to `destination` and closes the vault,
+    /// returning the amount sent
+    #[allow(clippy::too_many_arguments)]
+    fn empty_vault<'a>(
+        token_program: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        expected_mint: &Pubkey,
+        destination: &AccountInfo<'a>,
+        rent_destination: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        additional_accounts: &[AccountInfo<'a>],
+        pda_seeds: &[&[u8]],
+    ) -> Result<u64, ProgramError> {
+        let decimals = Self::check_mint(mint, expected_mint, token_program, vault)?;
+        let amount = Self::unpack_token_account(vault)?.amount;
+        invoke_transfer_checked(
+            token_program.key,
+            vault.clone(),
+            mint.clone(),
+            destination.clone(),
+            pda_account.clone(),
+            additional_accounts,
+            amount,
+            decimals,
+            &[pda_seeds],
+        )?;
+
+        Self::close_token_account(
+            token_program,
+            vault,
+            mint,
+            rent_destination,
+            pda_account,
+            pda_seeds,
+        )?;
+        Ok(amount)
+    }
+
+    /// Creates and initializes a vault token account for `mint` at a PDA of this program, owned
+    /// by `owner`
+    #[allow(clippy::too_many_arguments)]
+    fn create_vault<'a>(
+        payer: &AccountInfo<'a>,
+        vault: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        token_program: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        rent: &Rent,
+        owner: &Pubkey,
+        seeds: &[&[u8]],
+    ) -> ProgramResult {
+        // Token-2022 mints can require extensions on every account holding them
+        let vault_len = {
+            let data = mint.try_borrow_data()?;
+            let mint = StateWithExtensions::<Mint>::unpack(&data)?;
+            let extension_types =
+                ExtensionType::get_required_init_account_extensions(&mint.get_extension_types()?);
+            ExtensionType::try_calculate_account_len::<TokenAccount>(&extension_types)?
+        };
+        msg!("Calling the system program to create the pda's vault...");
+        Self::create_pda_account(
+            payer,
+            vault,
+            system_program,
+            rent,
+            vault_len,
+            token_program.key,
+            seeds,
+        )?;
+
+        let init_vault_ix = spl_token_2022::instruction::initialize_account3(
+            token_program.key,
+            vault.key,
+            mint.key,
+            owner,
+        )?;
+        msg!("Calling the token program to initialize the pda's vault...");
+        invoke(
+            &init_vault_ix,
+            &[vault.clone(), mint.clone(), token_program.clone()],
+        )
+    }
+
+    /// Closes the PDA's token account, first moving any transfer fees withheld in it to the
+    /// mint since Token-2022 refuses to close accounts holding them
+    fn close_token_account<'a>(
+        token_program: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        mint: &AccountInfo<'a>,
+        destination: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let withheld_amount = {
+            let data = account.try_borrow_data()?;
+            StateWithExtensions::<TokenAccount>::unpack(&data)?
+                .get_extension::<TransferFeeAmount>()
+                .map_or(0, |transfer_fee_amount| {
+                    u64::from(transfer_fee_amount.withheld_amount)
+                })
+        };
+        if withheld_amount > 0 {
+            let harvest_ix =
+                spl_token_2022::extension::transfer_fee::instruction::harvest_withheld_tokens_to_mint(
+                    token_program.key,
+                    mint.key,
+                    &[account.key],
+                )?;
+            msg!("Calling the token program to harvest the withheld transfer fees...");
+            invoke(
+                &harvest_ix,
+                &[mint.clone(), account.clone(), token_program.clone()],
+            )?;
+        }
+
+        let close_pdas_temp_acc_ix = spl_token_2022::instruction::close_account(
+            token_program.key,
+            account.key,
+            destination.key,
+            pda_account.key,
+            &[pda_account.key],
+        )?;
+        msg!("Calling the token program to close pda's temp account...");
         invoke_signed(
             &close_pdas_temp_acc_ix,
             &[
-                pdas_temp_token_account.clone(),
-                initializers_main_account.clone(),
+                account.clone(),
+                destination.clone(),
                 pda_account.clone(),
                 token_program.clone(),
             ],
-            &[&[&b"escrow"[..], &[nonce]]],
+            &[pda_seeds],
+        )
+    }
+
+    /// Makes `owner` the owner of the PDA's token account, along with whatever it still holds
+    fn hand_over_token_account<'a>(
+        token_program: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        owner: &AccountInfo<'a>,
+        pda_account: &AccountInfo<'a>,
+        pda_seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let set_owner_ix = spl_token_2022::instruction::set_authority(
+            token_program.key,
+            account.key,
+            Some(owner.key),
+            AuthorityType::AccountOwner,
+            pda_account.key,
+            &[pda_account.key],
+        )?;
+        msg!("Calling the token program to hand the pda's vault over to the initializer...");
+        invoke_signed(
+            &set_owner_ix,
+            &[account.clone(), pda_account.clone(), token_program.clone()],
+            &[pda_seeds],
+        )
+    }
+
+    /// Creates `account` at a PDA of this program. Anyone can send lamports to the address
+    /// beforehand, which makes `create_account` fail, so such an account is topped up,
+    /// allocated and assigned instead
+    #[allow(clippy::too_many_arguments)]
+    fn create_pda_account<'a>(
+        payer: &AccountInfo<'a>,
+        account: &AccountInfo<'a>,
+        system_program: &AccountInfo<'a>,
+        rent: &Rent,
+        space: usize,
+        owner: &Pubkey,
+        seeds: &[&[u8]],
+    ) -> ProgramResult {
+        let lamports = rent.minimum_balance(space);
+        if account.lamports() == 0 {
+            return invoke_signed(
+                &system_instruction::create_account(
+                    payer.key,
+                    account.key,
+                    lamports,
+                    space as u64,
+                    owner,
+                ),
+                &[payer.clone(), account.clone(), system_program.clone()],
+                &[seeds],
+            );
+        }
+
+        let top_up = lamports.saturating_sub(account.lamports());
+        if top_up > 0 {
+            invoke(
+                &system_instruction::transfer(payer.key, account.key, top_up),
+                &[payer.clone(), account.clone(), system_program.clone()],
+            )?;
+        }
+        invoke_signed(
+            &system_instruction::allocate(account.key, space as u64),
+            &[account.clone(), system_program.clone()],
+            &[seeds],
         )?;
+        invoke_signed(
+            &system_instruction::assign(account.key, owner),
+            &[account.clone(), system_program.clone()],
+            &[seeds],
+        )
+    }
 
-        msg!("Closing the escrow account...");
-        **initializers_main_account.try_borrow_mut_lamports()? = initializers_main_account
+    /// Moves lamports out of an account owned by this program
+    fn transfer_lamports(
+        source: &AccountInfo,
+        destination: &AccountInfo,
+        lamports: u64,
+    ) -> ProgramResult {
+        **source.try_borrow_mut_lamports()? = source
             .lamports()
-            .checked_add(escrow_account.lamports())
+            .checked_sub(lamports)
+            .ok_or(ProgramError::InsufficientFunds)?;
+        **destination.try_borrow_mut_lamports()? = destination
+            .lamports()
+            .checked_add(lamports)
             .ok_or(EscrowError::AmountOverflow)?;
-        **escrow_account.try_borrow_mut_lamports()? = 0;
-        *escrow_account.try_borrow_mut_data()? = &mut [];
+        Ok(())
+    }
+
+    /// Moves all of a program owned account's lamports to `destination` and wipes its data
+    fn close_program_account(account: &AccountInfo, destination: &AccountInfo) -> ProgramResult {
+        Self::transfer_lamports(account, destination, account.lamports())?;
+        *account.try_borrow_mut_data()? = &mut [];
 
         Ok(())
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,294 @@
+//! Account state of the program. The first byte of every account says what type it is:
+//!
+//! | Tag    | Account           |
+//! |--------|-------------------|
+//! | 0 or 1 | `Escrow`          |
+//! | 2      | `Bundle`          |
+//! | 3      | `ArbiterEscrow`   |
+//! | 4      | `MilestoneEscrow` |
+//! | 5      | `Stream`          |
+//! | 6      | `Config`          |
+//! | 7      | `IntentNonce`     |
+//!
+//! A fixed size account that isn't initialized yet holds 0, so its type is only known once it is.
+//! Unpacking checks the tag, so no account can be taken for one of another type.
+
 use solana_program::{
+    bpf_loader_upgradeable,
+    clock::UnixTimestamp,
     program_error::ProgramError,
     program_pack::{IsInitialized, Pack, Sealed},
     pubkey::Pubkey,
+    system_program,
 };
 
 use arrayref::{array_mut_ref, array_ref, array_refs, mut_array_refs};
+use num_traits::FromPrimitive;
+
+use crate::oracle::{OracleKind, OraclePrice};
+
+/// Seed prefix of the PDA that owns an escrow's vault token account, followed by
+/// the escrow account key and the initializer key
+pub const ESCROW_PDA_SEED: &[u8] = b"escrow";
+
+/// Seed prefix of the address the program creates an escrow's vault token account at,
+/// followed by the escrow account key
+pub const VAULT_PDA_SEED: &[u8] = b"vault";
+
+/// Seed of the PDA holding the program's `Config`
+pub const CONFIG_PDA_SEED: &[u8] = b"config";
+
+/// Seed prefix of the PDA holding a taker's `IntentNonce`, followed by the taker key
+pub const INTENT_NONCE_PDA_SEED: &[u8] = b"intent_nonce";
+
+/// Stand-in mint recorded in an `Escrow` for a leg paid in native lamports rather than an
+/// SPL token. No token mint can live at the system program's address
+pub const NATIVE_MINT: Pubkey = system_program::ID;
+
+/// Finds the PDA that owns the vault token account of the given escrow, or itself holds the
+/// offered lamports when SOL is offered
+pub fn find_escrow_pda(
+    program_id: &Pubkey,
+    escrow_account: &Pubkey,
+    initializer: &Pubkey,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            ESCROW_PDA_SEED,
+            escrow_account.as_ref(),
+            initializer.as_ref(),
+        ],
+        program_id,
+    )
+}
+
+/// Finds the address of the program's `Config` account
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_PDA_SEED], program_id)
+}
+
+/// Finds the address of the ProgramData account holding the given program's code and upgrade
+/// authority
+pub fn find_program_data_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id())
+}
+
+/// Finds the address of the given taker's `IntentNonce` account, which is also the delegate
+/// spending the taker's tokens on their signed intents
+pub fn find_intent_nonce_address(program_id: &Pubkey, taker: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[INTENT_NONCE_PDA_SEED, taker.as_ref()], program_id)
+}
+
+/// Finds the address of the vault token account holding the offered tokens of the given escrow
+pub fn find_vault_address(program_id: &Pubkey, escrow_account: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[VAULT_PDA_SEED, escrow_account.as_ref()], program_id)
+}
+
+/// Finds the address of the vault token account holding the offered tokens of leg `index` of
+/// the given bundle
+pub fn find_bundle_vault_address(
+    program_id: &Pubkey,
+    bundle_account: &Pubkey,
+    index: u8,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[VAULT_PDA_SEED, bundle_account.as_ref(), &[index]],
+        program_id,
+    )
+}
+
+/// Most offered tokens a bundle can hold, each one costs an account creation and a couple of
+/// token program calls on every instruction so this keeps them within the compute budget
+pub const MAX_BUNDLE_OFFERED_LEGS: usize = 4;
+
+/// Most requested tokens a bundle can ask for
+pub const MAX_BUNDLE_REQUESTED_LEGS: usize = 4;
+
+/// Most milestones a milestone escrow can be split into
+pub const MAX_MILESTONES: usize = 16;
+
+/// The whole of an amount, in basis points
+pub const MAX_FEE_BASIS_POINTS: u16 = 10_000;
+
+/// Highest protocol fee the config can be set to, 10%
+pub const MAX_PROTOCOL_FEE_BASIS_POINTS: u16 = 1_000;
+
+/// Dutch auction schedule of an escrow. The price for everything remaining moves in a straight
+/// line from the escrow's `expected_amount` at `start_time` to `end_amount` at `end_time`, and
+/// stays put outside that window
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Auction {
+    pub end_amount: u64,
+    pub start_time: UnixTimestamp,
+    pub end_time: UnixTimestamp,
+}
+
+/// Prices an escrow off an oracle. Takers pay the oracle's price for the offered tokens moved
+/// by `offset_basis_points`, rounded up, or the escrow's `expected_amount` if that is more,
+/// which makes it a floor under the oracle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OraclePricing {
+    pub kind: OracleKind,
+    pub price_account: Pubkey,
+    /// The oracle's id of the price feed, which the price account has to hold
+    pub feed_id: [u8; 32],
+    /// Added to the oracle's price, e.g. -100 for 1% under it
+    pub offset_basis_points: i16,
+    /// Most seconds a price can have been published before the exchange
+    pub max_age: u64,
+    /// Widest confidence interval accepted, relative to the price
+    pub max_confidence_basis_points: u16,
+}
+
+impl OraclePricing {
+    /// Whether `price` was published too long before the given unix timestamp to trade on, or
+    /// claims to be published after it and can't be trusted
+    pub fn is_stale(&self, price: &OraclePrice, now: UnixTimestamp) -> bool {
+        let age = now as i128 - price.publish_time as i128;
+        age < 0 || age > self.max_age as i128
+    }
+
+    /// Whether `price` is positive and known precisely enough to trade on
+    pub fn is_confident(&self, price: &OraclePrice) -> bool {
+        price.price > 0
+            && price.confidence as u128 * MAX_FEE_BASIS_POINTS as u128
+                <= price.price as u128 * self.max_confidence_basis_points as u128
+    }
+
+    /// What `amount` of the offered token costs at `price` after the offset, rounded up. `None`
+    /// if the price is not positive or the payment doesn't fit
+    pub fn payment_for(&self, amount: u64, price: &OraclePrice) -> Option<u64> {
+        let factor =
+            u128::try_from(MAX_FEE_BASIS_POINTS as i32 + self.offset_basis_points as i32).ok()?;
+        let mut numerator = (amount as u128)
+            .checked_mul(u128::try_from(price.price).ok()?)?
+            .checked_mul(factor)?;
+        let mut denominator = MAX_FEE_BASIS_POINTS as u128;
+        let scale = 10u128.checked_pow(price.exponent.unsigned_abs())?;
+        if price.exponent >= 0 {
+            numerator = numerator.checked_mul(scale)?;
+        } else {
+            denominator = denominator.checked_mul(scale)?;
+        }
+        let payment = numerator.checked_add(denominator - 1)? / denominator;
+        u64::try_from(payment).ok()
+    }
+}
 
 pub struct Escrow {
     pub is_initialized: bool,
     pub initializer_pubkey: Pubkey,
     pub temp_token_account_pubkey: Pubkey,
     pub initializer_token_to_receive_account_pubkey: Pubkey,
+    pub offered_mint_pubkey: Pubkey,
+    pub requested_mint_pubkey: Pubkey,
     pub expected_amount: u64,
+    pub remaining_amount: u64,
+    pub expires_at: Option<UnixTimestamp>,
+    pub allowed_taker: Option<Pubkey>,
+    pub bump_seed: u8,
+    /// Makes `expected_amount` the starting price of a Dutch auction, if any
+    pub auction: Option<Auction>,
+    /// Makes `expected_amount` a floor under an oracle's price, if any
+    pub oracle: Option<OraclePricing>,
+}
+
+impl Escrow {
+    /// What everything remaining costs at the given unix timestamp
+    pub fn expected_amount_at(&self, now: UnixTimestamp) -> u64 {
+        let Some(auction) = self.auction else {
+            return self.expected_amount;
+        };
+        if now <= auction.start_time {
+            return self.expected_amount;
+        }
+        if now >= auction.end_time {
+            return auction.end_amount;
+        }
+        // Both factors are below 2^64 so the product fits, and the offset is rounded towards the
+        // start price, which favours the initializer on the way down
+        let elapsed = (now as i128 - auction.start_time as i128) as u128;
+        let duration = (auction.end_time as i128 - auction.start_time as i128) as u128;
+        let offset =
+            (self.expected_amount.abs_diff(auction.end_amount) as u128 * elapsed / duration) as u64;
+        if auction.end_amount < self.expected_amount {
+            self.expected_amount - offset
+        } else {
+            self.expected_amount + offset
+        }
+    }
+
+    /// The amount of the requested token a taker pays at the given unix timestamp to receive
+    /// `amount` of the remaining offered tokens, rounded up so partial fills never pay out more
+    /// than their proportional share. `None` if `amount` is zero or exceeds what is left. For an
+    /// oracle priced escrow this is only the floor.
+    pub fn payment_for(&self, amount: u64, now: UnixTimestamp) -> Option<u64> {
+        if amount == 0 || amount > self.remaining_amount {
+            return None;
+        }
+        let payment = Self::pro_rata(self.expected_amount_at(now), amount, self.remaining_amount)?;
+        u64::try_from(payment).ok()
+    }
+
+    /// Takes a fill of `amount` paid with `payment` off the escrow. An auction's prices and an
+    /// oracle's floor are scaled down to what is left so they keep the same per token price
+    pub fn record_fill(&mut self, amount: u64, payment: u64) -> Option<()> {
+        let remaining_amount = self.remaining_amount.checked_sub(amount)?;
+        match self.auction.as_mut() {
+            Some(auction) if remaining_amount > 0 => {
+                let scale = |price| {
+                    Self::pro_rata(price, remaining_amount, self.remaining_amount)
+                        .and_then(|price| u64::try_from(price).ok())
+                };
+                self.expected_amount = scale(self.expected_amount)?;
+                auction.end_amount = scale(auction.end_amount)?;
+            }
+            Some(_) => self.expected_amount = 0,
+            // Paying above the floor doesn't lower it for the rest
+            None if self.oracle.is_some() => {
+                self.expected_amount = u64::try_from(Self::pro_rata(
+                    self.expected_amount,
+                    remaining_amount,
+                    self.remaining_amount,
+                )?)
+                .ok()?;
+            }
+            None => self.expected_amount = self.expected_amount.checked_sub(payment)?,
+        }
+        self.remaining_amount = remaining_amount;
+        Some(())
+    }
+
+    /// `price * numerator / denominator`, rounded up
+    fn pro_rata(price: u64, numerator: u64, denominator: u64) -> Option<u128> {
+        (price as u128)
+            .checked_mul(numerator as u128)?
+            .checked_add((denominator as u128).checked_sub(1)?)?
+            .checked_div(denominator as u128)
+    }
+
+    /// Whether the initializer offers lamports held in the PDA itself instead of a token account
+    pub fn offers_sol(&self) -> bool {
+        self.offered_mint_pubkey == NATIVE_MINT
+    }
+
+    /// Whether takers pay the initializer in lamports instead of tokens
+    pub fn requests_sol(&self) -> bool {
+        self.requested_mint_pubkey == NATIVE_MINT
+    }
+
+    /// Whether the trade can no longer be taken at the given unix timestamp
+    pub fn is_expired(&self, now: UnixTimestamp) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// Whether the given taker may accept the trade
+    pub fn is_taker_allowed(&self, taker: &Pubkey) -> bool {
+        match self.allowed_taker {
+            Some(allowed_taker) => allowed_taker == *taker,
+            None => true,
+        }
+    }
 }
 
 impl Sealed for Escrow {}
@@ -23,16 +300,28 @@ impl IsInitialized for Escrow {
 }
 
 impl Pack for Escrow {
-    const LEN: usize = 105;
+    const LEN: usize = 323;
     fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        // `array_ref!` panics on short input, and this is also reachable outside `Pack::unpack`
+        if src.len() < Escrow::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
         let src = array_ref![src, 0, Escrow::LEN];
         let (
             is_initialized,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_token_to_receive_account_pubkey,
+            offered_mint_pubkey,
+            requested_mint_pubkey,
             expected_amount,
-        ) = array_refs![src, 1, 32, 32, 32, 8];
+            remaining_amount,
+            expires_at,
+            allowed_taker,
+            bump_seed,
+            auction,
+            oracle,
+        ) = array_refs![src, 1, 32, 32, 32, 32, 32, 8, 8, 9, 33, 1, 25, 78];
         let is_initialized = match is_initialized {
             [0] => false,
             [1] => true,
@@ -46,7 +335,15 @@ impl Pack for Escrow {
             initializer_token_to_receive_account_pubkey: Pubkey::new_from_array(
                 *initializer_token_to_receive_account_pubkey,
             ),
+            offered_mint_pubkey: Pubkey::new_from_array(*offered_mint_pubkey),
+            requested_mint_pubkey: Pubkey::new_from_array(*requested_mint_pubkey),
             expected_amount: u64::from_le_bytes(*expected_amount),
+            remaining_amount: u64::from_le_bytes(*remaining_amount),
+            expires_at: unpack_timestamp_option(expires_at)?,
+            allowed_taker: unpack_pubkey_option(allowed_taker)?,
+            bump_seed: bump_seed[0],
+            auction: unpack_auction_option(auction)?,
+            oracle: unpack_oracle_option(oracle)?,
         })
     }
 
@@ -57,15 +354,31 @@ impl Pack for Escrow {
             initializer_pubkey_dst,
             temp_token_account_pubkey_dst,
             initializer_token_to_receive_account_pubkey_dst,
+            offered_mint_pubkey_dst,
+            requested_mint_pubkey_dst,
             expected_amount_dst,
-        ) = mut_array_refs![dst, 1, 32, 32, 32, 8];
+            remaining_amount_dst,
+            expires_at_dst,
+            allowed_taker_dst,
+            bump_seed_dst,
+            auction_dst,
+            oracle_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 32, 32, 8, 8, 9, 33, 1, 25, 78];
 
         let Escrow {
             is_initialized,
             initializer_pubkey,
             temp_token_account_pubkey,
             initializer_token_to_receive_account_pubkey,
+            offered_mint_pubkey,
+            requested_mint_pubkey,
             expected_amount,
+            remaining_amount,
+            expires_at,
+            allowed_taker,
+            bump_seed,
+            auction,
+            oracle,
         } = self;
 
         is_initialized_dst[0] = *is_initialized as u8;
@@ -73,6 +386,1404 @@ impl Pack for Escrow {
         temp_token_account_pubkey_dst.copy_from_slice(temp_token_account_pubkey.as_ref());
         initializer_token_to_receive_account_pubkey_dst
             .copy_from_slice(initializer_token_to_receive_account_pubkey.as_ref());
+        offered_mint_pubkey_dst.copy_from_slice(offered_mint_pubkey.as_ref());
+        requested_mint_pubkey_dst.copy_from_slice(requested_mint_pubkey.as_ref());
         *expected_amount_dst = expected_amount.to_le_bytes();
+        *remaining_amount_dst = remaining_amount.to_le_bytes();
+        pack_timestamp_option(expires_at, expires_at_dst);
+        pack_pubkey_option(allowed_taker, allowed_taker_dst);
+        bump_seed_dst[0] = *bump_seed;
+        pack_auction_option(auction, auction_dst);
+        pack_oracle_option(oracle, oracle_dst);
+    }
+}
+
+/// One token a bundle offers, held in its own vault token account
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BundleOffer {
+    pub vault_pubkey: Pubkey,
+    pub mint_pubkey: Pubkey,
+    /// What arrived in the vault, net of any transfer fee
+    pub amount: u64,
+}
+
+/// One token a bundle asks for, paid straight into the initializer's token account
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BundleRequest {
+    pub mint_pubkey: Pubkey,
+    pub initializer_token_to_receive_account_pubkey: Pubkey,
+    pub amount: u64,
+}
+
+/// An escrow trading several offered tokens for several requested tokens in one go. Unlike an
+/// `Escrow` it can only be filled in full, and its size depends on the number of legs.
+///
+/// Tagged 2.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Bundle {
+    pub initializer_pubkey: Pubkey,
+    pub expires_at: Option<UnixTimestamp>,
+    pub allowed_taker: Option<Pubkey>,
+    pub bump_seed: u8,
+    pub offered: Vec<BundleOffer>,
+    pub requested: Vec<BundleRequest>,
+}
+
+impl Bundle {
+    const TAG: u8 = 2;
+    const HEADER_LEN: usize = 78;
+    const LEG_LEN: usize = 72;
+
+    /// The size of a bundle account with the given number of legs
+    pub fn get_packed_len(offered_legs: usize, requested_legs: usize) -> usize {
+        Self::HEADER_LEN + (offered_legs + requested_legs) * Self::LEG_LEN
+    }
+
+    /// Whether the trade can no longer be taken at the given unix timestamp
+    pub fn is_expired(&self, now: UnixTimestamp) -> bool {
+        matches!(self.expires_at, Some(expires_at) if now >= expires_at)
+    }
+
+    /// Whether the given taker may accept the trade
+    pub fn is_taker_allowed(&self, taker: &Pubkey) -> bool {
+        match self.allowed_taker {
+            Some(allowed_taker) => allowed_taker == *taker,
+            None => true,
+        }
+    }
+
+    /// Unpacks an initialized bundle, checking the account size matches its legs
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let header = src
+            .get(..Self::HEADER_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let header = array_ref![header, 0, Bundle::HEADER_LEN];
+        let (
+            tag,
+            initializer_pubkey,
+            expires_at,
+            allowed_taker,
+            bump_seed,
+            offered_legs,
+            requested_legs,
+        ) = array_refs![header, 1, 32, 9, 33, 1, 1, 1];
+        match tag[0] {
+            Self::TAG => (),
+            0 => return Err(ProgramError::UninitializedAccount),
+            _ => return Err(ProgramError::InvalidAccountData),
+        }
+        let (offered_legs, requested_legs) = (offered_legs[0] as usize, requested_legs[0] as usize);
+        if src.len() != Self::get_packed_len(offered_legs, requested_legs) {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let mut legs = src[Self::HEADER_LEN..]
+            .chunks_exact(Self::LEG_LEN)
+            .map(|leg| array_refs![array_ref![leg, 0, Bundle::LEG_LEN], 32, 32, 8]);
+        let offered = legs
+            .by_ref()
+            .take(offered_legs)
+            .map(|(vault_pubkey, mint_pubkey, amount)| BundleOffer {
+                vault_pubkey: Pubkey::new_from_array(*vault_pubkey),
+                mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
+                amount: u64::from_le_bytes(*amount),
+            })
+            .collect();
+        let requested = legs
+            .map(
+                |(mint_pubkey, initializer_token_to_receive_account_pubkey, amount)| {
+                    BundleRequest {
+                        mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
+                        initializer_token_to_receive_account_pubkey: Pubkey::new_from_array(
+                            *initializer_token_to_receive_account_pubkey,
+                        ),
+                        amount: u64::from_le_bytes(*amount),
+                    }
+                },
+            )
+            .collect();
+
+        Ok(Bundle {
+            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
+            expires_at: unpack_timestamp_option(expires_at)?,
+            allowed_taker: unpack_pubkey_option(allowed_taker)?,
+            bump_seed: bump_seed[0],
+            offered,
+            requested,
+        })
+    }
+
+    /// Packs the bundle into an account sized for exactly its legs
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if self.offered.len() > MAX_BUNDLE_OFFERED_LEGS
+            || self.requested.len() > MAX_BUNDLE_REQUESTED_LEGS
+            || dst.len() != Self::get_packed_len(self.offered.len(), self.requested.len())
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (header, legs) = dst.split_at_mut(Self::HEADER_LEN);
+        let header = array_mut_ref![header, 0, Bundle::HEADER_LEN];
+        let (
+            tag_dst,
+            initializer_pubkey_dst,
+            expires_at_dst,
+            allowed_taker_dst,
+            bump_seed_dst,
+            offered_legs_dst,
+            requested_legs_dst,
+        ) = mut_array_refs![header, 1, 32, 9, 33, 1, 1, 1];
+        tag_dst[0] = Self::TAG;
+        initializer_pubkey_dst.copy_from_slice(self.initializer_pubkey.as_ref());
+        pack_timestamp_option(&self.expires_at, expires_at_dst);
+        pack_pubkey_option(&self.allowed_taker, allowed_taker_dst);
+        bump_seed_dst[0] = self.bump_seed;
+        offered_legs_dst[0] = self.offered.len() as u8;
+        requested_legs_dst[0] = self.requested.len() as u8;
+
+        let legs = self
+            .offered
+            .iter()
+            .map(|offer| (&offer.vault_pubkey, &offer.mint_pubkey, offer.amount))
+            .chain(self.requested.iter().map(|request| {
+                (
+                    &request.mint_pubkey,
+                    &request.initializer_token_to_receive_account_pubkey,
+                    request.amount,
+                )
+            }))
+            .zip(legs.chunks_exact_mut(Self::LEG_LEN));
+        for ((first, second, amount), dst) in legs {
+            let (first_dst, second_dst, amount_dst) =
+                mut_array_refs![array_mut_ref![dst, 0, Bundle::LEG_LEN], 32, 32, 8];
+            first_dst.copy_from_slice(first.as_ref());
+            second_dst.copy_from_slice(second.as_ref());
+            *amount_dst = amount.to_le_bytes();
+        }
+        Ok(())
+    }
+}
+
+/// An escrow paying a recipient for a service, settled by two of its three parties agreeing on
+/// where the tokens go. Once either the initializer or the recipient raises a dispute the arbiter
+/// can settle it alone.
+///
+/// Tagged 3 once initialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ArbiterEscrow {
+    pub is_initialized: bool,
+    pub initializer_pubkey: Pubkey,
+    pub recipient_pubkey: Pubkey,
+    pub arbiter_pubkey: Pubkey,
+    pub vault_pubkey: Pubkey,
+    pub mint_pubkey: Pubkey,
+    /// What arrived in the vault, net of any transfer fee
+    pub amount: u64,
+    pub is_disputed: bool,
+    pub bump_seed: u8,
+}
+
+impl ArbiterEscrow {
+    const TAG: u8 = 3;
+
+    /// Whether the parties that signed may release or refund the escrow
+    pub fn is_settlement_approved(
+        &self,
+        initializer_signed: bool,
+        recipient_signed: bool,
+        arbiter_signed: bool,
+    ) -> bool {
+        let signatures = [initializer_signed, recipient_signed, arbiter_signed]
+            .into_iter()
+            .filter(|&signed| signed)
+            .count();
+        signatures >= 2 || (self.is_disputed && arbiter_signed)
+    }
+}
+
+impl Sealed for ArbiterEscrow {}
+
+impl IsInitialized for ArbiterEscrow {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for ArbiterEscrow {
+    const LEN: usize = 171;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < ArbiterEscrow::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, ArbiterEscrow::LEN];
+        let (
+            tag,
+            initializer_pubkey,
+            recipient_pubkey,
+            arbiter_pubkey,
+            vault_pubkey,
+            mint_pubkey,
+            amount,
+            is_disputed,
+            bump_seed,
+        ) = array_refs![src, 1, 32, 32, 32, 32, 32, 8, 1, 1];
+        let is_initialized = match tag[0] {
+            0 => false,
+            Self::TAG => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let is_disputed = match is_disputed {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(ArbiterEscrow {
+            is_initialized,
+            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
+            recipient_pubkey: Pubkey::new_from_array(*recipient_pubkey),
+            arbiter_pubkey: Pubkey::new_from_array(*arbiter_pubkey),
+            vault_pubkey: Pubkey::new_from_array(*vault_pubkey),
+            mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
+            amount: u64::from_le_bytes(*amount),
+            is_disputed,
+            bump_seed: bump_seed[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, ArbiterEscrow::LEN];
+        let (
+            tag_dst,
+            initializer_pubkey_dst,
+            recipient_pubkey_dst,
+            arbiter_pubkey_dst,
+            vault_pubkey_dst,
+            mint_pubkey_dst,
+            amount_dst,
+            is_disputed_dst,
+            bump_seed_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 32, 32, 8, 1, 1];
+
+        tag_dst[0] = if self.is_initialized { Self::TAG } else { 0 };
+        initializer_pubkey_dst.copy_from_slice(self.initializer_pubkey.as_ref());
+        recipient_pubkey_dst.copy_from_slice(self.recipient_pubkey.as_ref());
+        arbiter_pubkey_dst.copy_from_slice(self.arbiter_pubkey.as_ref());
+        vault_pubkey_dst.copy_from_slice(self.vault_pubkey.as_ref());
+        mint_pubkey_dst.copy_from_slice(self.mint_pubkey.as_ref());
+        *amount_dst = self.amount.to_le_bytes();
+        is_disputed_dst[0] = self.is_disputed as u8;
+        bump_seed_dst[0] = self.bump_seed;
+    }
+}
+
+/// Tokens held for a beneficiary that vest linearly from `start_time` to `end_time`. Nothing can
+/// be claimed before the cliff, if any, after which everything vested so far can. The funder can
+/// cancel at any time, taking back only what has not vested.
+///
+/// Tagged 5 once initialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stream {
+    pub is_initialized: bool,
+    pub funder_pubkey: Pubkey,
+    pub beneficiary_pubkey: Pubkey,
+    pub vault_pubkey: Pubkey,
+    pub mint_pubkey: Pubkey,
+    /// What arrived in the vault, net of any transfer fee
+    pub total_amount: u64,
+    pub claimed_amount: u64,
+    pub start_time: UnixTimestamp,
+    pub end_time: UnixTimestamp,
+    pub cliff_time: Option<UnixTimestamp>,
+    pub bump_seed: u8,
+}
+
+impl Stream {
+    const TAG: u8 = 5;
+
+    /// How much of the total has vested at the given unix timestamp, rounded down
+    pub fn vested_amount_at(&self, now: UnixTimestamp) -> u64 {
+        if matches!(self.cliff_time, Some(cliff_time) if now < cliff_time) || now <= self.start_time
+        {
+            return 0;
+        }
+        if now >= self.end_time {
+            return self.total_amount;
+        }
+        // Both factors are below 2^64 so the product fits, and the quotient is below the total
+        let elapsed = (now as i128 - self.start_time as i128) as u128;
+        let duration = (self.end_time as i128 - self.start_time as i128) as u128;
+        (self.total_amount as u128 * elapsed / duration) as u64
+    }
+
+    /// What the beneficiary can claim at the given unix timestamp
+    pub fn claimable_amount_at(&self, now: UnixTimestamp) -> u64 {
+        self.vested_amount_at(now)
+            .saturating_sub(self.claimed_amount)
+    }
+}
+
+impl Sealed for Stream {}
+
+impl IsInitialized for Stream {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Stream {
+    const LEN: usize = 171;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Stream::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, Stream::LEN];
+        let (
+            tag,
+            funder_pubkey,
+            beneficiary_pubkey,
+            vault_pubkey,
+            mint_pubkey,
+            total_amount,
+            claimed_amount,
+            start_time,
+            end_time,
+            cliff_time,
+            bump_seed,
+        ) = array_refs![src, 1, 32, 32, 32, 32, 8, 8, 8, 8, 9, 1];
+        let is_initialized = match tag[0] {
+            0 => false,
+            Self::TAG => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(Stream {
+            is_initialized,
+            funder_pubkey: Pubkey::new_from_array(*funder_pubkey),
+            beneficiary_pubkey: Pubkey::new_from_array(*beneficiary_pubkey),
+            vault_pubkey: Pubkey::new_from_array(*vault_pubkey),
+            mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
+            total_amount: u64::from_le_bytes(*total_amount),
+            claimed_amount: u64::from_le_bytes(*claimed_amount),
+            start_time: UnixTimestamp::from_le_bytes(*start_time),
+            end_time: UnixTimestamp::from_le_bytes(*end_time),
+            cliff_time: unpack_timestamp_option(cliff_time)?,
+            bump_seed: bump_seed[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Stream::LEN];
+        let (
+            tag_dst,
+            funder_pubkey_dst,
+            beneficiary_pubkey_dst,
+            vault_pubkey_dst,
+            mint_pubkey_dst,
+            total_amount_dst,
+            claimed_amount_dst,
+            start_time_dst,
+            end_time_dst,
+            cliff_time_dst,
+            bump_seed_dst,
+        ) = mut_array_refs![dst, 1, 32, 32, 32, 32, 8, 8, 8, 8, 9, 1];
+
+        tag_dst[0] = if self.is_initialized { Self::TAG } else { 0 };
+        funder_pubkey_dst.copy_from_slice(self.funder_pubkey.as_ref());
+        beneficiary_pubkey_dst.copy_from_slice(self.beneficiary_pubkey.as_ref());
+        vault_pubkey_dst.copy_from_slice(self.vault_pubkey.as_ref());
+        mint_pubkey_dst.copy_from_slice(self.mint_pubkey.as_ref());
+        *total_amount_dst = self.total_amount.to_le_bytes();
+        *claimed_amount_dst = self.claimed_amount.to_le_bytes();
+        *start_time_dst = self.start_time.to_le_bytes();
+        *end_time_dst = self.end_time.to_le_bytes();
+        pack_timestamp_option(&self.cliff_time, cliff_time_dst);
+        bump_seed_dst[0] = self.bump_seed;
+    }
+}
+
+/// Program wide settings, held in a single account at `find_config_address`. Until it is
+/// initialized exchanges are free.
+///
+/// Tagged 6 once initialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    pub is_initialized: bool,
+    /// Who may update the config
+    pub admin_pubkey: Pubkey,
+    /// Share of both legs of every exchange taken as a protocol fee
+    pub fee_basis_points: u16,
+    /// Owner of the token accounts fees are paid into, and the account receiving lamports for
+    /// native SOL legs
+    pub treasury_pubkey: Pubkey,
+    pub bump_seed: u8,
+    /// Stops new trades and payouts while set, leaving depositors every way out of an existing one
+    pub is_paused: bool,
+}
+
+impl Config {
+    const TAG: u8 = 6;
+
+    /// The protocol fee on `amount`, rounded down. `None` if the fee is above
+    /// `MAX_PROTOCOL_FEE_BASIS_POINTS`
+    pub fn fee_for(&self, amount: u64) -> Option<u64> {
+        if self.fee_basis_points > MAX_PROTOCOL_FEE_BASIS_POINTS {
+            return None;
+        }
+        let fee = (amount as u128)
+            .checked_mul(self.fee_basis_points as u128)?
+            .checked_div(MAX_FEE_BASIS_POINTS as u128)?;
+        u64::try_from(fee).ok()
+    }
+}
+
+impl Sealed for Config {}
+
+impl IsInitialized for Config {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for Config {
+    const LEN: usize = 69;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < Config::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, Config::LEN];
+        let (tag, admin_pubkey, fee_basis_points, treasury_pubkey, bump_seed, is_paused) =
+            array_refs![src, 1, 32, 2, 32, 1, 1];
+        let is_initialized = match tag[0] {
+            0 => false,
+            Self::TAG => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+        let is_paused = match is_paused {
+            [0] => false,
+            [1] => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(Config {
+            is_initialized,
+            admin_pubkey: Pubkey::new_from_array(*admin_pubkey),
+            fee_basis_points: u16::from_le_bytes(*fee_basis_points),
+            treasury_pubkey: Pubkey::new_from_array(*treasury_pubkey),
+            bump_seed: bump_seed[0],
+            is_paused,
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, Config::LEN];
+        let (
+            tag_dst,
+            admin_pubkey_dst,
+            fee_basis_points_dst,
+            treasury_pubkey_dst,
+            bump_seed_dst,
+            is_paused_dst,
+        ) = mut_array_refs![dst, 1, 32, 2, 32, 1, 1];
+
+        tag_dst[0] = if self.is_initialized { Self::TAG } else { 0 };
+        admin_pubkey_dst.copy_from_slice(self.admin_pubkey.as_ref());
+        *fee_basis_points_dst = self.fee_basis_points.to_le_bytes();
+        treasury_pubkey_dst.copy_from_slice(self.treasury_pubkey.as_ref());
+        bump_seed_dst[0] = self.bump_seed;
+        is_paused_dst[0] = self.is_paused as u8;
+    }
+}
+
+/// Replay protection for a taker's signed intents, held in a PDA at `find_intent_nonce_address`.
+/// An intent is only accepted with a nonce of at least `next_nonce`, so using one also voids
+/// every unused intent with a lower nonce.
+///
+/// Tagged 7 once initialized.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IntentNonce {
+    pub is_initialized: bool,
+    pub taker_pubkey: Pubkey,
+    /// The lowest nonce the next intent may use
+    pub next_nonce: u64,
+    pub bump_seed: u8,
+}
+
+impl IntentNonce {
+    const TAG: u8 = 7;
+}
+
+impl Sealed for IntentNonce {}
+
+impl IsInitialized for IntentNonce {
+    fn is_initialized(&self) -> bool {
+        self.is_initialized
+    }
+}
+
+impl Pack for IntentNonce {
+    const LEN: usize = 42;
+    fn unpack_from_slice(src: &[u8]) -> Result<Self, ProgramError> {
+        if src.len() < IntentNonce::LEN {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let src = array_ref![src, 0, IntentNonce::LEN];
+        let (tag, taker_pubkey, next_nonce, bump_seed) = array_refs![src, 1, 32, 8, 1];
+        let is_initialized = match tag[0] {
+            0 => false,
+            Self::TAG => true,
+            _ => return Err(ProgramError::InvalidAccountData),
+        };
+
+        Ok(IntentNonce {
+            is_initialized,
+            taker_pubkey: Pubkey::new_from_array(*taker_pubkey),
+            next_nonce: u64::from_le_bytes(*next_nonce),
+            bump_seed: bump_seed[0],
+        })
+    }
+
+    fn pack_into_slice(&self, dst: &mut [u8]) {
+        let dst = array_mut_ref![dst, 0, IntentNonce::LEN];
+        let (tag_dst, taker_pubkey_dst, next_nonce_dst, bump_seed_dst) =
+            mut_array_refs![dst, 1, 32, 8, 1];
+
+        tag_dst[0] = if self.is_initialized { Self::TAG } else { 0 };
+        taker_pubkey_dst.copy_from_slice(self.taker_pubkey.as_ref());
+        *next_nonce_dst = self.next_nonce.to_le_bytes();
+        bump_seed_dst[0] = self.bump_seed;
+    }
+}
+
+/// A payment held for a recipient and paid out in fixed milestones, each released by the
+/// initializer approving it. Whatever is still unreleased once the deadline passes can be refunded
+/// to the initializer. Like a `Bundle`, its size depends on the number of milestones.
+///
+/// Tagged 4.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MilestoneEscrow {
+    pub initializer_pubkey: Pubkey,
+    pub recipient_pubkey: Pubkey,
+    pub vault_pubkey: Pubkey,
+    pub mint_pubkey: Pubkey,
+    /// Unix timestamp from which the unreleased milestones can be refunded
+    pub deadline: UnixTimestamp,
+    pub bump_seed: u8,
+    /// How many milestones have been released, always in order
+    pub released_milestones: u8,
+    pub milestones: Vec<u64>,
+}
+
+impl MilestoneEscrow {
+    const TAG: u8 = 4;
+    const HEADER_LEN: usize = 140;
+    const MILESTONE_LEN: usize = 8;
+
+    /// The size of a milestone escrow account with the given number of milestones
+    pub fn get_packed_len(milestones: usize) -> usize {
+        Self::HEADER_LEN + milestones * Self::MILESTONE_LEN
+    }
+
+    /// The amount of the next milestone to release, if any is left
+    pub fn next_milestone(&self) -> Option<u64> {
+        self.milestones
+            .get(self.released_milestones as usize)
+            .copied()
+    }
+
+    /// Whether the next milestone is the last one
+    pub fn is_last_milestone(&self) -> bool {
+        self.released_milestones as usize + 1 == self.milestones.len()
+    }
+
+    /// Whether the unreleased milestones can be refunded at the given unix timestamp
+    pub fn is_past_deadline(&self, now: UnixTimestamp) -> bool {
+        now >= self.deadline
+    }
+
+    /// Unpacks an initialized milestone escrow, checking the account size matches its milestones
+    pub fn unpack(src: &[u8]) -> Result<Self, ProgramError> {
+        let header = src
+            .get(..Self::HEADER_LEN)
+            .ok_or(ProgramError::InvalidAccountData)?;
+        let header = array_ref![header, 0, MilestoneEscrow::HEADER_LEN];
+        let (
+            tag,
+            initializer_pubkey,
+            recipient_pubkey,
+            vault_pubkey,
+            mint_pubkey,
+            deadline,
+            bump_seed,
+            released_milestones,
+            milestone_count,
+        ) = array_refs![header, 1, 32, 32, 32, 32, 8, 1, 1, 1];
+        match tag[0] {
+            Self::TAG => (),
+            0 => return Err(ProgramError::UninitializedAccount),
+            _ => return Err(ProgramError::InvalidAccountData),
+        }
+        let milestone_count = milestone_count[0] as usize;
+        if src.len() != Self::get_packed_len(milestone_count)
+            || released_milestones[0] as usize > milestone_count
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let milestones = src[Self::HEADER_LEN..]
+            .chunks_exact(Self::MILESTONE_LEN)
+            .map(|amount| u64::from_le_bytes(*array_ref![amount, 0, 8]))
+            .collect();
+
+        Ok(MilestoneEscrow {
+            initializer_pubkey: Pubkey::new_from_array(*initializer_pubkey),
+            recipient_pubkey: Pubkey::new_from_array(*recipient_pubkey),
+            vault_pubkey: Pubkey::new_from_array(*vault_pubkey),
+            mint_pubkey: Pubkey::new_from_array(*mint_pubkey),
+            deadline: UnixTimestamp::from_le_bytes(*deadline),
+            bump_seed: bump_seed[0],
+            released_milestones: released_milestones[0],
+            milestones,
+        })
+    }
+
+    /// Packs the milestone escrow into an account sized for exactly its milestones
+    pub fn pack(&self, dst: &mut [u8]) -> Result<(), ProgramError> {
+        if self.milestones.len() > MAX_MILESTONES
+            || self.released_milestones as usize > self.milestones.len()
+            || dst.len() != Self::get_packed_len(self.milestones.len())
+        {
+            return Err(ProgramError::InvalidAccountData);
+        }
+        let (header, milestones) = dst.split_at_mut(Self::HEADER_LEN);
+        let header = array_mut_ref![header, 0, MilestoneEscrow::HEADER_LEN];
+        let (
+            tag_dst,
+            initializer_pubkey_dst,
+            recipient_pubkey_dst,
+            vault_pubkey_dst,
+            mint_pubkey_dst,
+            deadline_dst,
+            bump_seed_dst,
+            released_milestones_dst,
+            milestone_count_dst,
+        ) = mut_array_refs![header, 1, 32, 32, 32, 32, 8, 1, 1, 1];
+        tag_dst[0] = Self::TAG;
+        initializer_pubkey_dst.copy_from_slice(self.initializer_pubkey.as_ref());
+        recipient_pubkey_dst.copy_from_slice(self.recipient_pubkey.as_ref());
+        vault_pubkey_dst.copy_from_slice(self.vault_pubkey.as_ref());
+        mint_pubkey_dst.copy_from_slice(self.mint_pubkey.as_ref());
+        *deadline_dst = self.deadline.to_le_bytes();
+        bump_seed_dst[0] = self.bump_seed;
+        released_milestones_dst[0] = self.released_milestones;
+        milestone_count_dst[0] = self.milestones.len() as u8;
+
+        for (amount, dst) in self
+            .milestones
+            .iter()
+            .zip(milestones.chunks_exact_mut(Self::MILESTONE_LEN))
+        {
+            dst.copy_from_slice(&amount.to_le_bytes());
+        }
+        Ok(())
+    }
+}
+
+fn unpack_timestamp_option(src: &[u8; 9]) -> Result<Option<UnixTimestamp>, ProgramError> {
+    let (tag, body) = array_refs![src, 1, 8];
+    match *tag {
+        [0] => Ok(None),
+        [1] => Ok(Some(UnixTimestamp::from_le_bytes(*body))),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+fn pack_timestamp_option(src: &Option<UnixTimestamp>, dst: &mut [u8; 9]) {
+    let (tag, body) = mut_array_refs![dst, 1, 8];
+    match src {
+        Some(timestamp) => {
+            *tag = [1];
+            *body = timestamp.to_le_bytes();
+        }
+        None => {
+            *tag = [0];
+            *body = [0; 8];
+        }
+    }
+}
+
+fn unpack_auction_option(src: &[u8; 25]) -> Result<Option<Auction>, ProgramError> {
+    let (tag, end_amount, start_time, end_time) = array_refs![src, 1, 8, 8, 8];
+    match *tag {
+        [0] => Ok(None),
+        [1] => Ok(Some(Auction {
+            end_amount: u64::from_le_bytes(*end_amount),
+            start_time: UnixTimestamp::from_le_bytes(*start_time),
+            end_time: UnixTimestamp::from_le_bytes(*end_time),
+        })),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+fn pack_auction_option(src: &Option<Auction>, dst: &mut [u8; 25]) {
+    let (tag, end_amount, start_time, end_time) = mut_array_refs![dst, 1, 8, 8, 8];
+    match src {
+        Some(auction) => {
+            *tag = [1];
+            *end_amount = auction.end_amount.to_le_bytes();
+            *start_time = auction.start_time.to_le_bytes();
+            *end_time = auction.end_time.to_le_bytes();
+        }
+        None => {
+            *tag = [0];
+            *end_amount = [0; 8];
+            *start_time = [0; 8];
+            *end_time = [0; 8];
+        }
+    }
+}
+
+fn unpack_oracle_option(src: &[u8; 78]) -> Result<Option<OraclePricing>, ProgramError> {
+    #[rustfmt::skip]
+    let (
+        tag,
+        kind,
+        price_account,
+        feed_id,
+        offset_basis_points,
+        max_age,
+        max_confidence_basis_points,
+    ) = array_refs![src, 1, 1, 32, 32, 2, 8, 2];
+    match *tag {
+        [0] => Ok(None),
+        [1] => Ok(Some(OraclePricing {
+            kind: OracleKind::from_u8(kind[0]).ok_or(ProgramError::InvalidAccountData)?,
+            price_account: Pubkey::new_from_array(*price_account),
+            feed_id: *feed_id,
+            offset_basis_points: i16::from_le_bytes(*offset_basis_points),
+            max_age: u64::from_le_bytes(*max_age),
+            max_confidence_basis_points: u16::from_le_bytes(*max_confidence_basis_points),
+        })),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+fn pack_oracle_option(src: &Option<OraclePricing>, dst: &mut [u8; 78]) {
+    #[rustfmt::skip]
+    let (
+        tag,
+        kind,
+        price_account,
+        feed_id,
+        offset_basis_points,
+        max_age,
+        max_confidence_basis_points,
+    ) = mut_array_refs![dst, 1, 1, 32, 32, 2, 8, 2];
+    match src {
+        Some(oracle) => {
+            *tag = [1];
+            *kind = [oracle.kind as u8];
+            price_account.copy_from_slice(oracle.price_account.as_ref());
+            *feed_id = oracle.feed_id;
+            *offset_basis_points = oracle.offset_basis_points.to_le_bytes();
+            *max_age = oracle.max_age.to_le_bytes();
+            *max_confidence_basis_points = oracle.max_confidence_basis_points.to_le_bytes();
+        }
+        None => {
+            *tag = [0];
+            *kind = [0];
+            *price_account = [0; 32];
+            *feed_id = [0; 32];
+            *offset_basis_points = [0; 2];
+            *max_age = [0; 8];
+            *max_confidence_basis_points = [0; 2];
+        }
+    }
+}
+
+fn unpack_pubkey_option(src: &[u8; 33]) -> Result<Option<Pubkey>, ProgramError> {
+    let (tag, body) = array_refs![src, 1, 32];
+    match *tag {
+        [0] => Ok(None),
+        [1] => Ok(Some(Pubkey::new_from_array(*body))),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+fn pack_pubkey_option(src: &Option<Pubkey>, dst: &mut [u8; 33]) {
+    let (tag, body) = mut_array_refs![dst, 1, 32];
+    match src {
+        Some(key) => {
+            *tag = [1];
+            body.copy_from_slice(key.as_ref());
+        }
+        None => {
+            *tag = [0];
+            *body = [0; 32];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn escrow(expected_amount: u64, remaining_amount: u64) -> Escrow {
+        Escrow {
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            temp_token_account_pubkey: Pubkey::new_unique(),
+            initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+            offered_mint_pubkey: Pubkey::new_unique(),
+            requested_mint_pubkey: Pubkey::new_unique(),
+            expected_amount,
+            remaining_amount,
+            expires_at: Some(1_700_000_000),
+            allowed_taker: Some(Pubkey::new_unique()),
+            bump_seed: 254,
+            auction: None,
+            oracle: None,
+        }
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let mut check = escrow(10, 3);
+        check.auction = Some(Auction {
+            end_amount: 2,
+            start_time: -5,
+            end_time: 1_700_000_000,
+        });
+        check.oracle = Some(oracle_pricing(-100));
+        let (check_auction, check_oracle) = (check.auction, check.oracle);
+        let mut packed = vec![0; Escrow::LEN];
+        Escrow::pack(check, &mut packed).unwrap();
+        let unpacked = Escrow::unpack(&packed).unwrap();
+        assert_eq!(unpacked.auction, check_auction);
+        assert_eq!(unpacked.oracle, check_oracle);
+        let mut repacked = vec![0; Escrow::LEN];
+        Escrow::pack(unpacked, &mut repacked).unwrap();
+        assert_eq!(packed, repacked);
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let mut escrow = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        assert!(!escrow.is_expired(i64::MAX));
+        escrow.expires_at = Some(1_000);
+        assert!(!escrow.is_expired(999));
+        // The expiry itself is already too late
+        assert!(escrow.is_expired(1_000));
+        assert!(escrow.is_expired(1_001));
+    }
+
+    #[test]
+    fn test_is_taker_allowed() {
+        let mut escrow = Escrow::unpack_unchecked(&[0; Escrow::LEN]).unwrap();
+        let taker = Pubkey::new_unique();
+        assert!(escrow.is_taker_allowed(&taker));
+        escrow.allowed_taker = Some(taker);
+        assert!(escrow.is_taker_allowed(&taker));
+        assert!(!escrow.is_taker_allowed(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn test_unpack_invalid() {
+        assert!(matches!(
+            Escrow::unpack_from_slice(&[1; Escrow::LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        ));
+        assert!(matches!(
+            Escrow::unpack(&[1; Escrow::LEN + 1]),
+            Err(ProgramError::InvalidAccountData)
+        ));
+        let mut bad_flag = vec![0; Escrow::LEN];
+        bad_flag[0] = 2;
+        assert!(matches!(
+            Escrow::unpack_unchecked(&bad_flag),
+            Err(ProgramError::InvalidAccountData)
+        ));
+        let mut escrow = escrow(10, 3);
+        escrow.oracle = Some(oracle_pricing(0));
+        let mut bad_kind = vec![0; Escrow::LEN];
+        Escrow::pack(escrow, &mut bad_kind).unwrap();
+        bad_kind[Escrow::LEN - 77] = 2;
+        assert!(matches!(
+            Escrow::unpack(&bad_kind),
+            Err(ProgramError::InvalidAccountData)
+        ));
+    }
+
+    fn bundle() -> Bundle {
+        Bundle {
+            initializer_pubkey: Pubkey::new_unique(),
+            expires_at: None,
+            allowed_taker: Some(Pubkey::new_unique()),
+            bump_seed: 253,
+            offered: (0..MAX_BUNDLE_OFFERED_LEGS as u64)
+                .map(|amount| BundleOffer {
+                    vault_pubkey: Pubkey::new_unique(),
+                    mint_pubkey: Pubkey::new_unique(),
+                    amount,
+                })
+                .collect(),
+            requested: vec![BundleRequest {
+                mint_pubkey: Pubkey::new_unique(),
+                initializer_token_to_receive_account_pubkey: Pubkey::new_unique(),
+                amount: u64::MAX,
+            }],
+        }
+    }
+
+    #[test]
+    fn test_bundle_pack_unpack() {
+        let check = bundle();
+        let mut packed = vec![0; Bundle::get_packed_len(MAX_BUNDLE_OFFERED_LEGS, 1)];
+        assert_eq!(
+            Bundle::unpack(&packed),
+            Err(ProgramError::UninitializedAccount)
+        );
+        check.pack(&mut packed).unwrap();
+        assert_eq!(Bundle::unpack(&packed).unwrap(), check);
+        // Never readable as a single escrow, whatever its size
+        assert!(Escrow::unpack_unchecked(&packed).is_err());
+
+        assert_eq!(
+            check.pack(&mut packed[1..]),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Bundle::unpack(&packed[..packed.len() - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Bundle::unpack(&packed[..Bundle::HEADER_LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        let mut too_many = bundle();
+        too_many.requested = vec![too_many.requested[0].clone(); MAX_BUNDLE_REQUESTED_LEGS + 1];
+        let mut packed =
+            vec![0; Bundle::get_packed_len(MAX_BUNDLE_OFFERED_LEGS, MAX_BUNDLE_REQUESTED_LEGS + 1)];
+        assert_eq!(
+            too_many.pack(&mut packed),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    fn arbiter_escrow(is_disputed: bool) -> ArbiterEscrow {
+        ArbiterEscrow {
+            is_initialized: true,
+            initializer_pubkey: Pubkey::new_unique(),
+            recipient_pubkey: Pubkey::new_unique(),
+            arbiter_pubkey: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+            mint_pubkey: Pubkey::new_unique(),
+            amount: 42,
+            is_disputed,
+            bump_seed: 253,
+        }
+    }
+
+    #[test]
+    fn test_arbiter_escrow_pack_unpack() {
+        let check = arbiter_escrow(true);
+        let mut packed = vec![0; ArbiterEscrow::LEN];
+        assert!(!ArbiterEscrow::unpack_unchecked(&packed)
+            .unwrap()
+            .is_initialized());
+        ArbiterEscrow::pack(check, &mut packed).unwrap();
+        assert_eq!(packed[0], ArbiterEscrow::TAG);
+        assert_eq!(ArbiterEscrow::unpack(&packed).unwrap(), check);
+        assert!(Escrow::unpack_unchecked(&packed).is_err());
+
+        let mut bad_flag = packed.clone();
+        bad_flag[ArbiterEscrow::LEN - 2] = 2;
+        assert_eq!(
+            ArbiterEscrow::unpack(&bad_flag),
+            Err(ProgramError::InvalidAccountData)
+        );
+        // An initialized escrow is never readable as an arbiter escrow
+        let mut escrow_data = vec![0; Escrow::LEN];
+        Escrow::pack(escrow(10, 3), &mut escrow_data).unwrap();
+        assert!(ArbiterEscrow::unpack_unchecked(&escrow_data[..ArbiterEscrow::LEN]).is_err());
+    }
+
+    #[test]
+    fn test_arbiter_settlement_approval() {
+        let escrow = arbiter_escrow(false);
+        assert!(escrow.is_settlement_approved(true, true, false));
+        assert!(escrow.is_settlement_approved(true, false, true));
+        assert!(escrow.is_settlement_approved(false, true, true));
+        assert!(!escrow.is_settlement_approved(true, false, false));
+        assert!(!escrow.is_settlement_approved(false, false, true));
+
+        let disputed = arbiter_escrow(true);
+        assert!(disputed.is_settlement_approved(false, false, true));
+        assert!(!disputed.is_settlement_approved(true, false, false));
+        assert!(!disputed.is_settlement_approved(false, true, false));
+    }
+
+    fn stream(cliff_time: Option<UnixTimestamp>) -> Stream {
+        Stream {
+            is_initialized: true,
+            funder_pubkey: Pubkey::new_unique(),
+            beneficiary_pubkey: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+            mint_pubkey: Pubkey::new_unique(),
+            total_amount: 1_000,
+            claimed_amount: 0,
+            start_time: 1_000,
+            end_time: 2_000,
+            cliff_time,
+            bump_seed: 251,
+        }
+    }
+
+    #[test]
+    fn test_stream_pack_unpack() {
+        let check = stream(Some(1_250));
+        let mut packed = vec![0; Stream::LEN];
+        assert!(!Stream::unpack_unchecked(&packed).unwrap().is_initialized());
+        Stream::pack(check, &mut packed).unwrap();
+        assert_eq!(packed[0], Stream::TAG);
+        assert_eq!(Stream::unpack(&packed).unwrap(), check);
+        assert!(ArbiterEscrow::unpack_unchecked(&packed[..ArbiterEscrow::LEN]).is_err());
+
+        let mut bad_flag = packed.clone();
+        bad_flag[Stream::LEN - 10] = 2;
+        assert_eq!(
+            Stream::unpack(&bad_flag),
+            Err(ProgramError::InvalidAccountData)
+        );
+        assert_eq!(
+            Stream::unpack(&packed[..Stream::LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_stream_vesting() {
+        let mut stream = stream(None);
+        assert_eq!(stream.vested_amount_at(0), 0);
+        assert_eq!(stream.vested_amount_at(1_000), 0);
+        assert_eq!(stream.vested_amount_at(1_001), 1);
+        assert_eq!(stream.vested_amount_at(1_500), 500);
+        assert_eq!(stream.vested_amount_at(2_000), 1_000);
+        assert_eq!(stream.vested_amount_at(i64::MAX), 1_000);
+
+        stream.claimed_amount = 300;
+        assert_eq!(stream.claimable_amount_at(1_500), 200);
+        assert_eq!(stream.claimable_amount_at(1_200), 0);
+
+        // Nothing vests before the cliff, then everything up to it does at once
+        stream.cliff_time = Some(1_250);
+        stream.claimed_amount = 0;
+        assert_eq!(stream.vested_amount_at(1_249), 0);
+        assert_eq!(stream.vested_amount_at(1_250), 250);
+
+        // Rounds down and can't overflow with extreme amounts and times
+        stream.total_amount = u64::MAX;
+        stream.start_time = i64::MIN;
+        stream.end_time = i64::MAX;
+        stream.cliff_time = None;
+        assert_eq!(stream.vested_amount_at(0), 1 << 63);
+    }
+
+    #[test]
+    fn test_config_pack_unpack() {
+        let check = Config {
+            is_initialized: true,
+            admin_pubkey: Pubkey::new_unique(),
+            fee_basis_points: 30,
+            treasury_pubkey: Pubkey::new_unique(),
+            bump_seed: 250,
+            is_paused: true,
+        };
+        let mut packed = vec![0; Config::LEN];
+        assert!(!Config::unpack_unchecked(&packed).unwrap().is_initialized());
+        Config::pack(check, &mut packed).unwrap();
+        assert_eq!(packed[0], Config::TAG);
+        assert_eq!(Config::unpack(&packed).unwrap(), check);
+        assert_eq!(
+            Config::unpack(&packed[..Config::LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        let mut bad_flag = packed.clone();
+        bad_flag[Config::LEN - 1] = 2;
+        assert_eq!(
+            Config::unpack(&bad_flag),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_intent_nonce_pack_unpack() {
+        let check = IntentNonce {
+            is_initialized: true,
+            taker_pubkey: Pubkey::new_unique(),
+            next_nonce: 42,
+            bump_seed: 251,
+        };
+        let mut packed = vec![0; IntentNonce::LEN];
+        assert!(!IntentNonce::unpack_unchecked(&packed)
+            .unwrap()
+            .is_initialized());
+        IntentNonce::pack(check, &mut packed).unwrap();
+        assert_eq!(packed[0], IntentNonce::TAG);
+        assert_eq!(IntentNonce::unpack(&packed).unwrap(), check);
+        assert_eq!(
+            IntentNonce::unpack(&packed[..IntentNonce::LEN - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        // A config is no nonce account
+        let mut config = vec![0; Config::LEN];
+        config[0] = Config::TAG;
+        assert_eq!(
+            IntentNonce::unpack(&config),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_fee_for() {
+        let mut config = Config {
+            is_initialized: true,
+            admin_pubkey: Pubkey::new_unique(),
+            fee_basis_points: 30,
+            treasury_pubkey: Pubkey::new_unique(),
+            bump_seed: 250,
+            is_paused: false,
+        };
+        assert_eq!(config.fee_for(10_000), Some(30));
+        // Rounds down, so small fills pay nothing
+        assert_eq!(config.fee_for(333), Some(0));
+        assert_eq!(config.fee_for(u64::MAX), Some(55_340_232_221_128_654));
+
+        config.fee_basis_points = MAX_PROTOCOL_FEE_BASIS_POINTS;
+        assert_eq!(config.fee_for(u64::MAX), Some(u64::MAX / 10));
+        config.fee_basis_points = MAX_PROTOCOL_FEE_BASIS_POINTS + 1;
+        assert_eq!(config.fee_for(1), None);
+    }
+
+    fn milestone_escrow() -> MilestoneEscrow {
+        MilestoneEscrow {
+            initializer_pubkey: Pubkey::new_unique(),
+            recipient_pubkey: Pubkey::new_unique(),
+            vault_pubkey: Pubkey::new_unique(),
+            mint_pubkey: Pubkey::new_unique(),
+            deadline: 1_700_000_000,
+            bump_seed: 252,
+            released_milestones: 1,
+            milestones: vec![10, 20, 30],
+        }
+    }
+
+    #[test]
+    fn test_milestone_escrow_pack_unpack() {
+        let check = milestone_escrow();
+        let mut packed = vec![0; MilestoneEscrow::get_packed_len(3)];
+        assert_eq!(
+            MilestoneEscrow::unpack(&packed),
+            Err(ProgramError::UninitializedAccount)
+        );
+        check.pack(&mut packed).unwrap();
+        assert_eq!(MilestoneEscrow::unpack(&packed).unwrap(), check);
+        assert!(Bundle::unpack(&packed).is_err());
+
+        assert_eq!(
+            MilestoneEscrow::unpack(&packed[..packed.len() - 1]),
+            Err(ProgramError::InvalidAccountData)
+        );
+        let mut overreleased = packed.clone();
+        overreleased[MilestoneEscrow::HEADER_LEN - 2] = 4;
+        assert_eq!(
+            MilestoneEscrow::unpack(&overreleased),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        let mut too_many = milestone_escrow();
+        too_many.milestones = vec![1; MAX_MILESTONES + 1];
+        let mut packed = vec![0; MilestoneEscrow::get_packed_len(MAX_MILESTONES + 1)];
+        assert_eq!(
+            too_many.pack(&mut packed),
+            Err(ProgramError::InvalidAccountData)
+        );
+    }
+
+    #[test]
+    fn test_milestone_progress() {
+        let mut escrow = milestone_escrow();
+        assert_eq!(escrow.next_milestone(), Some(20));
+        assert!(!escrow.is_last_milestone());
+        escrow.released_milestones = 2;
+        assert_eq!(escrow.next_milestone(), Some(30));
+        assert!(escrow.is_last_milestone());
+        escrow.released_milestones = 3;
+        assert_eq!(escrow.next_milestone(), None);
+
+        assert!(!escrow.is_past_deadline(1_699_999_999));
+        assert!(escrow.is_past_deadline(1_700_000_000));
+    }
+
+    #[test]
+    fn test_payment_for() {
+        let escrow = escrow(10, 3);
+        assert_eq!(escrow.payment_for(0, 0), None);
+        assert_eq!(escrow.payment_for(4, 0), None);
+        // 10 / 3 per token, always rounded in the initializer's favour
+        assert_eq!(escrow.payment_for(1, 0), Some(4));
+        assert_eq!(escrow.payment_for(2, 0), Some(7));
+        assert_eq!(escrow.payment_for(3, 0), Some(10));
+
+        let escrow = self::escrow(u64::MAX, u64::MAX);
+        assert_eq!(escrow.payment_for(u64::MAX, 0), Some(u64::MAX));
+        assert_eq!(escrow.payment_for(1, 0), Some(1));
+    }
+
+    #[test]
+    fn test_payment_for_never_underpays() {
+        let mut escrow = escrow(1_000, 7);
+        let mut paid = 0;
+        for amount in [1, 2, 1, 3] {
+            let payment = escrow.payment_for(amount, 0).unwrap();
+            assert!(
+                payment as u128 * escrow.remaining_amount as u128
+                    >= amount as u128 * escrow.expected_amount as u128
+            );
+            escrow.record_fill(amount, payment).unwrap();
+            paid += payment;
+        }
+        assert_eq!(escrow.remaining_amount, 0);
+        assert_eq!(paid, 1_000);
+    }
+
+    #[test]
+    fn test_auction_price() {
+        let mut escrow = escrow(1_000, 10);
+        escrow.auction = Some(Auction {
+            end_amount: 100,
+            start_time: 1_000,
+            end_time: 1_600,
+        });
+        assert_eq!(escrow.expected_amount_at(i64::MIN), 1_000);
+        assert_eq!(escrow.expected_amount_at(1_000), 1_000);
+        assert_eq!(escrow.expected_amount_at(1_300), 550);
+        // 1.5 tokens cheaper is rounded towards the start price
+        assert_eq!(escrow.expected_amount_at(1_001), 999);
+        assert_eq!(escrow.expected_amount_at(1_600), 100);
+        assert_eq!(escrow.expected_amount_at(i64::MAX), 100);
+        assert_eq!(escrow.payment_for(5, 1_300), Some(275));
+        assert_eq!(escrow.payment_for(5, 1_001), Some(500));
+
+        // A rising schedule works the same way
+        escrow.expected_amount = 0;
+        escrow.auction = Some(Auction {
+            end_amount: u64::MAX,
+            start_time: i64::MIN,
+            end_time: i64::MAX,
+        });
+        assert_eq!(escrow.expected_amount_at(0), u64::MAX / 2 + 1);
+        assert_eq!(escrow.expected_amount_at(i64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_auction_record_fill() {
+        let mut escrow = escrow(1_000, 10);
+        escrow.auction = Some(Auction {
+            end_amount: 101,
+            start_time: 1_000,
+            end_time: 1_900,
+        });
+        let payment = escrow.payment_for(4, 1_450).unwrap();
+        escrow.record_fill(4, payment).unwrap();
+        assert_eq!(escrow.remaining_amount, 6);
+        assert_eq!(escrow.expected_amount, 600);
+        assert_eq!(escrow.auction.unwrap().end_amount, 61);
+        // Still about the same per token price halfway through
+        assert_eq!(escrow.expected_amount_at(1_450), 331);
+
+        escrow.record_fill(6, 331).unwrap();
+        assert_eq!(escrow.remaining_amount, 0);
+        assert_eq!(escrow.record_fill(1, 0), None);
+    }
+
+    fn oracle_pricing(offset_basis_points: i16) -> OraclePricing {
+        OraclePricing {
+            kind: OracleKind::Mock,
+            price_account: Pubkey::new_unique(),
+            feed_id: [9; 32],
+            offset_basis_points,
+            max_age: 60,
+            max_confidence_basis_points: 100,
+        }
+    }
+
+    fn oracle_price(price: i64, exponent: i32) -> OraclePrice {
+        OraclePrice {
+            price,
+            confidence: 0,
+            exponent,
+            publish_time: 1_000,
+        }
+    }
+
+    #[test]
+    fn test_oracle_payment_for() {
+        let oracle = oracle_pricing(-100);
+        // 2.5 requested per offered, 1% under it
+        let price = oracle_price(25, -1);
+        assert_eq!(oracle.payment_for(100, &price), Some(248));
+        // 24.75 is rounded up
+        assert_eq!(oracle.payment_for(10, &price), Some(25));
+        assert_eq!(oracle.payment_for(0, &price), Some(0));
+        assert_eq!(
+            oracle_pricing(50).payment_for(4, &oracle_price(3, 2)),
+            Some(1_206)
+        );
+        assert_eq!(oracle.payment_for(10, &oracle_price(0, 0)), Some(0));
+        assert_eq!(oracle.payment_for(10, &oracle_price(-25, -1)), None);
+        assert_eq!(oracle.payment_for(u64::MAX, &oracle_price(2, 0)), None);
+        assert_eq!(oracle.payment_for(1, &oracle_price(1, 40)), None);
+        assert_eq!(oracle.payment_for(1, &oracle_price(1, -40)), None);
+        assert_eq!(oracle_pricing(-10_000).payment_for(10, &price), Some(0));
+        assert_eq!(oracle_pricing(-10_001).payment_for(10, &price), None);
+    }
+
+    #[test]
+    fn test_oracle_price_checks() {
+        let oracle = oracle_pricing(0);
+        let mut price = oracle_price(1_000, -3);
+        assert!(!oracle.is_stale(&price, 1_060));
+        assert!(oracle.is_stale(&price, 1_061));
+        assert!(!oracle.is_stale(&price, 1_000));
+        // A price from ahead of the cluster clock would never go stale
+        assert!(oracle.is_stale(&price, 999));
+        assert!(oracle.is_stale(&price, i64::MIN));
+        price.publish_time = i64::MIN;
+        assert!(oracle.is_stale(&price, i64::MAX));
+
+        price.confidence = 10;
+        assert!(oracle.is_confident(&price));
+        price.confidence = 11;
+        assert!(!oracle.is_confident(&price));
+        price.price = 0;
+        price.confidence = 0;
+        assert!(!oracle.is_confident(&price));
+        price.price = i64::MIN;
+        assert!(!oracle.is_confident(&price));
+    }
+
+    #[test]
+    fn test_oracle_record_fill() {
+        let mut escrow = escrow(100, 10);
+        escrow.oracle = Some(oracle_pricing(0));
+        // Paid above the floor, which keeps its per token price
+        escrow.record_fill(4, 70).unwrap();
+        assert_eq!(escrow.remaining_amount, 6);
+        assert_eq!(escrow.expected_amount, 60);
+        escrow.record_fill(6, 60).unwrap();
+        assert_eq!(escrow.expected_amount, 0);
     }
 }
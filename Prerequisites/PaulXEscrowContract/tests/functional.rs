@@ -0,0 +1,3092 @@
+use paulx_escrow_contract::{
+    error::EscrowError,
+    event::Settlement,
+    instruction::{self, BundleLeg},
+    oracle::{pyth_receiver, OracleKind, PythPriceUpdate},
+    processor::Processor,
+    state::{
+        find_bundle_vault_address, find_config_address, find_escrow_pda, find_intent_nonce_address,
+        find_program_data_address, find_vault_address, ArbiterEscrow, Auction, Bundle, Config,
+        Escrow, IntentNonce, MilestoneEscrow, OraclePricing, Stream, MAX_FEE_BASIS_POINTS,
+        MAX_PROTOCOL_FEE_BASIS_POINTS, NATIVE_MINT,
+    },
+};
+use solana_program::{
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    clock::Clock,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_instruction, system_program,
+};
+use solana_program_test::{processor, BanksClientError, ProgramTest, ProgramTestContext};
+use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentLevel,
+    instruction::{AccountMeta, Instruction, InstructionError},
+    signature::{Keypair, Signer},
+    transaction::{Transaction, TransactionError},
+};
+use spl_token_2022::{
+    error::TokenError,
+    extension::{
+        transfer_fee::instruction::initialize_transfer_fee_config, ExtensionType,
+        StateWithExtensions,
+    },
+    state::{Account as TokenAccount, Mint},
+};
+use std::time::{Duration, SystemTime};
+
+const OFFERED_AMOUNT: u64 = 10;
+const EXPECTED_AMOUNT: u64 = 30;
+/// How long a transaction may take before the banks client gives up on it. The 10 second
+/// default is too short once the suite slows down: solana-program-test 1.18 never drops a bank,
+/// its program cache holding on to the `BankForks` that owns it, so the accounts index threads
+/// of every test that already ran keep competing for the CPU
+const TRANSACTION_DEADLINE: Duration = Duration::from_secs(120);
+
+/// Alice offers `OFFERED_AMOUNT` of token X and asks `EXPECTED_AMOUNT` of token Y, Bob takes the trade
+struct Env {
+    context: ProgramTestContext,
+    program_id: Pubkey,
+    token_program: Pubkey,
+    /// Token-2022 transfer fee configured on both mints, if any
+    transfer_fee_basis_points: Option<u16>,
+    alice: Keypair,
+    bob: Keypair,
+    mint_x: Keypair,
+    mint_y: Keypair,
+    alice_x: Keypair,
+    alice_y: Keypair,
+    bob_x: Keypair,
+    bob_y: Keypair,
+    escrow: Keypair,
+}
+
+impl Env {
+    async fn new() -> Self {
+        Self::with_token_program(spl_token::id(), None).await
+    }
+
+    async fn with_token_program(
+        token_program: Pubkey,
+        transfer_fee_basis_points: Option<u16>,
+    ) -> Self {
+        let program_id = Pubkey::new_unique();
+        let program_test = ProgramTest::new(
+            "paulx_escrow_contract",
+            program_id,
+            processor!(Processor::process),
+        );
+        let mut env = Env {
+            context: program_test.start_with_context().await,
+            program_id,
+            token_program,
+            transfer_fee_basis_points,
+            alice: Keypair::new(),
+            bob: Keypair::new(),
+            mint_x: Keypair::new(),
+            mint_y: Keypair::new(),
+            alice_x: Keypair::new(),
+            alice_y: Keypair::new(),
+            bob_x: Keypair::new(),
+            bob_y: Keypair::new(),
+            escrow: Keypair::new(),
+        };
+
+        let payer = env.context.payer.pubkey();
+        let fund = [env.alice.pubkey(), env.bob.pubkey()]
+            .iter()
+            .map(|wallet| system_instruction::transfer(&payer, wallet, 1_000_000_000))
+            .collect::<Vec<_>>();
+        env.process(&fund, &[]).await.unwrap();
+
+        env.create_mint(&env.mint_x.insecure_clone()).await;
+        env.create_mint(&env.mint_y.insecure_clone()).await;
+
+        let (alice, bob) = (env.alice.pubkey(), env.bob.pubkey());
+        let (mint_x, mint_y) = (env.mint_x.pubkey(), env.mint_y.pubkey());
+        env.create_token_account(
+            &env.alice_x.insecure_clone(),
+            &mint_x,
+            &alice,
+            OFFERED_AMOUNT,
+        )
+        .await;
+        env.create_token_account(&env.alice_y.insecure_clone(), &mint_y, &alice, 0)
+            .await;
+        env.create_token_account(&env.bob_x.insecure_clone(), &mint_x, &bob, 0)
+            .await;
+        env.create_token_account(&env.bob_y.insecure_clone(), &mint_y, &bob, 100)
+            .await;
+
+        env
+    }
+
+    async fn process(
+        &mut self,
+        instructions: &[Instruction],
+        signers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        let blockhash = self.context.get_new_latest_blockhash().await.unwrap();
+        let mut all_signers = vec![&self.context.payer];
+        all_signers.extend_from_slice(signers);
+        let transaction = Transaction::new_signed_with_payer(
+            instructions,
+            Some(&self.context.payer.pubkey()),
+            &all_signers,
+            blockhash,
+        );
+        let mut context = tarpc::context::current();
+        context.deadline = SystemTime::now() + TRANSACTION_DEADLINE;
+        let result = self
+            .context
+            .banks_client
+            .process_transaction_with_commitment_and_context(
+                context,
+                transaction,
+                CommitmentLevel::default(),
+            )
+            .await?;
+        result
+            .ok_or(BanksClientError::ClientError(
+                "invalid blockhash or fee-payer",
+            ))?
+            .map_err(Into::into)
+    }
+
+    async fn create_mint(&mut self, mint: &Keypair) {
+        let payer = self.context.payer.pubkey();
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let extensions = match self.transfer_fee_basis_points {
+            Some(_) => vec![ExtensionType::TransferFeeConfig],
+            None => vec![],
+        };
+        let space = ExtensionType::try_calculate_account_len::<Mint>(&extensions).unwrap();
+        let mut instructions = vec![system_instruction::create_account(
+            &payer,
+            &mint.pubkey(),
+            rent.minimum_balance(space),
+            space as u64,
+            &self.token_program,
+        )];
+        if let Some(basis_points) = self.transfer_fee_basis_points {
+            instructions.push(
+                initialize_transfer_fee_config(
+                    &self.token_program,
+                    &mint.pubkey(),
+                    None,
+                    None,
+                    basis_points,
+                    u64::MAX,
+                )
+                .unwrap(),
+            );
+        }
+        instructions.push(
+            spl_token_2022::instruction::initialize_mint(
+                &self.token_program,
+                &mint.pubkey(),
+                &payer,
+                None,
+                0,
+            )
+            .unwrap(),
+        );
+        self.process(&instructions, &[mint]).await.unwrap();
+    }
+
+    async fn create_token_account(
+        &mut self,
+        account: &Keypair,
+        mint: &Pubkey,
+        owner: &Pubkey,
+        amount: u64,
+    ) {
+        let payer = self.context.payer.pubkey();
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let extensions = match self.transfer_fee_basis_points {
+            Some(_) => vec![ExtensionType::TransferFeeAmount],
+            None => vec![],
+        };
+        let space = ExtensionType::try_calculate_account_len::<TokenAccount>(&extensions).unwrap();
+        let instructions = [
+            system_instruction::create_account(
+                &payer,
+                &account.pubkey(),
+                rent.minimum_balance(space),
+                space as u64,
+                &self.token_program,
+            ),
+            spl_token_2022::instruction::initialize_account(
+                &self.token_program,
+                &account.pubkey(),
+                mint,
+                owner,
+            )
+            .unwrap(),
+            spl_token_2022::instruction::mint_to(
+                &self.token_program,
+                mint,
+                &account.pubkey(),
+                &payer,
+                &[],
+                amount,
+            )
+            .unwrap(),
+        ];
+        self.process(&instructions, &[account]).await.unwrap();
+    }
+
+    async fn create_escrow_account(&mut self, lamports: u64) {
+        let instruction = system_instruction::create_account(
+            &self.context.payer.pubkey(),
+            &self.escrow.pubkey(),
+            lamports,
+            Escrow::LEN as u64,
+            &self.program_id,
+        );
+        let escrow = self.escrow.insecure_clone();
+        self.process(&[instruction], &[&escrow]).await.unwrap();
+    }
+
+    fn init_escrow_instruction(&self) -> Instruction {
+        instruction::init_escrow(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            EXPECTED_AMOUNT,
+            None,
+            None,
+            None,
+            None,
+        )
+    }
+
+    fn vault(&self) -> Pubkey {
+        find_vault_address(&self.program_id, &self.escrow.pubkey()).0
+    }
+
+    async fn init_escrow_with(&mut self, instruction: Instruction) -> Result<(), BanksClientError> {
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        self.create_escrow_account(rent.minimum_balance(Escrow::LEN))
+            .await;
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await
+    }
+
+    async fn init_escrow(&mut self) {
+        self.init_escrow_with(self.init_escrow_instruction())
+            .await
+            .unwrap();
+    }
+
+    fn exchange_instruction(&self, amount: u64) -> Instruction {
+        instruction::exchange(
+            &self.program_id,
+            &self.token_program,
+            &self.token_program,
+            &self.bob.pubkey(),
+            &self.bob_y.pubkey(),
+            &self.bob_x.pubkey(),
+            &self.vault(),
+            &self.alice.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            &self.mint_y.pubkey(),
+            amount,
+            u64::MAX,
+            MAX_PROTOCOL_FEE_BASIS_POINTS,
+            None,
+            None,
+        )
+    }
+
+    async fn exchange_with(&mut self, instruction: Instruction) -> Result<(), BanksClientError> {
+        let bob = self.bob.insecure_clone();
+        self.process(&[instruction], &[&bob]).await
+    }
+
+    fn cancel_instruction(&self) -> Instruction {
+        instruction::cancel(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.vault(),
+            &self.alice_x.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+        )
+    }
+
+    async fn cancel_with(&mut self, instruction: Instruction) -> Result<(), BanksClientError> {
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await
+    }
+
+    async fn set_unix_timestamp(&mut self, unix_timestamp: i64) {
+        let mut clock = self
+            .context
+            .banks_client
+            .get_sysvar::<Clock>()
+            .await
+            .unwrap();
+        clock.unix_timestamp = unix_timestamp;
+        self.context.set_sysvar(&clock);
+    }
+
+    async fn get_account(&mut self, address: &Pubkey) -> Option<Account> {
+        self.context
+            .banks_client
+            .get_account(*address)
+            .await
+            .unwrap()
+    }
+
+    async fn get_escrow(&mut self) -> Escrow {
+        let account = self.get_account(&self.escrow.pubkey()).await.unwrap();
+        Escrow::unpack(&account.data).unwrap()
+    }
+
+    async fn token_balance(&mut self, address: &Pubkey) -> u64 {
+        let account = self.get_account(address).await.unwrap();
+        StateWithExtensions::<TokenAccount>::unpack(&account.data)
+            .unwrap()
+            .base
+            .amount
+    }
+}
+
+fn assert_instruction_error(result: Result<(), BanksClientError>, expected: InstructionError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(0, expected)
+    );
+}
+
+fn assert_escrow_error(result: Result<(), BanksClientError>, expected: EscrowError) {
+    assert_instruction_error(result, InstructionError::Custom(expected as u32));
+}
+
+#[tokio::test]
+async fn test_init_escrow() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    let escrow = env.get_escrow().await;
+    let (pda, bump_seed) =
+        find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    assert!(escrow.is_initialized);
+    assert_eq!(escrow.initializer_pubkey, env.alice.pubkey());
+    assert_eq!(escrow.temp_token_account_pubkey, env.vault());
+    assert_eq!(
+        escrow.initializer_token_to_receive_account_pubkey,
+        env.alice_y.pubkey()
+    );
+    assert_eq!(escrow.offered_mint_pubkey, env.mint_x.pubkey());
+    assert_eq!(escrow.requested_mint_pubkey, env.mint_y.pubkey());
+    assert_eq!(escrow.expected_amount, EXPECTED_AMOUNT);
+    assert_eq!(escrow.remaining_amount, OFFERED_AMOUNT);
+    assert_eq!(escrow.bump_seed, bump_seed);
+
+    let vault = env.get_account(&env.vault()).await.unwrap();
+    assert_eq!(TokenAccount::unpack(&vault.data).unwrap().owner, pda);
+    assert_eq!(env.token_balance(&env.vault()).await, OFFERED_AMOUNT);
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 0);
+}
+
+#[tokio::test]
+async fn test_init_escrow_wrong_vault() {
+    let mut env = Env::new().await;
+    let mut instruction = env.init_escrow_instruction();
+    instruction.accounts[2].pubkey = Pubkey::new_unique();
+
+    let result = env.init_escrow_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidSeeds);
+}
+
+#[tokio::test]
+async fn test_init_escrow_prefunded_vault() {
+    let mut env = Env::new().await;
+    let payer = env.context.payer.pubkey();
+    let rent = env.context.banks_client.get_rent().await.unwrap();
+    let instruction = system_instruction::transfer(&payer, &env.vault(), rent.minimum_balance(0));
+    env.process(&[instruction], &[]).await.unwrap();
+
+    env.init_escrow().await;
+
+    assert_eq!(env.token_balance(&env.vault()).await, OFFERED_AMOUNT);
+}
+
+#[tokio::test]
+async fn test_init_escrow_missing_signature() {
+    let mut env = Env::new().await;
+    let mut instruction = env.init_escrow_instruction();
+    instruction.accounts[0].is_signer = false;
+    let rent = env.context.banks_client.get_rent().await.unwrap();
+    env.create_escrow_account(rent.minimum_balance(Escrow::LEN))
+        .await;
+
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_init_escrow_not_rent_exempt() {
+    let mut env = Env::new().await;
+    // The runtime refuses to create rent paying accounts, so plant one directly
+    env.context.set_account(
+        &env.escrow.pubkey(),
+        &Account {
+            lamports: 1,
+            data: vec![0; Escrow::LEN],
+            owner: env.program_id,
+            ..Account::default()
+        }
+        .into(),
+    );
+    let alice = env.alice.insecure_clone();
+
+    let result = env
+        .process(&[env.init_escrow_instruction()], &[&alice])
+        .await;
+    assert_escrow_error(result, EscrowError::NotRentExempt);
+}
+
+#[tokio::test]
+async fn test_init_escrow_already_initialized() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let alice = env.alice.insecure_clone();
+
+    let result = env
+        .process(&[env.init_escrow_instruction()], &[&alice])
+        .await;
+    assert_instruction_error(result, InstructionError::AccountAlreadyInitialized);
+}
+
+#[tokio::test]
+async fn test_init_escrow_source_not_token_account() {
+    let mut env = Env::new().await;
+    let mut instruction = env.init_escrow_instruction();
+    instruction.accounts[1].pubkey = env.bob.pubkey();
+
+    let result = env.init_escrow_with(instruction).await;
+    assert_instruction_error(result, InstructionError::IncorrectProgramId);
+}
+
+#[tokio::test]
+async fn test_init_escrow_already_expired() {
+    let mut env = Env::new().await;
+    env.set_unix_timestamp(1_000).await;
+    let instruction = instruction::init_escrow(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        EXPECTED_AMOUNT,
+        Some(1_000),
+        None,
+        None,
+        None,
+    );
+
+    let result = env.init_escrow_with(instruction).await;
+    assert_escrow_error(result, EscrowError::Expired);
+}
+
+#[tokio::test]
+async fn test_exchange() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let alice_lamports = env.get_account(&env.alice.pubkey()).await.unwrap().lamports;
+
+    env.exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(
+        env.token_balance(&env.bob_y.pubkey()).await,
+        100 - EXPECTED_AMOUNT
+    );
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    assert!(env.get_account(&env.alice.pubkey()).await.unwrap().lamports > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_exchange_leaves_extra_vault_tokens() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    // Tokens sent to the vault on top of what Alice escrowed
+    let mint_extra = spl_token_2022::instruction::mint_to(
+        &env.token_program,
+        &env.mint_x.pubkey(),
+        &env.vault(),
+        &env.context.payer.pubkey(),
+        &[],
+        5,
+    )
+    .unwrap();
+    env.process(&[mint_extra], &[]).await.unwrap();
+
+    env.exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    // Alice gets the vault with the extra tokens in it
+    let vault = env.get_account(&env.vault()).await.unwrap();
+    let vault = StateWithExtensions::<TokenAccount>::unpack(&vault.data)
+        .unwrap()
+        .base;
+    assert_eq!(vault.owner, env.alice.pubkey());
+    assert_eq!(vault.amount, 5);
+}
+
+#[tokio::test]
+async fn test_exchange_partial_fills() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    env.exchange_with(env.exchange_instruction(3))
+        .await
+        .unwrap();
+    let escrow = env.get_escrow().await;
+    assert_eq!(escrow.remaining_amount, 7);
+    assert_eq!(escrow.expected_amount, 21);
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 3);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 9);
+
+    env.exchange_with(env.exchange_instruction(7))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 10);
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_missing_signature() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[0].is_signer = false;
+
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_exchange_amount_mismatch() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    let result = env
+        .exchange_with(env.exchange_instruction(OFFERED_AMOUNT + 1))
+        .await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+
+    let result = env.exchange_with(env.exchange_instruction(0)).await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_temp_account() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[3].pubkey = env.alice_x.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_initializer() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[4].pubkey = env.bob.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_initializer_receive_account() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[5].pubkey = env.bob_y.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_mint() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let bob = env.bob.pubkey();
+    let mint_x = env.mint_x.pubkey();
+    let bob_other_x = Keypair::new();
+    env.create_token_account(&bob_other_x, &mint_x, &bob, 100)
+        .await;
+
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[1].pubkey = bob_other_x.pubkey();
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::MintMismatch);
+
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[2].pubkey = env.bob_y.pubkey();
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::MintMismatch);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_token_account_owner() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[2].pubkey = env.alice_x.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::TokenAccountOwnerMismatch);
+}
+
+#[tokio::test]
+async fn test_exchange_wrong_pda() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[8].pubkey = Pubkey::new_unique();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidSeeds);
+}
+
+#[tokio::test]
+async fn test_exchange_expired() {
+    let mut env = Env::new().await;
+    env.set_unix_timestamp(1_000).await;
+    let instruction = instruction::init_escrow(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        EXPECTED_AMOUNT,
+        Some(2_000),
+        None,
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+    env.set_unix_timestamp(2_000).await;
+
+    let result = env
+        .exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await;
+    assert_escrow_error(result, EscrowError::Expired);
+}
+
+#[tokio::test]
+async fn test_exchange_taker_not_allowed() {
+    let mut env = Env::new().await;
+    let instruction = instruction::init_escrow(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        EXPECTED_AMOUNT,
+        None,
+        Some(Pubkey::new_unique()),
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+
+    let result = env
+        .exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await;
+    assert_escrow_error(result, EscrowError::TakerNotAllowed);
+}
+
+impl Env {
+    fn amend_instruction(
+        &self,
+        new_expected_amount: u64,
+        top_up_amount: u64,
+        withdraw_amount: u64,
+    ) -> Instruction {
+        instruction::amend(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.vault(),
+            &self.alice_x.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            new_expected_amount,
+            top_up_amount,
+            withdraw_amount,
+        )
+    }
+
+    fn exchange_instruction_with_max(&self, amount: u64, max_payment: u64) -> Instruction {
+        let mut instruction = self.exchange_instruction(amount);
+        instruction.data = instruction::EscrowInstruction::Exchange {
+            amount,
+            max_payment,
+            max_fee_basis_points: MAX_PROTOCOL_FEE_BASIS_POINTS,
+        }
+        .pack();
+        instruction
+    }
+}
+
+#[tokio::test]
+async fn test_amend_price() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    // Signed by Bob against the original terms but landing after the amendment
+    let stale = env.exchange_instruction_with_max(OFFERED_AMOUNT, EXPECTED_AMOUNT);
+
+    env.cancel_with(env.amend_instruction(2 * EXPECTED_AMOUNT, 0, 0))
+        .await
+        .unwrap();
+    assert_eq!(env.get_escrow().await.expected_amount, 2 * EXPECTED_AMOUNT);
+
+    let result = env.exchange_with(stale).await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+
+    env.exchange_with(env.exchange_instruction_with_max(OFFERED_AMOUNT, 2 * EXPECTED_AMOUNT))
+        .await
+        .unwrap();
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        2 * EXPECTED_AMOUNT
+    );
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+}
+
+#[tokio::test]
+async fn test_amend_withdraw_and_top_up() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    env.cancel_with(env.amend_instruction(EXPECTED_AMOUNT, 0, 4))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 4);
+    assert_eq!(env.token_balance(&env.vault()).await, 6);
+    assert_eq!(env.get_escrow().await.remaining_amount, 6);
+    // The same price for fewer tokens is worse for the taker
+    let result = env
+        .exchange_with(env.exchange_instruction_with_max(3, 10))
+        .await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+
+    env.cancel_with(env.amend_instruction(EXPECTED_AMOUNT, 2, 0))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 2);
+    assert_eq!(env.token_balance(&env.vault()).await, 8);
+    assert_eq!(env.get_escrow().await.remaining_amount, 8);
+}
+
+#[tokio::test]
+async fn test_amend_withdraw_everything() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    let result = env
+        .cancel_with(env.amend_instruction(EXPECTED_AMOUNT, 0, OFFERED_AMOUNT))
+        .await;
+    assert_instruction_error(result, InstructionError::InsufficientFunds);
+}
+
+#[tokio::test]
+async fn test_amend_not_initializer() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.amend_instruction(1, 0, 0);
+    instruction.accounts[0].pubkey = env.bob.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+#[tokio::test]
+async fn test_amend_missing_signature() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.amend_instruction(1, 0, 0);
+    instruction.accounts[0].is_signer = false;
+
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+const AUCTION_START_AMOUNT: u64 = 90;
+const AUCTION_START_TIME: i64 = 1_000;
+const AUCTION_END_TIME: i64 = 1_600;
+
+impl Env {
+    fn init_auction_instruction(&self, start_time: i64, end_time: i64) -> Instruction {
+        instruction::init_escrow(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            AUCTION_START_AMOUNT,
+            None,
+            None,
+            Some(Auction {
+                end_amount: EXPECTED_AMOUNT,
+                start_time,
+                end_time,
+            }),
+            None,
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_auction_exchange() {
+    let mut env = Env::new().await;
+    env.set_unix_timestamp(AUCTION_START_TIME).await;
+    env.init_escrow_with(env.init_auction_instruction(AUCTION_START_TIME, AUCTION_END_TIME))
+        .await
+        .unwrap();
+    let early = env.exchange_instruction_with_max(5, 30);
+    let result = env.exchange_with(early).await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+
+    // Half way through the price has come down from 90 to 60 for all ten tokens
+    env.set_unix_timestamp(1_300).await;
+    env.exchange_with(env.exchange_instruction_with_max(5, 30))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 5);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 30);
+    let escrow = env.get_escrow().await;
+    assert_eq!(escrow.remaining_amount, 5);
+    assert_eq!(escrow.expected_amount, 45);
+    assert_eq!(escrow.auction.unwrap().end_amount, 15);
+
+    // The price rests at the end amount once the auction is over
+    env.set_unix_timestamp(5_000).await;
+    env.exchange_with(env.exchange_instruction_with_max(5, 15))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 45);
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_init_auction_without_duration() {
+    let mut env = Env::new().await;
+
+    let result = env
+        .init_escrow_with(env.init_auction_instruction(AUCTION_END_TIME, AUCTION_END_TIME))
+        .await;
+    assert_escrow_error(result, EscrowError::InvalidInstruction);
+}
+
+const ORACLE_TIME: i64 = 1_000;
+const ORACLE_MAX_AGE: u64 = 60;
+const ORACLE_FEED_ID: [u8; 32] = [9; 32];
+
+impl Env {
+    /// Alice asks the oracle's price less 1%, but never less than `EXPECTED_AMOUNT`
+    fn init_oracle_instruction(&self, price_account: &Pubkey) -> Instruction {
+        instruction::init_escrow(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            EXPECTED_AMOUNT,
+            None,
+            None,
+            None,
+            Some(OraclePricing {
+                kind: OracleKind::Pyth,
+                price_account: *price_account,
+                feed_id: ORACLE_FEED_ID,
+                offset_basis_points: -100,
+                max_age: ORACLE_MAX_AGE,
+                max_confidence_basis_points: 100,
+            }),
+        )
+    }
+
+    /// Posts a Pyth price update of `price` tenths of token Y per token X
+    async fn set_oracle_price(
+        &mut self,
+        price_account: &Pubkey,
+        price: i64,
+        confidence: u64,
+        publish_time: i64,
+    ) {
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let mut data = PythPriceUpdate::DISCRIMINATOR.to_vec();
+        data.extend_from_slice(Pubkey::new_unique().as_ref());
+        data.push(PythPriceUpdate::FULL_VERIFICATION);
+        data.extend_from_slice(&ORACLE_FEED_ID);
+        data.extend_from_slice(&price.to_le_bytes());
+        data.extend_from_slice(&confidence.to_le_bytes());
+        data.extend_from_slice(&(-1i32).to_le_bytes());
+        data.extend_from_slice(&publish_time.to_le_bytes());
+        data.resize(PythPriceUpdate::LEN, 0);
+        self.context.set_account(
+            price_account,
+            &Account {
+                lamports: rent.minimum_balance(data.len()),
+                data,
+                owner: pyth_receiver::id(),
+                ..Account::default()
+            }
+            .into(),
+        );
+    }
+
+    fn oracle_exchange_instruction(
+        &self,
+        price_account: &Pubkey,
+        amount: u64,
+        max_payment: u64,
+    ) -> Instruction {
+        instruction::exchange(
+            &self.program_id,
+            &self.token_program,
+            &self.token_program,
+            &self.bob.pubkey(),
+            &self.bob_y.pubkey(),
+            &self.bob_x.pubkey(),
+            &self.vault(),
+            &self.alice.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            &self.mint_y.pubkey(),
+            amount,
+            max_payment,
+            MAX_PROTOCOL_FEE_BASIS_POINTS,
+            None,
+            Some(price_account),
+        )
+    }
+}
+
+#[tokio::test]
+async fn test_oracle_exchange() {
+    let mut env = Env::new().await;
+    let price_account = Pubkey::new_unique();
+    env.set_unix_timestamp(ORACLE_TIME).await;
+    env.init_escrow_with(env.init_oracle_instruction(&price_account))
+        .await
+        .unwrap();
+
+    // Five tokens at 4 less 1% come to 19.8, rounded up
+    env.set_oracle_price(&price_account, 40, 0, ORACLE_TIME - 10)
+        .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, 19))
+        .await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+    env.exchange_with(env.oracle_exchange_instruction(&price_account, 5, 20))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 5);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 20);
+    let escrow = env.get_escrow().await;
+    assert_eq!(escrow.remaining_amount, 5);
+    assert_eq!(escrow.expected_amount, EXPECTED_AMOUNT / 2);
+
+    // Below the floor the floor applies
+    env.set_oracle_price(&price_account, 20, 0, ORACLE_TIME)
+        .await;
+    env.exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 35);
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_oracle_exchange_rejects_bad_prices() {
+    let mut env = Env::new().await;
+    let price_account = Pubkey::new_unique();
+    env.set_unix_timestamp(ORACLE_TIME).await;
+    env.init_escrow_with(env.init_oracle_instruction(&price_account))
+        .await
+        .unwrap();
+
+    env.set_oracle_price(
+        &price_account,
+        40,
+        0,
+        ORACLE_TIME - ORACLE_MAX_AGE as i64 - 1,
+    )
+    .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::StaleOraclePrice);
+    env.set_oracle_price(&price_account, 40, 0, ORACLE_TIME + 1)
+        .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::StaleOraclePrice);
+
+    // A confidence interval of 0.1 is 2.5% of the price
+    env.set_oracle_price(&price_account, 40, 1, ORACLE_TIME)
+        .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::OraclePriceUncertain);
+
+    env.set_oracle_price(&price_account, 0, 0, ORACLE_TIME)
+        .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::OraclePriceUncertain);
+
+    let other_account = Pubkey::new_unique();
+    env.set_oracle_price(&other_account, 40, 0, ORACLE_TIME)
+        .await;
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&other_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::MissingPriceAccount);
+    let result = env.exchange_with(env.exchange_instruction(5)).await;
+    assert_escrow_error(result, EscrowError::MissingPriceAccount);
+
+    // A verified update of another feed, posted by whoever writes the price account
+    env.set_oracle_price(&price_account, 40, 0, ORACLE_TIME)
+        .await;
+    let mut account = env.get_account(&price_account).await.unwrap();
+    account.data[41..73].copy_from_slice(&[7; 32]);
+    env.context.set_account(&price_account, &account.into());
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_escrow_error(result, EscrowError::WrongOracleFeed);
+
+    // A price update the Pyth receiver didn't post
+    let mut account = env.get_account(&price_account).await.unwrap();
+    account.owner = Pubkey::new_unique();
+    env.context.set_account(&price_account, &account.into());
+    let result = env
+        .exchange_with(env.oracle_exchange_instruction(&price_account, 5, u64::MAX))
+        .await;
+    assert_instruction_error(result, InstructionError::IncorrectProgramId);
+
+    assert_eq!(env.get_escrow().await.remaining_amount, OFFERED_AMOUNT);
+}
+
+#[tokio::test]
+async fn test_init_oracle_escrow_invalid() {
+    let mut env = Env::new().await;
+    let mut instruction = env.init_oracle_instruction(&Pubkey::new_unique());
+    let instruction::EscrowInstruction::InitEscrow {
+        oracle: Some(oracle),
+        ..
+    } = instruction::EscrowInstruction::unpack(&instruction.data).unwrap()
+    else {
+        unreachable!();
+    };
+    let init_data = |auction, offset_basis_points| {
+        instruction::EscrowInstruction::InitEscrow {
+            offered_amount: OFFERED_AMOUNT,
+            amount: EXPECTED_AMOUNT,
+            expires_at: None,
+            allowed_taker: None,
+            auction,
+            oracle: Some(OraclePricing {
+                offset_basis_points,
+                ..oracle
+            }),
+        }
+        .pack()
+    };
+
+    // Priced by an auction and an oracle at once
+    instruction.data = init_data(
+        Some(Auction {
+            end_amount: EXPECTED_AMOUNT,
+            start_time: AUCTION_START_TIME,
+            end_time: AUCTION_END_TIME,
+        }),
+        -100,
+    );
+    let result = env.init_escrow_with(instruction.clone()).await;
+    assert_escrow_error(result, EscrowError::InvalidInstruction);
+
+    // Nothing is left of a price 100% under the oracle
+    instruction.data = init_data(None, -(MAX_FEE_BASIS_POINTS as i16));
+    let alice = env.alice.insecure_clone();
+    let result = env.process(&[instruction.clone()], &[&alice]).await;
+    assert_escrow_error(result, EscrowError::InvalidInstruction);
+
+    // The mock oracle isn't built into the program without the `mock-oracle` feature
+    if !cfg!(feature = "mock-oracle") {
+        instruction.data = init_data(None, -100);
+        let kind = instruction
+            .data
+            .windows(32)
+            .position(|key| key == oracle.price_account.as_ref())
+            .unwrap()
+            - 1;
+        assert_eq!(instruction.data[kind], OracleKind::Pyth as u8);
+        instruction.data[kind] = 0;
+        let result = env.process(&[instruction], &[&alice]).await;
+        assert_escrow_error(result, EscrowError::InvalidInstruction);
+    }
+}
+
+#[tokio::test]
+async fn test_cancel() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    env.cancel_with(env.cancel_instruction()).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT
+    );
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_cancel_missing_signature() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let instruction = instruction::refund_expired(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.vault(),
+        &env.alice_x.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+    );
+
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_refund_expired() {
+    let mut env = Env::new().await;
+    env.set_unix_timestamp(1_000).await;
+    let instruction = instruction::init_escrow(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        EXPECTED_AMOUNT,
+        Some(2_000),
+        None,
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+    env.set_unix_timestamp(2_000).await;
+
+    let instruction = instruction::refund_expired(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.vault(),
+        &env.alice_x.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+    );
+    env.process(&[instruction], &[]).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT
+    );
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_refund_expired_to_foreign_account() {
+    let mut env = Env::new().await;
+    env.set_unix_timestamp(1_000).await;
+    let instruction = instruction::init_escrow(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        EXPECTED_AMOUNT,
+        Some(2_000),
+        None,
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+    env.set_unix_timestamp(2_000).await;
+
+    let instruction = instruction::refund_expired(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.vault(),
+        &env.bob_x.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+    );
+    let result = env.process(&[instruction], &[]).await;
+    assert_escrow_error(result, EscrowError::TokenAccountOwnerMismatch);
+}
+
+#[tokio::test]
+async fn test_cancel_wrong_initializer() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    let mut instruction = env.cancel_instruction();
+    instruction.accounts[0].pubkey = env.bob.pubkey();
+    let bob = env.bob.insecure_clone();
+
+    let result = env.process(&[instruction], &[&bob]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+const OFFERED_LAMPORTS: u64 = 100_000_000;
+
+impl Env {
+    async fn init_sol_offer(&mut self) {
+        let instruction = instruction::init_sol_offer(
+            &self.program_id,
+            &self.alice.pubkey(),
+            &self.alice_y.pubkey(),
+            &self.escrow.pubkey(),
+            OFFERED_LAMPORTS,
+            EXPECTED_AMOUNT,
+            None,
+            None,
+            None,
+            None,
+        );
+        self.init_escrow_with(instruction).await.unwrap();
+    }
+
+    async fn lamports(&mut self, address: &Pubkey) -> u64 {
+        self.get_account(address)
+            .await
+            .map(|account| account.lamports)
+            .unwrap_or_default()
+    }
+}
+
+#[tokio::test]
+async fn test_sol_offer_exchange() {
+    let mut env = Env::new().await;
+    env.init_sol_offer().await;
+    let (pda, _) = find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    assert_eq!(env.get_account(&pda).await.unwrap().owner, env.program_id);
+    let escrow = env.get_escrow().await;
+    assert!(escrow.offers_sol());
+    assert_eq!(escrow.remaining_amount, OFFERED_LAMPORTS);
+
+    let bob_lamports = env.lamports(&env.bob.pubkey()).await;
+    let alice_lamports = env.lamports(&env.alice.pubkey()).await;
+    let exchange = |amount| {
+        instruction::exchange(
+            &env.program_id,
+            &system_program::id(),
+            &env.token_program,
+            &env.bob.pubkey(),
+            &env.bob_y.pubkey(),
+            &env.bob.pubkey(),
+            &pda,
+            &env.alice.pubkey(),
+            &env.alice_y.pubkey(),
+            &env.escrow.pubkey(),
+            &NATIVE_MINT,
+            &env.mint_y.pubkey(),
+            amount,
+            u64::MAX,
+            MAX_PROTOCOL_FEE_BASIS_POINTS,
+            None,
+            None,
+        )
+    };
+    let (partial, rest) = (exchange(40_000_000), exchange(60_000_000));
+
+    env.exchange_with(partial).await.unwrap();
+    assert_eq!(
+        env.lamports(&env.bob.pubkey()).await,
+        bob_lamports + 40_000_000
+    );
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 12);
+
+    env.exchange_with(rest).await.unwrap();
+    assert_eq!(
+        env.lamports(&env.bob.pubkey()).await,
+        bob_lamports + OFFERED_LAMPORTS
+    );
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert!(env.get_account(&pda).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    assert!(env.lamports(&env.alice.pubkey()).await > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_sol_offer_exchange_returns_extra_lamports() {
+    let mut env = Env::new().await;
+    env.init_sol_offer().await;
+    let (pda, _) = find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    let payer = env.context.payer.pubkey();
+    env.process(
+        &[system_instruction::transfer(&payer, &pda, 5_000_000)],
+        &[],
+    )
+    .await
+    .unwrap();
+    let bob_lamports = env.lamports(&env.bob.pubkey()).await;
+    let alice_lamports = env.lamports(&env.alice.pubkey()).await;
+    let vault_lamports = env.lamports(&pda).await;
+
+    let exchange = instruction::exchange(
+        &env.program_id,
+        &system_program::id(),
+        &env.token_program,
+        &env.bob.pubkey(),
+        &env.bob_y.pubkey(),
+        &env.bob.pubkey(),
+        &pda,
+        &env.alice.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &NATIVE_MINT,
+        &env.mint_y.pubkey(),
+        OFFERED_LAMPORTS,
+        u64::MAX,
+        MAX_PROTOCOL_FEE_BASIS_POINTS,
+        None,
+        None,
+    );
+    env.exchange_with(exchange).await.unwrap();
+
+    assert_eq!(
+        env.lamports(&env.bob.pubkey()).await,
+        bob_lamports + OFFERED_LAMPORTS
+    );
+    assert!(env.get_account(&pda).await.is_none());
+    // Alice gets the extra lamports back along with the vault's and the escrow's rent
+    assert!(
+        env.lamports(&env.alice.pubkey()).await
+            > alice_lamports + vault_lamports - OFFERED_LAMPORTS
+    );
+}
+
+#[tokio::test]
+async fn test_sol_offer_amend() {
+    let mut env = Env::new().await;
+    env.init_sol_offer().await;
+    let (pda, _) = find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    let pda_lamports = env.lamports(&pda).await;
+    let amend = |top_up_amount, withdraw_amount| {
+        instruction::amend(
+            &env.program_id,
+            &system_program::id(),
+            &env.alice.pubkey(),
+            &pda,
+            &env.alice.pubkey(),
+            &env.escrow.pubkey(),
+            &NATIVE_MINT,
+            EXPECTED_AMOUNT,
+            top_up_amount,
+            withdraw_amount,
+        )
+    };
+    let (withdraw, top_up) = (amend(0, 30_000_000), amend(10_000_000, 0));
+
+    env.cancel_with(withdraw).await.unwrap();
+    assert_eq!(env.lamports(&pda).await, pda_lamports - 30_000_000);
+    env.cancel_with(top_up).await.unwrap();
+    assert_eq!(env.lamports(&pda).await, pda_lamports - 20_000_000);
+    assert_eq!(
+        env.get_escrow().await.remaining_amount,
+        OFFERED_LAMPORTS - 20_000_000
+    );
+}
+
+#[tokio::test]
+async fn test_sol_offer_wrong_vault() {
+    let mut env = Env::new().await;
+    let mut instruction = instruction::init_sol_offer(
+        &env.program_id,
+        &env.alice.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        OFFERED_LAMPORTS,
+        EXPECTED_AMOUNT,
+        None,
+        None,
+        None,
+        None,
+    );
+    instruction.accounts[1].pubkey = Pubkey::new_unique();
+
+    let result = env.init_escrow_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidSeeds);
+}
+
+#[tokio::test]
+async fn test_sol_offer_cancel() {
+    let mut env = Env::new().await;
+    let alice_lamports = env.lamports(&env.alice.pubkey()).await;
+    env.init_sol_offer().await;
+    let (pda, _) = find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    assert!(env.lamports(&env.alice.pubkey()).await < alice_lamports - OFFERED_LAMPORTS);
+
+    let instruction = instruction::cancel(
+        &env.program_id,
+        &system_program::id(),
+        &env.alice.pubkey(),
+        &pda,
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &NATIVE_MINT,
+    );
+    env.cancel_with(instruction).await.unwrap();
+
+    assert!(env.get_account(&pda).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    // Alice also gets the escrow account's rent, which the payer funded
+    assert!(env.lamports(&env.alice.pubkey()).await > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_sol_offer_foreign_escrow_account() {
+    let mut env = Env::new().await;
+    env.init_sol_offer().await;
+    let (pda, _) = find_escrow_pda(&env.program_id, &env.escrow.pubkey(), &env.alice.pubkey());
+    let pda_lamports = env.lamports(&pda).await;
+    // The same escrow data, in an account some other program can write
+    let mut escrow_account = env.get_account(&env.escrow.pubkey()).await.unwrap();
+    escrow_account.owner = Pubkey::new_unique();
+    env.context
+        .set_account(&env.escrow.pubkey(), &escrow_account.into());
+
+    let exchange = instruction::exchange(
+        &env.program_id,
+        &system_program::id(),
+        &env.token_program,
+        &env.bob.pubkey(),
+        &env.bob_y.pubkey(),
+        &env.bob.pubkey(),
+        &pda,
+        &env.alice.pubkey(),
+        &env.alice_y.pubkey(),
+        &env.escrow.pubkey(),
+        &NATIVE_MINT,
+        &env.mint_y.pubkey(),
+        OFFERED_LAMPORTS,
+        u64::MAX,
+        MAX_PROTOCOL_FEE_BASIS_POINTS,
+        None,
+        None,
+    );
+    let result = env.exchange_with(exchange).await;
+    assert_instruction_error(result, InstructionError::IncorrectProgramId);
+    let cancel = instruction::cancel(
+        &env.program_id,
+        &system_program::id(),
+        &env.alice.pubkey(),
+        &pda,
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &NATIVE_MINT,
+    );
+    let result = env.cancel_with(cancel).await;
+    assert_instruction_error(result, InstructionError::IncorrectProgramId);
+
+    assert_eq!(env.lamports(&pda).await, pda_lamports);
+}
+
+#[tokio::test]
+async fn test_sol_request_exchange() {
+    let mut env = Env::new().await;
+    let instruction = instruction::init_sol_request(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        OFFERED_LAMPORTS,
+        None,
+        None,
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+    assert!(env.get_escrow().await.requests_sol());
+    let bob_lamports = env.lamports(&env.bob.pubkey()).await;
+    let alice_lamports = env.lamports(&env.alice.pubkey()).await;
+
+    let instruction = instruction::exchange(
+        &env.program_id,
+        &env.token_program,
+        &system_program::id(),
+        &env.bob.pubkey(),
+        &env.bob.pubkey(),
+        &env.bob_x.pubkey(),
+        &env.vault(),
+        &env.alice.pubkey(),
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        &NATIVE_MINT,
+        OFFERED_AMOUNT,
+        OFFERED_LAMPORTS,
+        MAX_PROTOCOL_FEE_BASIS_POINTS,
+        None,
+        None,
+    );
+    env.exchange_with(instruction).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(
+        env.lamports(&env.bob.pubkey()).await,
+        bob_lamports - OFFERED_LAMPORTS
+    );
+    assert!(env.lamports(&env.alice.pubkey()).await > alice_lamports + OFFERED_LAMPORTS);
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_sol_request_wrong_taker_account() {
+    let mut env = Env::new().await;
+    let instruction = instruction::init_sol_request(
+        &env.program_id,
+        &env.token_program,
+        &env.alice.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        OFFERED_AMOUNT,
+        OFFERED_LAMPORTS,
+        None,
+        None,
+        None,
+        None,
+    );
+    env.init_escrow_with(instruction).await.unwrap();
+
+    let instruction = instruction::exchange(
+        &env.program_id,
+        &env.token_program,
+        &system_program::id(),
+        &env.bob.pubkey(),
+        &env.bob_y.pubkey(),
+        &env.bob_x.pubkey(),
+        &env.vault(),
+        &env.alice.pubkey(),
+        &env.alice.pubkey(),
+        &env.escrow.pubkey(),
+        &env.mint_x.pubkey(),
+        &NATIVE_MINT,
+        OFFERED_AMOUNT,
+        OFFERED_LAMPORTS,
+        MAX_PROTOCOL_FEE_BASIS_POINTS,
+        None,
+        None,
+    );
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::TokenAccountOwnerMismatch);
+}
+
+const TRANSFER_FEE_BASIS_POINTS: u16 = 1_000;
+
+#[tokio::test]
+async fn test_token_2022_exchange() {
+    let mut env = Env::with_token_program(spl_token_2022::id(), None).await;
+    env.init_escrow().await;
+
+    env.exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_token_2022_transfer_fee_exchange() {
+    let mut env =
+        Env::with_token_program(spl_token_2022::id(), Some(TRANSFER_FEE_BASIS_POINTS)).await;
+    env.init_escrow().await;
+    // 1 of the 10 deposited tokens is withheld in the vault
+    assert_eq!(env.get_escrow().await.remaining_amount, 9);
+
+    env.exchange_with(env.exchange_instruction(9))
+        .await
+        .unwrap();
+
+    // Bob sends 34 so Alice gets exactly 30 after the 4 token fee
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert_eq!(env.token_balance(&env.bob_y.pubkey()).await, 100 - 34);
+    // Bob bears the fee on the offered leg
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 8);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_token_2022_transfer_fee_cancel() {
+    let mut env =
+        Env::with_token_program(spl_token_2022::id(), Some(TRANSFER_FEE_BASIS_POINTS)).await;
+    env.init_escrow().await;
+
+    env.cancel_with(env.cancel_instruction()).await.unwrap();
+
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 8);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_token_2022_wrong_token_program() {
+    let mut env = Env::with_token_program(spl_token_2022::id(), None).await;
+    env.init_escrow().await;
+    let mut instruction = env.exchange_instruction(OFFERED_AMOUNT);
+    instruction.accounts[7].pubkey = spl_token::id();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::IncorrectProgramId);
+}
+
+const BUNDLE_X: u64 = 5;
+const BUNDLE_Z: u64 = 7;
+const BUNDLE_Y: u64 = 20;
+const BUNDLE_W: u64 = 3;
+
+/// Alice bundles `BUNDLE_X` of token X and `BUNDLE_Z` of token Z for `BUNDLE_Y` of token Y and
+/// `BUNDLE_W` of token W
+struct BundleTrade {
+    bundle: Keypair,
+    mint_z: Keypair,
+    mint_w: Keypair,
+    alice_z: Keypair,
+    alice_w: Keypair,
+    bob_z: Keypair,
+    bob_w: Keypair,
+}
+
+impl Env {
+    async fn create_bundle_trade(&mut self, bob_w_amount: u64) -> BundleTrade {
+        let trade = BundleTrade {
+            bundle: Keypair::new(),
+            mint_z: Keypair::new(),
+            mint_w: Keypair::new(),
+            alice_z: Keypair::new(),
+            alice_w: Keypair::new(),
+            bob_z: Keypair::new(),
+            bob_w: Keypair::new(),
+        };
+        self.create_mint(&trade.mint_z).await;
+        self.create_mint(&trade.mint_w).await;
+        let (alice, bob) = (self.alice.pubkey(), self.bob.pubkey());
+        let (mint_z, mint_w) = (trade.mint_z.pubkey(), trade.mint_w.pubkey());
+        self.create_token_account(&trade.alice_z, &mint_z, &alice, BUNDLE_Z)
+            .await;
+        self.create_token_account(&trade.alice_w, &mint_w, &alice, 0)
+            .await;
+        self.create_token_account(&trade.bob_z, &mint_z, &bob, 0)
+            .await;
+        self.create_token_account(&trade.bob_w, &mint_w, &bob, bob_w_amount)
+            .await;
+
+        let space = Bundle::get_packed_len(2, 2);
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let instruction = system_instruction::create_account(
+            &self.context.payer.pubkey(),
+            &trade.bundle.pubkey(),
+            rent.minimum_balance(space),
+            space as u64,
+            &self.program_id,
+        );
+        self.process(&[instruction], &[&trade.bundle])
+            .await
+            .unwrap();
+        trade
+    }
+
+    fn bundle_offered_legs(
+        &self,
+        trade: &BundleTrade,
+        x_account: &Keypair,
+        z_account: &Keypair,
+    ) -> Vec<BundleLeg> {
+        vec![
+            BundleLeg {
+                mint: self.mint_x.pubkey(),
+                token_program_id: self.token_program,
+                token_account: x_account.pubkey(),
+            },
+            BundleLeg {
+                mint: trade.mint_z.pubkey(),
+                token_program_id: self.token_program,
+                token_account: z_account.pubkey(),
+            },
+        ]
+    }
+
+    fn init_bundle_instruction(&self, trade: &BundleTrade) -> Instruction {
+        instruction::init_bundle(
+            &self.program_id,
+            &self.alice.pubkey(),
+            &trade.bundle.pubkey(),
+            &self.bundle_offered_legs(trade, &self.alice_x, &trade.alice_z),
+            &[BUNDLE_X, BUNDLE_Z],
+            &[self.alice_y.pubkey(), trade.alice_w.pubkey()],
+            &[BUNDLE_Y, BUNDLE_W],
+            None,
+            None,
+        )
+    }
+
+    fn exchange_bundle_instruction(&self, trade: &BundleTrade) -> Instruction {
+        instruction::exchange_bundle(
+            &self.program_id,
+            &self.bob.pubkey(),
+            &self.alice.pubkey(),
+            &trade.bundle.pubkey(),
+            &self.bundle_offered_legs(trade, &self.bob_x, &trade.bob_z),
+            &[
+                BundleLeg {
+                    mint: self.mint_y.pubkey(),
+                    token_program_id: self.token_program,
+                    token_account: self.bob_y.pubkey(),
+                },
+                BundleLeg {
+                    mint: trade.mint_w.pubkey(),
+                    token_program_id: self.token_program,
+                    token_account: trade.bob_w.pubkey(),
+                },
+            ],
+            &[self.alice_y.pubkey(), trade.alice_w.pubkey()],
+        )
+    }
+
+    fn bundle_vaults(&self, trade: &BundleTrade) -> [Pubkey; 2] {
+        [0, 1].map(|index| {
+            find_bundle_vault_address(&self.program_id, &trade.bundle.pubkey(), index).0
+        })
+    }
+}
+
+#[tokio::test]
+async fn test_bundle_exchange() {
+    let mut env = Env::new().await;
+    let trade = env.create_bundle_trade(BUNDLE_W).await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.init_bundle_instruction(&trade)], &[&alice])
+        .await
+        .unwrap();
+
+    let bundle = env.get_account(&trade.bundle.pubkey()).await.unwrap();
+    let bundle = Bundle::unpack(&bundle.data).unwrap();
+    let vaults = env.bundle_vaults(&trade);
+    assert_eq!(bundle.initializer_pubkey, alice.pubkey());
+    assert_eq!(bundle.offered[0].vault_pubkey, vaults[0]);
+    assert_eq!(bundle.offered[1].mint_pubkey, trade.mint_z.pubkey());
+    assert_eq!(bundle.offered[1].amount, BUNDLE_Z);
+    assert_eq!(bundle.requested[1].mint_pubkey, trade.mint_w.pubkey());
+    assert_eq!(bundle.requested[1].amount, BUNDLE_W);
+    assert_eq!(env.token_balance(&vaults[0]).await, BUNDLE_X);
+    assert_eq!(env.token_balance(&vaults[1]).await, BUNDLE_Z);
+    let alice_lamports = env.get_account(&alice.pubkey()).await.unwrap().lamports;
+
+    env.exchange_with(env.exchange_bundle_instruction(&trade))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, BUNDLE_X);
+    assert_eq!(env.token_balance(&trade.bob_z.pubkey()).await, BUNDLE_Z);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, BUNDLE_Y);
+    assert_eq!(env.token_balance(&trade.alice_w.pubkey()).await, BUNDLE_W);
+    assert_eq!(env.token_balance(&env.bob_y.pubkey()).await, 100 - BUNDLE_Y);
+    assert_eq!(env.token_balance(&trade.bob_w.pubkey()).await, 0);
+    for vault in vaults {
+        assert!(env.get_account(&vault).await.is_none());
+    }
+    assert!(env.get_account(&trade.bundle.pubkey()).await.is_none());
+    assert!(env.get_account(&alice.pubkey()).await.unwrap().lamports > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_bundle_exchange_is_atomic() {
+    let mut env = Env::new().await;
+    // Bob can pay for every leg but the last
+    let trade = env.create_bundle_trade(BUNDLE_W - 1).await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.init_bundle_instruction(&trade)], &[&alice])
+        .await
+        .unwrap();
+
+    let result = env
+        .exchange_with(env.exchange_bundle_instruction(&trade))
+        .await;
+    assert_instruction_error(
+        result,
+        InstructionError::Custom(TokenError::InsufficientFunds as u32),
+    );
+
+    let vaults = env.bundle_vaults(&trade);
+    assert_eq!(env.token_balance(&vaults[0]).await, BUNDLE_X);
+    assert_eq!(env.token_balance(&vaults[1]).await, BUNDLE_Z);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 0);
+    assert_eq!(env.token_balance(&env.bob_y.pubkey()).await, 100);
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+    assert!(env.get_account(&trade.bundle.pubkey()).await.is_some());
+}
+
+#[tokio::test]
+async fn test_bundle_cancel() {
+    let mut env = Env::new().await;
+    let trade = env.create_bundle_trade(BUNDLE_W).await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.init_bundle_instruction(&trade)], &[&alice])
+        .await
+        .unwrap();
+
+    let instruction = instruction::cancel_bundle(
+        &env.program_id,
+        &alice.pubkey(),
+        &trade.bundle.pubkey(),
+        &env.bundle_offered_legs(&trade, &env.alice_x, &trade.alice_z),
+        true,
+    );
+    env.cancel_with(instruction).await.unwrap();
+
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT
+    );
+    assert_eq!(env.token_balance(&trade.alice_z.pubkey()).await, BUNDLE_Z);
+    for vault in env.bundle_vaults(&trade) {
+        assert!(env.get_account(&vault).await.is_none());
+    }
+    assert!(env.get_account(&trade.bundle.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_bundle_cancel_missing_signature() {
+    let mut env = Env::new().await;
+    let trade = env.create_bundle_trade(BUNDLE_W).await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.init_bundle_instruction(&trade)], &[&alice])
+        .await
+        .unwrap();
+
+    let instruction = instruction::cancel_bundle(
+        &env.program_id,
+        &alice.pubkey(),
+        &trade.bundle.pubkey(),
+        &env.bundle_offered_legs(&trade, &env.alice_x, &trade.alice_z),
+        false,
+    );
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_init_bundle_too_many_legs() {
+    let mut env = Env::new().await;
+    let trade = env.create_bundle_trade(BUNDLE_W).await;
+    let alice = env.alice.insecure_clone();
+    let leg = env.bundle_offered_legs(&trade, &env.alice_x, &trade.alice_z)[0];
+    let instruction = instruction::init_bundle(
+        &env.program_id,
+        &alice.pubkey(),
+        &trade.bundle.pubkey(),
+        &[leg; 5],
+        &[1; 5],
+        &[env.alice_y.pubkey()],
+        &[BUNDLE_Y],
+        None,
+        None,
+    );
+
+    let result = env.process(&[instruction], &[&alice]).await;
+    assert_escrow_error(result, EscrowError::InvalidBundleSize);
+}
+
+#[tokio::test]
+async fn test_exchange_rejects_bundle_account() {
+    let mut env = Env::new().await;
+    let trade = env.create_bundle_trade(BUNDLE_W).await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.init_bundle_instruction(&trade)], &[&alice])
+        .await
+        .unwrap();
+    let mut instruction = env.exchange_instruction(BUNDLE_X);
+    instruction.accounts[6].pubkey = trade.bundle.pubkey();
+
+    let result = env.exchange_with(instruction).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+}
+
+/// Alice pays Bob `OFFERED_AMOUNT` of token X through an arbiter escrow, with Carol arbitrating
+impl Env {
+    async fn create_arbiter_escrow_account(&mut self) {
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let instruction = system_instruction::create_account(
+            &self.context.payer.pubkey(),
+            &self.escrow.pubkey(),
+            rent.minimum_balance(ArbiterEscrow::LEN),
+            ArbiterEscrow::LEN as u64,
+            &self.program_id,
+        );
+        let escrow = self.escrow.insecure_clone();
+        self.process(&[instruction], &[&escrow]).await.unwrap();
+    }
+
+    async fn init_arbiter_escrow_with(
+        &mut self,
+        recipient: &Pubkey,
+        arbiter: &Pubkey,
+    ) -> Result<(), BanksClientError> {
+        let instruction = instruction::init_arbiter_escrow(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            recipient,
+            arbiter,
+        );
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await
+    }
+
+    async fn init_arbiter_escrow(&mut self, arbiter: &Keypair) {
+        self.create_arbiter_escrow_account().await;
+        self.init_arbiter_escrow_with(&self.bob.pubkey(), &arbiter.pubkey())
+            .await
+            .unwrap();
+    }
+
+    async fn settle(
+        &mut self,
+        settlement: Settlement,
+        arbiter: &Keypair,
+        approvers: &[&Keypair],
+    ) -> Result<(), BanksClientError> {
+        let approver_keys = approvers
+            .iter()
+            .map(|approver| approver.pubkey())
+            .collect::<Vec<_>>();
+        let build = match settlement {
+            Settlement::Released => instruction::release,
+            Settlement::Refunded => instruction::refund,
+        };
+        let destination = match settlement {
+            Settlement::Released => self.bob_x.pubkey(),
+            Settlement::Refunded => self.alice_x.pubkey(),
+        };
+        let instruction = build(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.bob.pubkey(),
+            &arbiter.pubkey(),
+            &self.escrow.pubkey(),
+            &destination,
+            &self.mint_x.pubkey(),
+            &approver_keys,
+        );
+        self.process(&[instruction], approvers).await
+    }
+}
+
+#[tokio::test]
+async fn test_arbiter_escrow_release() {
+    let mut env = Env::new().await;
+    let carol = Keypair::new();
+    env.init_arbiter_escrow(&carol).await;
+    assert_eq!(env.token_balance(&env.vault()).await, OFFERED_AMOUNT);
+    let alice_lamports = env.get_account(&env.alice.pubkey()).await.unwrap().lamports;
+
+    let (alice, bob) = (env.alice.insecure_clone(), env.bob.insecure_clone());
+    env.settle(Settlement::Released, &carol, &[&alice, &bob])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    assert!(env.get_account(&env.alice.pubkey()).await.unwrap().lamports > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_arbiter_escrow_refund() {
+    let mut env = Env::new().await;
+    let carol = Keypair::new();
+    env.init_arbiter_escrow(&carol).await;
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 0);
+
+    let alice = env.alice.insecure_clone();
+    env.settle(Settlement::Refunded, &carol, &[&alice, &carol])
+        .await
+        .unwrap();
+
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT
+    );
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_arbiter_escrow_needs_two_approvals() {
+    let mut env = Env::new().await;
+    let carol = Keypair::new();
+    env.init_arbiter_escrow(&carol).await;
+
+    let (alice, bob) = (env.alice.insecure_clone(), env.bob.insecure_clone());
+    let result = env.settle(Settlement::Refunded, &carol, &[&alice]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+    let result = env.settle(Settlement::Released, &carol, &[&bob]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+    let result = env.settle(Settlement::Released, &carol, &[&carol]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_arbiter_escrow_dispute() {
+    let mut env = Env::new().await;
+    let carol = Keypair::new();
+    env.init_arbiter_escrow(&carol).await;
+
+    // Only the paying and the paid party can raise a dispute
+    let dispute = instruction::dispute(&env.program_id, &carol.pubkey(), &env.escrow.pubkey());
+    let result = env.process(&[dispute], &[&carol]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+
+    let bob = env.bob.insecure_clone();
+    let dispute = instruction::dispute(&env.program_id, &bob.pubkey(), &env.escrow.pubkey());
+    env.process(&[dispute], &[&bob]).await.unwrap();
+    assert!(
+        ArbiterEscrow::unpack(&env.get_account(&env.escrow.pubkey()).await.unwrap().data)
+            .unwrap()
+            .is_disputed
+    );
+    let alice = env.alice.insecure_clone();
+    let dispute = instruction::dispute(&env.program_id, &alice.pubkey(), &env.escrow.pubkey());
+    let result = env.process(&[dispute], &[&alice]).await;
+    assert_escrow_error(result, EscrowError::AlreadyDisputed);
+
+    // The parties still can't settle alone, but the arbiter now can
+    let result = env.settle(Settlement::Refunded, &carol, &[&alice]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+    env.settle(Settlement::Released, &carol, &[&carol])
+        .await
+        .unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+}
+
+#[tokio::test]
+async fn test_init_arbiter_escrow_duplicate_parties() {
+    let mut env = Env::new().await;
+    env.create_arbiter_escrow_account().await;
+    let (alice, bob) = (env.alice.pubkey(), env.bob.pubkey());
+
+    let result = env.init_arbiter_escrow_with(&bob, &alice).await;
+    assert_escrow_error(result, EscrowError::InvalidInstruction);
+    let result = env.init_arbiter_escrow_with(&bob, &bob).await;
+    assert_escrow_error(result, EscrowError::InvalidInstruction);
+}
+
+/// Alice pays Bob `OFFERED_AMOUNT` of token X in three milestones
+const MILESTONES: [u64; 3] = [2, 3, 5];
+const MILESTONE_DEADLINE: i64 = 2_000;
+
+impl Env {
+    async fn init_milestone_escrow(&mut self) {
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let len = MilestoneEscrow::get_packed_len(MILESTONES.len());
+        let instruction = system_instruction::create_account(
+            &self.context.payer.pubkey(),
+            &self.escrow.pubkey(),
+            rent.minimum_balance(len),
+            len as u64,
+            &self.program_id,
+        );
+        let escrow = self.escrow.insecure_clone();
+        self.process(&[instruction], &[&escrow]).await.unwrap();
+
+        self.set_unix_timestamp(1_000).await;
+        let instruction = instruction::init_milestone_escrow(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            &MILESTONES,
+            MILESTONE_DEADLINE,
+            &self.bob.pubkey(),
+        );
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await.unwrap();
+    }
+
+    fn approve_milestone_instruction(&self) -> Instruction {
+        instruction::approve_milestone(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.escrow.pubkey(),
+            &self.bob_x.pubkey(),
+            &self.mint_x.pubkey(),
+        )
+    }
+
+    async fn refund_milestones(&mut self) -> Result<(), BanksClientError> {
+        let instruction = instruction::refund_milestones(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.escrow.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.mint_x.pubkey(),
+        );
+        self.process(&[instruction], &[]).await
+    }
+}
+
+#[tokio::test]
+async fn test_milestone_escrow_approvals() {
+    let mut env = Env::new().await;
+    env.init_milestone_escrow().await;
+    assert_eq!(env.token_balance(&env.vault()).await, OFFERED_AMOUNT);
+    let alice = env.alice.insecure_clone();
+
+    let mut released = 0;
+    for (index, milestone) in MILESTONES.iter().enumerate() {
+        let data = env.get_account(&env.escrow.pubkey()).await.unwrap().data;
+        let escrow_info = MilestoneEscrow::unpack(&data).unwrap();
+        assert_eq!(escrow_info.released_milestones as usize, index);
+        env.process(&[env.approve_milestone_instruction()], &[&alice])
+            .await
+            .unwrap();
+        released += milestone;
+        assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, released);
+    }
+
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_milestone_escrow_approval_needs_initializer() {
+    let mut env = Env::new().await;
+    env.init_milestone_escrow().await;
+
+    let bob = env.bob.insecure_clone();
+    let mut instruction = env.approve_milestone_instruction();
+    instruction.accounts[0].pubkey = bob.pubkey();
+    let result = env.process(&[instruction], &[&bob]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+
+    let mut instruction = env.approve_milestone_instruction();
+    instruction.accounts[0].is_signer = false;
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_milestone_escrow_refund_after_deadline() {
+    let mut env = Env::new().await;
+    env.init_milestone_escrow().await;
+    let alice = env.alice.insecure_clone();
+    env.process(&[env.approve_milestone_instruction()], &[&alice])
+        .await
+        .unwrap();
+
+    let result = env.refund_milestones().await;
+    assert_escrow_error(result, EscrowError::DeadlineNotReached);
+
+    env.set_unix_timestamp(MILESTONE_DEADLINE).await;
+    env.refund_milestones().await.unwrap();
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT - MILESTONES[0]
+    );
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, MILESTONES[0]);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_init_milestone_escrow_invalid_milestones() {
+    let mut env = Env::new().await;
+    for amounts in [&[][..], &[5, 0, 5]] {
+        let instruction = instruction::init_milestone_escrow(
+            &env.program_id,
+            &env.token_program,
+            &env.alice.pubkey(),
+            &env.alice_x.pubkey(),
+            &env.escrow.pubkey(),
+            &env.mint_x.pubkey(),
+            amounts,
+            MILESTONE_DEADLINE,
+            &env.bob.pubkey(),
+        );
+        let alice = env.alice.insecure_clone();
+        let result = env.process(&[instruction], &[&alice]).await;
+        assert_escrow_error(result, EscrowError::InvalidMilestones);
+    }
+}
+
+/// Alice streams `OFFERED_AMOUNT` of token X to Bob over a thousand seconds
+const STREAM_START: i64 = 1_000;
+const STREAM_END: i64 = 2_000;
+const STREAM_CLIFF: i64 = 1_250;
+
+impl Env {
+    async fn init_stream(&mut self) {
+        let rent = self.context.banks_client.get_rent().await.unwrap();
+        let instruction = system_instruction::create_account(
+            &self.context.payer.pubkey(),
+            &self.escrow.pubkey(),
+            rent.minimum_balance(Stream::LEN),
+            Stream::LEN as u64,
+            &self.program_id,
+        );
+        let escrow = self.escrow.insecure_clone();
+        self.process(&[instruction], &[&escrow]).await.unwrap();
+
+        let instruction = instruction::init_stream(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.escrow.pubkey(),
+            &self.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            STREAM_START,
+            STREAM_END,
+            Some(STREAM_CLIFF),
+            &self.bob.pubkey(),
+        );
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await.unwrap();
+    }
+
+    async fn claim(&mut self) -> Result<(), BanksClientError> {
+        let instruction = instruction::claim(
+            &self.program_id,
+            &self.token_program,
+            &self.bob.pubkey(),
+            &self.alice.pubkey(),
+            &self.escrow.pubkey(),
+            &self.bob_x.pubkey(),
+            &self.mint_x.pubkey(),
+        );
+        let bob = self.bob.insecure_clone();
+        self.process(&[instruction], &[&bob]).await
+    }
+
+    async fn cancel_stream(&mut self) -> Result<(), BanksClientError> {
+        let instruction = instruction::cancel_stream(
+            &self.program_id,
+            &self.token_program,
+            &self.alice.pubkey(),
+            &self.escrow.pubkey(),
+            &self.alice_x.pubkey(),
+            &self.bob_x.pubkey(),
+            &self.mint_x.pubkey(),
+        );
+        let alice = self.alice.insecure_clone();
+        self.process(&[instruction], &[&alice]).await
+    }
+}
+
+#[tokio::test]
+async fn test_stream_claims() {
+    let mut env = Env::new().await;
+    env.init_stream().await;
+    assert_eq!(env.token_balance(&env.vault()).await, OFFERED_AMOUNT);
+
+    env.set_unix_timestamp(STREAM_CLIFF - 1).await;
+    let result = env.claim().await;
+    assert_escrow_error(result, EscrowError::NothingToClaim);
+
+    env.set_unix_timestamp(1_500).await;
+    env.claim().await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 5);
+    let stream =
+        Stream::unpack(&env.get_account(&env.escrow.pubkey()).await.unwrap().data).unwrap();
+    assert_eq!(stream.claimed_amount, 5);
+
+    // Claiming again in the same second finds nothing new
+    let result = env.claim().await;
+    assert_escrow_error(result, EscrowError::NothingToClaim);
+
+    env.set_unix_timestamp(STREAM_END).await;
+    env.claim().await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_stream_claim_by_beneficiary_only() {
+    let mut env = Env::new().await;
+    env.init_stream().await;
+    env.set_unix_timestamp(STREAM_END).await;
+
+    let alice = env.alice.insecure_clone();
+    let mut instruction = instruction::claim(
+        &env.program_id,
+        &env.token_program,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &env.escrow.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.mint_x.pubkey(),
+    );
+    let result = env.process(&[instruction.clone()], &[&alice]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+
+    // The beneficiary has to sign, even with their own accounts
+    instruction.accounts[0].pubkey = env.bob.pubkey();
+    instruction.accounts[0].is_signer = false;
+    instruction.accounts[4].pubkey = env.bob_x.pubkey();
+    let result = env.process(&[instruction], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+}
+
+#[tokio::test]
+async fn test_cancel_stream() {
+    let mut env = Env::new().await;
+    env.init_stream().await;
+    env.set_unix_timestamp(1_500).await;
+    env.claim().await.unwrap();
+    let alice_lamports = env.get_account(&env.alice.pubkey()).await.unwrap().lamports;
+
+    // Bob keeps what vested after his claim, Alice gets the unvested rest
+    env.set_unix_timestamp(1_700).await;
+    env.cancel_stream().await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 7);
+    assert_eq!(env.token_balance(&env.alice_x.pubkey()).await, 3);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+    assert!(env.get_account(&env.alice.pubkey()).await.unwrap().lamports > alice_lamports);
+}
+
+#[tokio::test]
+async fn test_init_stream_invalid_schedule() {
+    let mut env = Env::new().await;
+    for (start_time, end_time, cliff_time) in [
+        (STREAM_END, STREAM_START, None),
+        (STREAM_START, STREAM_START, None),
+        (STREAM_START, STREAM_END, Some(STREAM_START - 1)),
+        (STREAM_START, STREAM_END, Some(STREAM_END + 1)),
+    ] {
+        let instruction = instruction::init_stream(
+            &env.program_id,
+            &env.token_program,
+            &env.alice.pubkey(),
+            &env.alice_x.pubkey(),
+            &env.escrow.pubkey(),
+            &env.mint_x.pubkey(),
+            OFFERED_AMOUNT,
+            start_time,
+            end_time,
+            cliff_time,
+            &env.bob.pubkey(),
+        );
+        let alice = env.alice.insecure_clone();
+        let result = env.process(&[instruction], &[&alice]).await;
+        assert_escrow_error(result, EscrowError::InvalidInstruction);
+    }
+}
+
+/// A 10% protocol fee, paid to token accounts owned by the treasury
+const PROTOCOL_FEE_BASIS_POINTS: u16 = 1_000;
+
+struct Treasury {
+    owner: Keypair,
+    x: Keypair,
+    y: Keypair,
+}
+
+impl Env {
+    async fn initialize_config(&mut self, admin: &Keypair) -> Treasury {
+        let treasury = Treasury {
+            owner: Keypair::new(),
+            x: Keypair::new(),
+            y: Keypair::new(),
+        };
+        let (mint_x, mint_y) = (self.mint_x.pubkey(), self.mint_y.pubkey());
+        self.create_token_account(&treasury.x, &mint_x, &treasury.owner.pubkey(), 0)
+            .await;
+        self.create_token_account(&treasury.y, &mint_y, &treasury.owner.pubkey(), 0)
+            .await;
+
+        self.set_upgrade_authority(Some(&admin.pubkey()));
+        let payer = self.context.payer.pubkey();
+        let fund = system_instruction::transfer(&payer, &admin.pubkey(), 1_000_000_000);
+        let instruction = instruction::initialize_config(
+            &self.program_id,
+            &admin.pubkey(),
+            PROTOCOL_FEE_BASIS_POINTS,
+            &treasury.owner.pubkey(),
+        );
+        self.process(&[fund, instruction], &[admin]).await.unwrap();
+        treasury
+    }
+
+    /// Plants the ProgramData account the upgradeable loader would have created on deploy
+    fn set_upgrade_authority(&mut self, authority: Option<&Pubkey>) {
+        let (program_data, _) = find_program_data_address(&self.program_id);
+        let state = UpgradeableLoaderState::ProgramData {
+            slot: 0,
+            upgrade_authority_address: authority.copied(),
+        };
+        let account = Account::new_data_with_space(
+            1_000_000_000,
+            &state,
+            UpgradeableLoaderState::size_of_programdata_metadata(),
+            &bpf_loader_upgradeable::id(),
+        )
+        .unwrap();
+        self.context.set_account(&program_data, &account.into());
+    }
+
+    fn exchange_instruction_with_fee(&self, amount: u64, treasury: &Treasury) -> Instruction {
+        let mut instruction = self.exchange_instruction(amount);
+        instruction.accounts[14] = AccountMeta::new(treasury.x.pubkey(), false);
+        instruction.accounts[15] = AccountMeta::new(treasury.y.pubkey(), false);
+        instruction
+    }
+
+    async fn get_config(&mut self) -> Config {
+        let (config, _) = find_config_address(&self.program_id);
+        Config::unpack(&self.get_account(&config).await.unwrap().data).unwrap()
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_collects_protocol_fee() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let treasury = env.initialize_config(&admin).await;
+    env.init_escrow().await;
+
+    env.exchange_with(env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 9);
+    assert_eq!(env.token_balance(&treasury.x.pubkey()).await, 1);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 27);
+    assert_eq!(env.token_balance(&treasury.y.pubkey()).await, 3);
+    assert_eq!(
+        env.token_balance(&env.bob_y.pubkey()).await,
+        100 - EXPECTED_AMOUNT
+    );
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_without_config_is_free() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+
+    env.exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_fee_wrong_treasury() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let treasury = env.initialize_config(&admin).await;
+    env.init_escrow().await;
+
+    // Fees can't be diverted to another account, nor skipped by leaving the treasury out
+    let mut instruction = env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury);
+    instruction.accounts[15].pubkey = env.alice_y.pubkey();
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::TokenAccountOwnerMismatch);
+    let result = env
+        .exchange_with(env.exchange_instruction(OFFERED_AMOUNT))
+        .await;
+    assert!(result.is_err());
+
+    // Without the config there is nothing to tell whether the program is paused
+    let mut instruction = env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury);
+    instruction.accounts[13].pubkey = Pubkey::new_unique();
+    let result = env.exchange_with(instruction).await;
+    assert_escrow_error(result, EscrowError::MissingConfigAccount);
+}
+
+#[tokio::test]
+async fn test_exchange_fee_above_takers_limit() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let treasury = env.initialize_config(&admin).await;
+    env.init_escrow().await;
+
+    // The taker agreed to a lower fee than the admin has since configured
+    let with_limit = |max_fee_basis_points| {
+        let mut instruction = env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury);
+        instruction.data = instruction::EscrowInstruction::Exchange {
+            amount: OFFERED_AMOUNT,
+            max_payment: u64::MAX,
+            max_fee_basis_points,
+        }
+        .pack();
+        instruction
+    };
+    let (too_low, exact) = (
+        with_limit(PROTOCOL_FEE_BASIS_POINTS - 1),
+        with_limit(PROTOCOL_FEE_BASIS_POINTS),
+    );
+    let result = env.exchange_with(too_low).await;
+    assert_escrow_error(result, EscrowError::ProtocolFeeTooHigh);
+    env.exchange_with(exact).await.unwrap();
+    assert_eq!(env.token_balance(&treasury.y.pubkey()).await, 3);
+}
+
+#[tokio::test]
+async fn test_initialize_config_requires_upgrade_authority() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let stranger = Keypair::new();
+    let payer = env.context.payer.pubkey();
+    let fund = system_instruction::transfer(&payer, &stranger.pubkey(), 1_000_000_000);
+    env.process(&[fund], &[]).await.unwrap();
+    let initialize =
+        instruction::initialize_config(&env.program_id, &stranger.pubkey(), 0, &admin.pubkey());
+
+    // Only whoever can upgrade the program may claim the config, and nobody once it is immutable
+    env.set_upgrade_authority(Some(&admin.pubkey()));
+    let result = env
+        .process(std::slice::from_ref(&initialize), &[&stranger])
+        .await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+    env.set_upgrade_authority(None);
+    let result = env
+        .process(std::slice::from_ref(&initialize), &[&stranger])
+        .await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+
+    // Nor can another account stand in for the ProgramData account
+    let mut instruction = initialize;
+    instruction.accounts[3].pubkey = Pubkey::new_unique();
+    let result = env.process(&[instruction], &[&stranger]).await;
+    assert_instruction_error(result, InstructionError::InvalidSeeds);
+
+    let (config, _) = find_config_address(&env.program_id);
+    assert!(env.get_account(&config).await.is_none());
+}
+
+#[tokio::test]
+async fn test_update_config() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    env.initialize_config(&admin).await;
+    let config = env.get_config().await;
+    assert_eq!(config.admin_pubkey, admin.pubkey());
+    assert_eq!(config.fee_basis_points, PROTOCOL_FEE_BASIS_POINTS);
+
+    let instruction =
+        instruction::initialize_config(&env.program_id, &admin.pubkey(), 0, &admin.pubkey());
+    let result = env.process(&[instruction], &[&admin]).await;
+    assert_instruction_error(result, InstructionError::AccountAlreadyInitialized);
+
+    let new_admin = Keypair::new();
+    let treasury = Pubkey::new_unique();
+    let update = |admin: &Keypair, fee_basis_points| {
+        instruction::update_config(
+            &env.program_id,
+            &admin.pubkey(),
+            &new_admin.pubkey(),
+            fee_basis_points,
+            &treasury,
+        )
+    };
+    let (by_stranger, too_high, valid) = (
+        update(&new_admin, 30),
+        update(&admin, MAX_PROTOCOL_FEE_BASIS_POINTS + 1),
+        update(&admin, 30),
+    );
+
+    let result = env.process(&[by_stranger], &[&new_admin]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+    let result = env.process(&[too_high], &[&admin]).await;
+    assert_escrow_error(result, EscrowError::InvalidFee);
+    env.process(&[valid], &[&admin]).await.unwrap();
+
+    let config = env.get_config().await;
+    assert_eq!(config.admin_pubkey, new_admin.pubkey());
+    assert_eq!(config.fee_basis_points, 30);
+    assert_eq!(config.treasury_pubkey, treasury);
+}
+
+#[tokio::test]
+async fn test_pause_keeps_exits_open() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let treasury = env.initialize_config(&admin).await;
+    env.init_escrow().await;
+
+    let stranger = Keypair::new();
+    let instruction = instruction::pause(&env.program_id, &stranger.pubkey());
+    let result = env.process(&[instruction], &[&stranger]).await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+
+    let instruction = instruction::pause(&env.program_id, &admin.pubkey());
+    env.process(&[instruction], &[&admin]).await.unwrap();
+    assert!(env.get_config().await.is_paused);
+
+    let result = env
+        .exchange_with(env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury))
+        .await;
+    assert_escrow_error(result, EscrowError::ProgramPaused);
+
+    env.cancel_with(env.cancel_instruction()).await.unwrap();
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        OFFERED_AMOUNT
+    );
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_pause_holds_payouts() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    env.initialize_config(&admin).await;
+    let mint_to = spl_token_2022::instruction::mint_to(
+        &env.token_program,
+        &env.mint_x.pubkey(),
+        &env.alice_x.pubkey(),
+        &env.context.payer.pubkey(),
+        &[],
+        2 * OFFERED_AMOUNT,
+    )
+    .unwrap();
+    env.process(&[mint_to], &[]).await.unwrap();
+
+    let carol = Keypair::new();
+    env.init_arbiter_escrow(&carol).await;
+    let arbiter_escrow = std::mem::replace(&mut env.escrow, Keypair::new());
+    env.init_milestone_escrow().await;
+    let milestone_escrow = std::mem::replace(&mut env.escrow, Keypair::new());
+    env.init_stream().await;
+
+    let instruction = instruction::pause(&env.program_id, &admin.pubkey());
+    env.process(&[instruction], &[&admin]).await.unwrap();
+    env.set_unix_timestamp(1_500).await;
+    let (alice, bob) = (env.alice.insecure_clone(), env.bob.insecure_clone());
+
+    // Nothing is paid to the beneficiary while paused, but the funder can still end the stream
+    // and take back what hasn't vested
+    let result = env.claim().await;
+    assert_escrow_error(result, EscrowError::ProgramPaused);
+    env.cancel_stream().await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+    assert_eq!(env.token_balance(&env.vault()).await, 5);
+    let stream =
+        Stream::unpack(&env.get_account(&env.escrow.pubkey()).await.unwrap().data).unwrap();
+    assert_eq!((stream.total_amount, stream.end_time), (5, 1_500));
+    let stream_escrow = std::mem::replace(&mut env.escrow, Keypair::new());
+
+    env.escrow = milestone_escrow;
+    let instruction = env.approve_milestone_instruction();
+    let result = env.process(&[instruction], &[&alice]).await;
+    assert_escrow_error(result, EscrowError::ProgramPaused);
+    env.set_unix_timestamp(MILESTONE_DEADLINE).await;
+    env.refund_milestones().await.unwrap();
+
+    env.escrow = arbiter_escrow;
+    let result = env
+        .settle(Settlement::Released, &carol, &[&alice, &bob])
+        .await;
+    assert_escrow_error(result, EscrowError::ProgramPaused);
+    env.settle(Settlement::Refunded, &carol, &[&alice, &bob])
+        .await
+        .unwrap();
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+    assert_eq!(
+        env.token_balance(&env.alice_x.pubkey()).await,
+        3 * OFFERED_AMOUNT - 5
+    );
+
+    // What had vested is claimable once unpaused, and claiming it closes the stream
+    let instruction = instruction::unpause(&env.program_id, &admin.pubkey());
+    env.process(&[instruction], &[&admin]).await.unwrap();
+    env.escrow = stream_escrow;
+    env.set_unix_timestamp(STREAM_END).await;
+    env.claim().await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 5);
+    assert!(env.get_account(&env.vault()).await.is_none());
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_unpause_resumes_trading() {
+    let mut env = Env::new().await;
+    let admin = Keypair::new();
+    let treasury = env.initialize_config(&admin).await;
+    let instruction = instruction::pause(&env.program_id, &admin.pubkey());
+    env.process(&[instruction], &[&admin]).await.unwrap();
+
+    let result = env.init_escrow_with(env.init_escrow_instruction()).await;
+    assert_escrow_error(result, EscrowError::ProgramPaused);
+
+    // Leaving the config out doesn't get around the pause
+    let alice = env.alice.insecure_clone();
+    let mut instruction = env.init_escrow_instruction();
+    instruction.accounts.remove(9);
+    let result = env.process(&[instruction], &[&alice]).await;
+    assert_escrow_error(result, EscrowError::MissingConfigAccount);
+
+    let instruction = instruction::unpause(&env.program_id, &admin.pubkey());
+    env.process(&[instruction], &[&admin]).await.unwrap();
+    assert!(!env.get_config().await.is_paused);
+
+    env.process(&[env.init_escrow_instruction()], &[&alice])
+        .await
+        .unwrap();
+    env.exchange_with(env.exchange_instruction_with_fee(OFFERED_AMOUNT, &treasury))
+        .await
+        .unwrap();
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+impl Env {
+    /// Opens `count` escrows of Alice's, each on the same terms as `init_escrow` and funded with
+    /// newly minted tokens, and returns the `Exchange` taking each of them in full. The last one
+    /// is left as `escrow`
+    async fn init_escrows(&mut self, count: usize) -> Vec<Instruction> {
+        let mut exchanges = Vec::with_capacity(count);
+        for _ in 0..count {
+            let mint_to = spl_token_2022::instruction::mint_to(
+                &self.token_program,
+                &self.mint_x.pubkey(),
+                &self.alice_x.pubkey(),
+                &self.context.payer.pubkey(),
+                &[],
+                OFFERED_AMOUNT,
+            )
+            .unwrap();
+            self.process(&[mint_to], &[]).await.unwrap();
+            self.escrow = Keypair::new();
+            self.init_escrow().await;
+            exchanges.push(self.exchange_instruction(OFFERED_AMOUNT));
+        }
+        exchanges
+    }
+
+    async fn exchange_many(
+        &mut self,
+        exchanges: &[Instruction],
+        max_total: u64,
+    ) -> Result<(), BanksClientError> {
+        let instruction = instruction::exchange_many(
+            &self.program_id,
+            &self.bob.pubkey(),
+            exchanges,
+            max_total,
+            MAX_PROTOCOL_FEE_BASIS_POINTS,
+        );
+        self.exchange_with(instruction).await
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_many() {
+    let mut env = Env::new().await;
+    let exchanges = env.init_escrows(3).await;
+
+    env.exchange_many(&exchanges, 3 * EXPECTED_AMOUNT)
+        .await
+        .unwrap();
+
+    assert_eq!(
+        env.token_balance(&env.bob_x.pubkey()).await,
+        3 * OFFERED_AMOUNT
+    );
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        3 * EXPECTED_AMOUNT
+    );
+    for exchange in &exchanges {
+        assert!(env
+            .get_account(&exchange.accounts[6].pubkey)
+            .await
+            .is_none());
+    }
+}
+
+#[tokio::test]
+async fn test_exchange_many_max_total() {
+    let mut env = Env::new().await;
+    let exchanges = env.init_escrows(2).await;
+
+    let result = env.exchange_many(&exchanges, 2 * EXPECTED_AMOUNT - 1).await;
+    assert_escrow_error(result, EscrowError::ExpectedAmountMismatch);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 0);
+
+    env.exchange_many(&exchanges, 2 * EXPECTED_AMOUNT)
+        .await
+        .unwrap();
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        2 * EXPECTED_AMOUNT
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_many_is_atomic() {
+    let mut env = Env::new().await;
+    let exchanges = env.init_escrows(2).await;
+    env.cancel_with(env.cancel_instruction()).await.unwrap();
+
+    // The first escrow would fill, but the second one is gone
+    let result = env.exchange_many(&exchanges, u64::MAX).await;
+    assert!(result.is_err());
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+    assert_eq!(env.token_balance(&env.bob_y.pubkey()).await, 100);
+    let first_escrow = exchanges[0].accounts[6].pubkey;
+    assert!(env.get_account(&first_escrow).await.is_some());
+
+    // Nor can the same escrow be taken twice
+    let result = env
+        .exchange_many(&[exchanges[0].clone(), exchanges[0].clone()], u64::MAX)
+        .await;
+    assert_instruction_error(result, InstructionError::InvalidAccountData);
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+}
+
+impl Env {
+    /// Lets Bob's nonce account spend his token Y, which his wallet does once before his first
+    /// intent
+    async fn approve_intent_delegate(&mut self) {
+        let (nonce_account, _) = find_intent_nonce_address(&self.program_id, &self.bob.pubkey());
+        let instruction = spl_token_2022::instruction::approve(
+            &self.token_program,
+            &self.bob_y.pubkey(),
+            &nonce_account,
+            &self.bob.pubkey(),
+            &[],
+            100,
+        )
+        .unwrap();
+        let bob = self.bob.insecure_clone();
+        self.process(&[instruction], &[&bob]).await.unwrap();
+    }
+
+    /// The ed25519 check of `signer`'s intent to take `amount` and the `ExchangeWithIntent` the
+    /// payer relays for Bob
+    fn intent_instructions(
+        &self,
+        signer: &Keypair,
+        amount: u64,
+        nonce: u64,
+        expires_at: i64,
+    ) -> [Instruction; 2] {
+        let message = instruction::taker_intent_message(
+            &self.escrow.pubkey(),
+            amount,
+            u64::MAX,
+            MAX_PROTOCOL_FEE_BASIS_POINTS,
+            nonce,
+            expires_at,
+        );
+        let signature = signer.sign_message(&message);
+        [
+            instruction::verify_taker_intent(
+                &signer.pubkey(),
+                signature.as_ref().try_into().unwrap(),
+                &message,
+            ),
+            instruction::exchange_with_intent(
+                &self.program_id,
+                &self.exchange_instruction(amount),
+                &self.context.payer.pubkey(),
+                nonce,
+                expires_at,
+            ),
+        ]
+    }
+
+    async fn get_intent_nonce(&mut self) -> IntentNonce {
+        let (nonce_account, _) = find_intent_nonce_address(&self.program_id, &self.bob.pubkey());
+        IntentNonce::unpack(&self.get_account(&nonce_account).await.unwrap().data).unwrap()
+    }
+}
+
+fn assert_intent_error(result: Result<(), BanksClientError>, expected: InstructionError) {
+    assert_eq!(
+        result.unwrap_err().unwrap(),
+        TransactionError::InstructionError(1, expected)
+    );
+}
+
+#[tokio::test]
+async fn test_exchange_with_intent() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    env.approve_intent_delegate().await;
+    let bob = env.bob.insecure_clone();
+
+    // Relayed by the payer, without Bob signing the transaction
+    let instructions = env.intent_instructions(&bob, 4, 0, i64::MAX);
+    env.process(&instructions, &[]).await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 4);
+    assert_eq!(env.token_balance(&env.alice_y.pubkey()).await, 12);
+    assert_eq!(env.get_intent_nonce().await.next_nonce, 1);
+
+    let result = env.process(&instructions, &[]).await;
+    assert_intent_error(
+        result,
+        InstructionError::Custom(EscrowError::NonceAlreadyUsed as u32),
+    );
+
+    // Skipping ahead is fine, and voids every nonce skipped
+    let instructions = env.intent_instructions(&bob, 6, 5, i64::MAX);
+    env.process(&instructions, &[]).await.unwrap();
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, OFFERED_AMOUNT);
+    assert_eq!(
+        env.token_balance(&env.alice_y.pubkey()).await,
+        EXPECTED_AMOUNT
+    );
+    assert_eq!(env.get_intent_nonce().await.next_nonce, 6);
+    assert!(env.get_account(&env.escrow.pubkey()).await.is_none());
+}
+
+#[tokio::test]
+async fn test_exchange_with_intent_rejects_bad_intents() {
+    let mut env = Env::new().await;
+    env.init_escrow().await;
+    env.approve_intent_delegate().await;
+    let (alice, bob) = (env.alice.insecure_clone(), env.bob.insecure_clone());
+
+    // Signed by someone other than the taker
+    let instructions = env.intent_instructions(&alice, 4, 0, i64::MAX);
+    let result = env.process(&instructions, &[]).await;
+    assert_intent_error(result, InstructionError::MissingRequiredSignature);
+
+    // Relayed for more than Bob signed for
+    let [verify, mut exchange] = env.intent_instructions(&bob, 4, 0, i64::MAX);
+    exchange.data = env.intent_instructions(&bob, 5, 0, i64::MAX)[1]
+        .data
+        .clone();
+    let result = env.process(&[verify, exchange.clone()], &[]).await;
+    assert_intent_error(result, InstructionError::MissingRequiredSignature);
+
+    // Forged, with the signed message swapped out under Bob's signature
+    let [mut verify, _] = env.intent_instructions(&bob, 4, 0, i64::MAX);
+    let [forged, exchange] = env.intent_instructions(&alice, 5, 0, i64::MAX);
+    let message_start = verify.data.len() - 80;
+    verify.data[message_start..].copy_from_slice(&forged.data[message_start..]);
+    let result = env.process(&[verify, exchange.clone()], &[]).await;
+    assert!(result.is_err());
+
+    // Without any signature check
+    let result = env.process(&[exchange], &[]).await;
+    assert_instruction_error(result, InstructionError::MissingRequiredSignature);
+
+    env.set_unix_timestamp(2_000).await;
+    let instructions = env.intent_instructions(&bob, 4, 0, 2_000);
+    let result = env.process(&instructions, &[]).await;
+    assert_intent_error(
+        result,
+        InstructionError::Custom(EscrowError::Expired as u32),
+    );
+
+    assert_eq!(env.token_balance(&env.bob_x.pubkey()).await, 0);
+    let (nonce_account, _) = find_intent_nonce_address(&env.program_id, &bob.pubkey());
+    assert!(env.get_account(&nonce_account).await.is_none());
+}